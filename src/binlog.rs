@@ -9,6 +9,8 @@ pub mod parsevalue;
 pub mod jsonb;
 pub mod rollback;
 pub mod getsql;
+pub mod error;
+pub mod flashback;
 use std::fs::File;
 use std::io::{BufReader};
 
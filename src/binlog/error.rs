@@ -0,0 +1,92 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/20
+*/
+
+use std::fmt;
+use std::io;
+use std::error::Error;
+
+///
+/// binlog解析错误的类型
+///     Io: 读取数据失败，通常是binlog被截断(宕机时最后一个event没有写完整)
+///     Corrupt: 数据内容不合法，如长度越界、checksum不一致
+///     Unsupported: 不支持的字段类型、json类型等
+///     Schema: 无法获取表结构或表结构与binlog不一致，无法生成sql
+///
+#[derive(Debug)]
+pub enum BinlogErrorKind {
+    Io(io::Error),
+    Corrupt(String),
+    Unsupported(String),
+    Schema(String),
+}
+
+///
+/// binlog解析错误，带有出错event所在的binlog文件及偏移量
+///     解码函数中只产生错误类型，由readbinlog在返回前补充文件及偏移量
+///
+#[derive(Debug)]
+pub struct BinlogError {
+    pub kind: BinlogErrorKind,
+    pub binlog: String,         //binlog文件名，服务端推送的binlog片段为空
+    pub offset: Option<u64>,    //出错event的起始位置
+}
+
+pub type BinlogResult<T> = Result<T, BinlogError>;
+
+impl BinlogError {
+    pub fn corrupt<S: Into<String>>(msg: S) -> BinlogError {
+        BinlogError{ kind: BinlogErrorKind::Corrupt(msg.into()), binlog: String::from(""), offset: None }
+    }
+
+    pub fn unsupported<S: Into<String>>(msg: S) -> BinlogError {
+        BinlogError{ kind: BinlogErrorKind::Unsupported(msg.into()), binlog: String::from(""), offset: None }
+    }
+
+    pub fn schema<S: Into<String>>(msg: S) -> BinlogError {
+        BinlogError{ kind: BinlogErrorKind::Schema(msg.into()), binlog: String::from(""), offset: None }
+    }
+
+    ///
+    /// 补充出错的位置，已经有位置信息时保留原有的(嵌套解析rotate后的binlog时以内层为准)
+    ///
+    pub fn context(mut self, binlog: &String, offset: u64) -> BinlogError {
+        if self.offset.is_none() {
+            self.binlog = binlog.clone();
+            self.offset = Some(offset);
+        }
+        self
+    }
+}
+
+impl fmt::Display for BinlogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match &self.kind {
+            BinlogErrorKind::Io(e) => format!("read binlog error: {}", e),
+            BinlogErrorKind::Corrupt(m) => format!("corrupt binlog: {}", m),
+            BinlogErrorKind::Unsupported(m) => format!("unsupported: {}", m),
+            BinlogErrorKind::Schema(m) => format!("table schema error: {}", m),
+        };
+        match self.offset {
+            Some(offset) if self.binlog.len() > 0 => write!(f, "{} at {}:{}", msg, self.binlog, offset),
+            Some(offset) => write!(f, "{} at offset {}", msg, offset),
+            None => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl Error for BinlogError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            BinlogErrorKind::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for BinlogError {
+    fn from(e: io::Error) -> BinlogError {
+        BinlogError{ kind: BinlogErrorKind::Io(e), binlog: String::from(""), offset: None }
+    }
+}
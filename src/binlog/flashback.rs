@@ -0,0 +1,196 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/22
+*/
+
+use std::fs;
+use std::io::{Write, BufWriter};
+use std::error::Error;
+use byteorder::{WriteBytesExt, LittleEndian};
+use uuid::Uuid;
+use crate::binlog::readevent::FormatDescriptionEvent;
+use crate::binlog::readbinlog::{RowsSql, Traction};
+
+/*
+flashback binlog, 与mariadb的mysqlbinlog --flashback输出一致，可以使用mysqlbinlog | mysql回放:
+    magic : 4bytes (\xfebin)
+    format_description_event
+    previous_gtids_log_event (空)
+    每个事务(按原来的顺序反过来):
+        gtid_event : 新的gtid，随机uuid，gno从1开始
+        query_event : BEGIN
+        table_map_event + rows_event : 回滚的row event，一个事务中后面的先写
+        xid_event
+    所有event重新计算event_length、next_position及crc32
+*/
+const BINLOG_MAGIC: [u8; 4] = [0xfe, 0x62, 0x69, 0x6e];
+const QUERY_EVENT: u8 = 2;
+const FORMAT_DESCRIPTION_EVENT: u8 = 15;
+const XID_EVENT: u8 = 16;
+const TABLE_MAP_EVENT: u8 = 19;
+const GTID_LOG_EVENT: u8 = 33;
+const PREVIOUS_GTIDS_LOG_EVENT: u8 = 35;
+const STMT_END_F: u16 = 1;
+
+///
+/// 没有读取到format description event时使用的post header长度(mysql 5.7, 每种event类型1bytes)
+///
+const DEFAULT_POST_HEADER_LEN: [u8; 38] = [
+    56, 13, 0, 8, 0, 18, 0, 4, 4, 4, 4, 18, 0, 0, 95, 0, 4, 26, 8, 0,
+    0, 0, 8, 8, 8, 2, 0, 0, 0, 10, 10, 10, 42, 42, 0, 18, 52, 0];
+
+struct BinlogWriter<W: Write> {
+    writer: W,
+    position: u32,
+}
+
+impl<W: Write> BinlogWriter<W> {
+    fn new(mut writer: W) -> Result<BinlogWriter<W>, Box<dyn Error>> {
+        writer.write_all(&BINLOG_MAGIC)?;
+        Ok(BinlogWriter{ writer, position: BINLOG_MAGIC.len() as u32 })
+    }
+
+    ///
+    /// 写入一个event，body为19bytes header之后的部分，末尾追加crc32
+    ///
+    fn write_event(&mut self, timestamp: u32, type_byte: u8, server_id: u32, flags: u16, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        let event_length = (19 + body.len() + 4) as u32;
+        let next_position = self.position + event_length;
+        let mut pack = vec![];
+        pack.write_u32::<LittleEndian>(timestamp)?;
+        pack.push(type_byte);
+        pack.write_u32::<LittleEndian>(server_id)?;
+        pack.write_u32::<LittleEndian>(event_length)?;
+        pack.write_u32::<LittleEndian>(next_position)?;
+        pack.write_u16::<LittleEndian>(flags)?;
+        pack.extend(body);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&pack);
+        pack.write_u32::<LittleEndian>(hasher.finalize())?;
+        self.writer.write_all(&pack)?;
+        self.position = next_position;
+        Ok(())
+    }
+
+    ///
+    /// 写入解析时保留的完整event(包含header，不包含checksum)，header中的长度及位置重新计算
+    ///
+    fn write_raw_event(&mut self, event: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        if event.len() < 19 {
+            return Err(format!("invalid event length: {}", event.len()).into());
+        }
+        let timestamp = crate::readvalue::read_u32(&event[0..4]);
+        let server_id = crate::readvalue::read_u32(&event[5..9]);
+        let flags = crate::readvalue::read_u16(&event[17..19]);
+        self.write_event(timestamp, event[4], server_id, flags, &event[19..])
+    }
+
+    fn write_format_description(&mut self, fde: &FormatDescriptionEvent, timestamp: u32, server_id: u32) -> Result<(), Box<dyn Error>> {
+        let mut body = vec![];
+        body.write_u16::<LittleEndian>(4)?;
+        let mut server_version = match fde.server_version.len() {
+            0 => b"5.7.0".to_vec(),
+            _ => fde.server_version.as_bytes().to_vec()
+        };
+        server_version.resize(50, 0);
+        body.extend(server_version);
+        body.write_u32::<LittleEndian>(timestamp)?;
+        body.push(19);
+        match fde.post_header_len.len() {
+            0 => body.extend(DEFAULT_POST_HEADER_LEN.iter()),
+            _ => body.extend(&fde.post_header_len)
+        }
+        //checksum_alg: crc32
+        body.push(1);
+        self.write_event(timestamp, FORMAT_DESCRIPTION_EVENT, server_id, 0, &body)
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// gtid_event: commit_flag(1bytes), sid(16bytes), gno(8bytes), lt_type(1bytes, 2),
+///             last_committed(8bytes), sequence_number(8bytes)
+///
+fn gtid_body(sid: &Uuid, gno: i64) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut body = vec![1u8];
+    body.extend(sid.as_bytes());
+    body.write_i64::<LittleEndian>(gno)?;
+    body.push(2);
+    body.write_i64::<LittleEndian>(gno - 1)?;
+    body.write_i64::<LittleEndian>(gno)?;
+    Ok(body)
+}
+
+///
+/// query_event: thread_id(4bytes), exec_time(4bytes), db_len(1bytes), error_code(2bytes),
+///              status_vars_len(2bytes), db\0, query
+///
+fn begin_body() -> Vec<u8> {
+    let mut body = vec![0u8; 13];
+    body.push(0);
+    body.extend(b"BEGIN");
+    body
+}
+
+///
+/// rows event的flags在table_id(6bytes)之后，每个rows event前都写了table map，需要都标记为语句结束
+///
+fn set_stmt_end(event: &mut Vec<u8>) {
+    if event.len() >= 27 {
+        let flags = crate::readvalue::read_u16(&event[25..27]) | STMT_END_F;
+        event[25..27].copy_from_slice(&flags.to_le_bytes());
+    }
+}
+
+///
+/// 把回滚的event写入path，返回生成的gtid集合，没有可回滚的事务时返回None且不生成文件
+///     before image不完整的事务不能回滚，返回错误
+///
+pub fn write_flashback(path: &String, fde: &FormatDescriptionEvent, sqls: &RowsSql) -> Result<Option<String>, Box<dyn Error>> {
+    if sqls.sqls.iter().any(|t| t.rollback_incomplete) {
+        return Err(String::from("binlog row image is not full, can not write flashback binlog").into());
+    }
+    let tractions: Vec<_> = sqls.sqls.iter().rev().filter(|t| t.rollback_events.len() > 0).collect();
+    if tractions.len() == 0 {
+        return Ok(None);
+    }
+    for t in sqls.sqls.iter() {
+        for event in t.event.iter() {
+            if let Traction::QueryEvent(q) = event {
+                if q.command.to_uppercase() != "BEGIN" {
+                    info!("flashback binlog skip statement: {}", q.command);
+                }
+            }
+        }
+    }
+
+    let timestamp = (crate::timestamp() / 1000) as u32;
+    let server_id = tractions[0].server_id;
+    let sid = Uuid::new_v4();
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut writer = BinlogWriter::new(BufWriter::new(fs::File::create(&tmp_path)?))?;
+        writer.write_format_description(fde, timestamp, server_id)?;
+        writer.write_event(timestamp, PREVIOUS_GTIDS_LOG_EVENT, server_id, 0, &0u64.to_le_bytes())?;
+        for (idx, traction) in tractions.iter().enumerate() {
+            let gno = idx as i64 + 1;
+            writer.write_event(timestamp, GTID_LOG_EVENT, traction.server_id, 0, &gtid_body(&sid, gno)?)?;
+            writer.write_event(timestamp, QUERY_EVENT, traction.server_id, 0, &begin_body())?;
+            for event in traction.rollback_events.iter() {
+                let mut event = event.clone();
+                if event.len() > 4 && event[4] != TABLE_MAP_EVENT {
+                    set_stmt_end(&mut event);
+                }
+                writer.write_raw_event(&event)?;
+            }
+            writer.write_event(timestamp, XID_EVENT, traction.server_id, 0, &(gno as u64).to_le_bytes())?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(Some(format!("{}:1-{}", sid, tractions.len())))
+}
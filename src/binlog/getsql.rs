@@ -9,27 +9,69 @@ use crate::binlog::parsevalue::RowValue;
 use crate::binlog::parsevalue::MySQLValue;
 use failure::_core::str::from_utf8;
 use hex;
-use bigdecimal::ToPrimitive;
+use chrono::NaiveDateTime;
+use crate::meta::ColumnTypeDict;
 use std::error::Error;
+use crate::meta::ColumnMeta;
+use crate::io::command::Param;
+use serde::Serialize;
+
+///
+/// sql生成方式
+///     Literal: 值直接拼接在sql中，用于展示及发送给服务端
+///     Param: 值使用?占位，通过预处理语句执行
+///
+pub enum RenderMode {
+    Literal,
+    Param
+}
+
+///
+/// 生成的timestamp值为UTC时间，执行生成的sql前需要先设置会话时区
+///
+pub const SESSION_TIME_ZONE: &str = "set session time_zone = '+00:00'";
+
+///
+/// 参数化的sql语句，Literal方式时params为空
+///
+#[derive(Debug, Clone)]
+pub struct SqlStatement {
+    pub sql: String,
+    pub params: Vec<Param>,
+}
+
+impl SqlStatement {
+    fn new(sql: String) -> SqlStatement {
+        SqlStatement{ sql, params: vec![] }
+    }
+}
 
 pub fn get_command(
     row_values: &RowValue,code: &BinlogEvent,
-    table_cols_info: &mut HashMap<String, Vec<HashMap<String, String>>>,
+    table_cols_info: &mut HashMap<String, Vec<ColumnMeta>>,
     db_tbl: &String, map: &TableMap) -> Result<Vec<String>, Box<dyn Error>> {
+    let stmts = get_statements(row_values, code, table_cols_info, db_tbl, map, &RenderMode::Literal)?;
+    Ok(stmts.into_iter().map(|s| s.sql).collect())
+}
 
-    let mut sqls: Vec<String> = vec![];
+pub fn get_statements(
+    row_values: &RowValue,code: &BinlogEvent,
+    table_cols_info: &mut HashMap<String, Vec<ColumnMeta>>,
+    db_tbl: &String, map: &TableMap, mode: &RenderMode) -> Result<Vec<SqlStatement>, Box<dyn Error>> {
+
+    let mut sqls: Vec<SqlStatement> = vec![];
     match table_cols_info.get(db_tbl) {
         Some(t) => {
             let cols = t;
-            //let pri_idex = 0 as usize;          //主键索引
-            //let mut pri = &String::from("");    //主键名称
-            let mut pri_info: HashMap<String, usize> = HashMap::new();
+            //用于定位行的索引字段下标，按索引中的顺序排列
+            let mut key_pos: Vec<(usize, usize)> = vec![];
             for (idx,r) in cols.iter().enumerate(){
-                if r.get("COLUMN_KEY").unwrap() == &String::from("PRI") {
-                    let pri = r.get("COLUMN_NAME").unwrap();
-                    pri_info.insert(pri.parse().unwrap(), idx);
+                if let Some(ordinal) = r.key_ordinal {
+                    key_pos.push((ordinal, idx));
                 }
             }
+            key_pos.sort();
+            let key_info: Vec<usize> = key_pos.into_iter().map(|(_, idx)| idx).collect();
 
             match code {
                 BinlogEvent::UpdateEvent => {
@@ -52,21 +94,21 @@ pub fn get_command(
                     for row in rows{
                         let befor_value = row[0];
                         let after_value = row[1];
-                        let v = out_update(befor_value, after_value, cols, &pri_info,map);
+                        let v = out_update(befor_value, after_value, cols, &key_info,map, mode);
                         sqls.push(v);
                     }
                 }
                 BinlogEvent::WriteEvent => {
                     //println!("-- Insert Row Value");
                     for row in &row_values.rows {
-                        let v = out_insert(row, cols, map);
+                        let v = out_insert(row, cols, map, mode);
                         sqls.push(v);
                     }
                 }
                 BinlogEvent::DeleteEvent => {
                     //println!("-- Delete Row Value");
                     for row in &row_values.rows {
-                        let v = out_delete(row, cols, &pri_info, map);
+                        let v = out_delete(row, cols, &key_info, map, mode);
                         sqls.push(v);
                     }
                 }
@@ -81,286 +123,504 @@ pub fn get_command(
     return Ok(sqls);
 }
 
+///
+/// 单行数据的变更，字段值使用字段名作为key，不在row image中的字段不输出
+///     insert只有after，delete只有before
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct RowChange {
+    pub op: String,             //insert/update/delete
+    pub database: String,
+    pub table: String,
+    pub before: Option<serde_json::Map<String, serde_json::Value>>,
+    pub after: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+pub fn get_row_changes(
+    row_values: &RowValue,code: &BinlogEvent,
+    table_cols_info: &HashMap<String, Vec<ColumnMeta>>,
+    db_tbl: &String, map: &TableMap) -> Result<Vec<RowChange>, Box<dyn Error>> {
+    let cols = match table_cols_info.get(db_tbl) {
+        Some(t) => t,
+        None => return Err(format!("no columns info: {}", db_tbl).into())
+    };
+    let new_change = |op: &str| RowChange{
+        op: op.to_string(),
+        database: map.database_name.clone(),
+        table: map.table_name.clone(),
+        before: None,
+        after: None
+    };
+    let mut changes = vec![];
+    match code {
+        BinlogEvent::UpdateEvent => {
+            for row in row_values.rows.chunks(2) {
+                let mut change = new_change("update");
+                change.before = Some(get_row_json(&row[0], cols, map));
+                if let Some(after) = row.get(1) {
+                    change.after = Some(get_row_json(after, cols, map));
+                }
+                changes.push(change);
+            }
+        }
+        BinlogEvent::WriteEvent => {
+            for row in &row_values.rows {
+                let mut change = new_change("insert");
+                change.after = Some(get_row_json(row, cols, map));
+                changes.push(change);
+            }
+        }
+        BinlogEvent::DeleteEvent => {
+            for row in &row_values.rows {
+                let mut change = new_change("delete");
+                change.before = Some(get_row_json(row, cols, map));
+                changes.push(change);
+            }
+        }
+        _ => {}
+    }
+    Ok(changes)
+}
+
+fn get_row_json(row_value: &Vec<Option<MySQLValue>>, table_cols_info: &Vec<ColumnMeta>, map: &TableMap) -> serde_json::Map<String, serde_json::Value> {
+    let mut row = serde_json::Map::new();
+    for (idx, value) in row_value.iter().enumerate() {
+        if value.is_none() || idx >= table_cols_info.len() {
+            continue;
+        }
+        let col_meta = &table_cols_info[idx];
+        row.insert(col_meta.column_name.clone(), get_json_value(value, col_meta, column_fsp(map, idx)));
+    }
+    row
+}
+
+///
+/// 单个值的json表示
+///     整数、浮点数为数字，json字段为原始的json值，二进制数据为16进制字符串
+///     decimal及时间类型与生成sql时的格式一致(字符串)，timestamp为UTC时间
+///
+fn get_json_value(value: &Option<MySQLValue>, col_meta: &ColumnMeta, fsp: usize) -> serde_json::Value {
+    match value {
+        Some(MySQLValue::Json(t)) => return t.clone(),
+        Some(MySQLValue::Float(t)) => {
+            //f32直接转换为f64会带上多余的小数位
+            if let Ok(v) = t.to_string().parse::<f64>() {
+                return serde_json::Value::from(v);
+            }
+        }
+        Some(MySQLValue::Blob(t)) => {
+            let col_type = &col_meta.column_type;
+            if col_type.find("text").is_some() || col_type.find("char").is_some() {
+                if let Ok(v) = from_utf8(t) {
+                    return serde_json::Value::from(v);
+                }
+            }
+            return serde_json::Value::from(hex::encode(t));
+        }
+        _ => {}
+    }
+    let mut params = vec![];
+    if get_values_info(value, col_meta, fsp, &RenderMode::Param, &mut params).is_none() {
+        return serde_json::Value::Null;
+    }
+    match params.pop() {
+        Some(Param::Int(v)) => serde_json::Value::from(v),
+        Some(Param::UInt(v)) => serde_json::Value::from(v),
+        Some(Param::Double(v)) => serde_json::Value::from(v),
+        Some(Param::Bytes(v)) => serde_json::Value::from(String::from_utf8_lossy(&v).to_string()),
+        _ => serde_json::Value::Null
+    }
+}
+
 
 enum GetType{
     GetWhere,
+    GetNullSafe,
     GetSet
 }
 
 
 pub fn out_delete(
     row_value: &Vec<Option<MySQLValue>>,
-    table_cols_info: &Vec<HashMap<String, String>>,
-    pri_info: &HashMap<String, usize>,
-    map: &TableMap) -> String {
-
-    let mut sql = format!("DELETE FROM {}.{} ", map.database_name, map.table_name);
-    let where_str = get_where_str(row_value, table_cols_info, pri_info);
-    sql.push_str(&where_str);
-    sql
+    table_cols_info: &Vec<ColumnMeta>,
+    key_info: &Vec<usize>,
+    map: &TableMap, mode: &RenderMode) -> SqlStatement {
+
+    let mut stmt = SqlStatement::new(format!("DELETE FROM {}.{} ", quote_identifier(&map.database_name), quote_identifier(&map.table_name)));
+    let where_str = get_where_str(row_value, table_cols_info, key_info, map, mode, &mut stmt.params);
+    stmt.sql.push_str(&where_str);
+    stmt
 }
 
 pub fn out_insert(
     row_value: &Vec<Option<MySQLValue>>,
-    table_cols_info: &Vec<HashMap<String, String>>,
-    map: &TableMap) -> String {
-
-    let mut sql = format!("INSERT INTO {}.{}", map.database_name,map.table_name);
-    let col_str = get_insert_col_str(table_cols_info);
-    let value_str = get_values_str(row_value, table_cols_info);
-    sql.push_str(&col_str);
-    sql.push_str(" ");
-    sql.push_str(&value_str);
-    sql
+    table_cols_info: &Vec<ColumnMeta>,
+    map: &TableMap, mode: &RenderMode) -> SqlStatement {
+
+    let mut stmt = SqlStatement::new(format!("INSERT INTO {}.{}", quote_identifier(&map.database_name), quote_identifier(&map.table_name)));
+    let col_str = get_insert_col_str(row_value, table_cols_info);
+    let value_str = get_values_str(row_value, table_cols_info, map, mode, &mut stmt.params);
+    stmt.sql.push_str(&col_str);
+    stmt.sql.push_str(" ");
+    stmt.sql.push_str(&value_str);
+    stmt
 }
 
-fn get_values_str(values: &Vec<Option<MySQLValue>>, table_cols_info: &Vec<HashMap<String, String>>) -> String{
-    let mut values_str = format!("VALUES(");
-    let value_len = values.len();
+fn get_values_str(values: &Vec<Option<MySQLValue>>, table_cols_info: &Vec<ColumnMeta>, map: &TableMap, mode: &RenderMode, params: &mut Vec<Param>) -> String{
+    let mut value_list = vec![];
     for (idx,value) in values.iter().enumerate() {
-        let col_type = table_cols_info[idx].get("COLUMN_TYPE").unwrap();
-        values_str.push_str(get_values_info(value, col_type).as_ref());
-        if idx < value_len - 1{
-            values_str.push_str(",");
-        }else {
-            values_str.push_str(");");
+        //不在row image中的字段不写入，使用默认值
+        if value.is_none() {
+            continue;
+        }
+        match get_values_info(value, &table_cols_info[idx], column_fsp(map, idx), mode, params) {
+            Some(v) => value_list.push(v),
+            None => value_list.push(String::from("Null"))
         }
     }
-    values_str
+    format!("VALUES({});", value_list.join(","))
 }
 
-fn get_values_info(value: &Option<MySQLValue>, col_type: &String) -> String {
-    let mut value_str = String::from("");
-    match value {
-        Some(MySQLValue::String(t)) => {
-            value_str.push_str(&format!("'{}'",t));
+///
+/// Literal方式返回值的字面量，Param方式返回?并把值加入params
+///
+fn bind(literal: String, param: Param, mode: &RenderMode, params: &mut Vec<Param>) -> String {
+    match mode {
+        RenderMode::Literal => literal,
+        RenderMode::Param => {
+            params.push(param);
+            String::from("?")
         }
-        Some(MySQLValue::Blob(t)) => {
-            match col_type.find("text") {
-                Some(_) => {
-                    value_str.push_str(&format!("'{}'",from_utf8(t).unwrap()));
-                    return value_str;
-                }
-                None => {}
+    }
+}
+
+///
+/// 字符类型的值，参数按binary传递，字符集已知时使用CONVERT(? USING charset)按字段的字符集解释
+///     与Literal方式的_charset前缀结果一致，都不受连接字符集影响
+///
+fn bind_text(value: Vec<u8>, charset: &Option<String>, mode: &RenderMode, params: &mut Vec<Param>) -> String {
+    match mode {
+        RenderMode::Literal => text_literal(&value, charset),
+        RenderMode::Param => {
+            params.push(Param::Bytes(value));
+            match charset {
+                Some(c) => format!("CONVERT(? USING {})", c),
+                None => String::from("?")
             }
-            match col_type.find("char") {
-                Some(_) => {
-                    value_str.push_str(&format!("'{}'",from_utf8(t).unwrap()));
-                    return value_str;
+        }
+    }
+}
+
+///
+/// 时间类型字段的小数秒精度，取自table map中的column meta
+///
+fn column_fsp(map: &TableMap, idx: usize) -> usize {
+    match map.column_info.get(idx) {
+        Some(info) => {
+            match info.column_type {
+                ColumnTypeDict::MysqlTypeTimestamp2 |
+                ColumnTypeDict::MysqlTypeDatetime2 |
+                ColumnTypeDict::MysqlTypeTime2 => {
+                    info.column_meta.get(0).cloned().unwrap_or(0).min(6)
                 }
-                None => {}
+                _ => 0
             }
+        }
+        None => 0
+    }
+}
+
+///
+/// 按字段精度输出小数秒，subsecond单位为微秒
+///
+fn fsp_str(subsecond: u32, fsp: usize) -> String {
+    if fsp == 0 {
+        return String::from("");
+    }
+    let v = format!("{:06}", subsecond);
+    format!(".{}", &v[..fsp])
+}
 
-            if t.len()> 0{
-                value_str.push_str(&format!("0x{}",hex::encode(t)));
-            }else {
-                value_str.push_str(&format!("Null"));
+///
+/// 生成单个值在sql中的表示，值为NULL时返回None，由调用方决定使用Null还是is Null
+///     时间类型按字段精度完整输出，timestamp输出为UTC时间，执行时需设置time_zone为'+00:00'
+///
+fn get_values_info(value: &Option<MySQLValue>, col_meta: &ColumnMeta, fsp: usize, mode: &RenderMode, params: &mut Vec<Param>) -> Option<String> {
+    let col_type = &col_meta.column_type;
+    let value_str = match value {
+        Some(MySQLValue::String(t)) => {
+            let v = t.as_bytes().to_vec();
+            bind_text(v, &col_meta.character_set, mode, params)
+        }
+        Some(MySQLValue::Blob(t)) => {
+            if col_type.find("text").is_some() || col_type.find("char").is_some() {
+                return Some(bind_text(t.clone(), &col_meta.character_set, mode, params));
             }
+            //二进制数据使用16进制表示，空值为空字符串而不是NULL
+            bind(hex_literal(t), Param::Bytes(t.clone()), mode, params)
         }
         Some(MySQLValue::Timestamp {unix_time, subsecond}) => {
-            value_str.push_str(&format!("from_unixtime({}.{})", unix_time, subsecond));
+            let v = if *unix_time == 0 && *subsecond == 0 {
+                format!("0000-00-00 00:00:00{}", fsp_str(0, fsp))
+            } else {
+                let t = NaiveDateTime::from_timestamp(*unix_time as i64, 0);
+                format!("{}{}", t.format("%Y-%m-%d %H:%M:%S"), fsp_str(*subsecond, fsp))
+            };
+            bind(format!("'{}'", v), Param::Bytes(v.into_bytes()), mode, params)
         }
         Some(MySQLValue::Enum(t)) => {
-            value_str.push_str(&format!("{}",t));
+            //使用取值字符串，序号0为非法值写入时产生的空字符串，取值列表未知时使用序号
+            let members = type_members(col_type);
+            let member = match *t {
+                0 => Some(String::from("")),
+                v => members.get(v as usize - 1).cloned()
+            };
+            match member {
+                Some(m) => bind_text(m.into_bytes(), &col_meta.character_set, mode, params),
+                None => bind(format!("{}", t), Param::UInt(*t as u64), mode, params)
+            }
+        }
+        Some(MySQLValue::Set(t)) => {
+            //bitmask中第n位对应第n个取值
+            let members = type_members(col_type);
+            if members.len() == 0 || (members.len() < 64 && *t >> members.len() > 0) {
+                return Some(bind(format!("{}", t), Param::UInt(*t), mode, params));
+            }
+            let v: Vec<String> = members.into_iter().enumerate()
+                .filter(|(idx, _)| *t & (1 << *idx as u64) > 0)
+                .map(|(_, m)| m)
+                .collect();
+            let v = v.join(",");
+            bind_text(v.into_bytes(), &col_meta.character_set, mode, params)
+        }
+        Some(MySQLValue::Bit(t)) => {
+            bind(format!("b'{:b}'", t), Param::UInt(*t), mode, params)
         }
         Some(MySQLValue::DateTime {year, month, day, hour, minute, second, subsecond}) => {
-            value_str.push_str(&format!("'{}-{}-{} {}:{}:{}.{}'", year,month,day,hour,minute,second,subsecond));
+            let v = format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}{}", year,month,day,hour,minute,second,fsp_str(*subsecond, fsp));
+            bind(format!("'{}'", v), Param::Bytes(v.into_bytes()), mode, params)
         }
         Some(MySQLValue::Double(t)) => {
-            value_str.push_str(&format!("{}", t));
+            bind(format!("{}", t), Param::Double(*t), mode, params)
         }
         Some(MySQLValue::Float(t)) => {
-            value_str.push_str(&format!("{}",t));
+            bind(format!("{}",t), Param::Bytes(t.to_string().into_bytes()), mode, params)
         }
         Some(MySQLValue::Year(t)) => {
-            value_str.push_str(&format!("{}",t));
+            //year字段写入0会被转换为2000，需要使用'0000'
+            if *t == 0 {
+                bind(String::from("'0000'"), Param::Bytes(b"0000".to_vec()), mode, params)
+            } else {
+                bind(format!("{}",t), Param::Int(*t as i64), mode, params)
+            }
         }
         Some(MySQLValue::Decimal(t)) => {
-            value_str.push_str(&format!("{}",t.to_f64().unwrap()));
+            //BigDecimal输出时保留原有的小数位数
+            bind(t.to_string(), Param::Bytes(t.to_string().into_bytes()), mode, params)
         }
         Some(MySQLValue::SignedInteger(t)) => {
-            value_str.push_str(&format!("{}", t));
+            bind(format!("{}", t), Param::Int(*t), mode, params)
+        }
+        Some(MySQLValue::UnsignedInteger(t)) => {
+            bind(format!("{}", t), Param::UInt(*t), mode, params)
         }
         Some(MySQLValue::Json(t)) => {
-            value_str.push_str(&format!("'{}'", serde_json::to_string(&t).unwrap()));
+            //json字段的字符集固定为utf8mb4
+            let v = serde_json::to_string(&t).unwrap();
+            bind_text(v.into_bytes(), &Some(String::from("utf8mb4")), mode, params)
         }
         Some(MySQLValue::Null) => {
-            value_str.push_str(&format!("Null"));
+            return None;
         }
-        Some(MySQLValue::Time {hours, minutes, seconds, subseconds}) => {
-            value_str.push_str(&format!("'{}:{}:{}.{}'",hours, minutes, seconds, subseconds));
+        Some(MySQLValue::Time {negative, hours, minutes, seconds, subseconds}) => {
+            let sign = if *negative { "-" } else { "" };
+            let v = format!("{}{:02}:{:02}:{:02}{}", sign, hours, minutes, seconds, fsp_str(*subseconds, fsp));
+            bind(format!("'{}'", v), Param::Bytes(v.into_bytes()), mode, params)
         }
         Some(MySQLValue::Date {year, month, day}) => {
-            value_str.push_str(&format!("'{}-{}-{}'", year, month, day));
+            let v = format!("{:04}-{:02}-{:02}", year, month, day);
+            bind(format!("'{}'", v), Param::Bytes(v.into_bytes()), mode, params)
         }
-        _ => {
-            info!("{:?}",value);
+        None => {
+            return None;
         }
+    };
+    Some(value_str)
+}
+
+///
+/// 字符串字面量转义，单引号双写，不使用反斜杠转义，sql_mode包含NO_BACKSLASH_ESCAPES时结果相同
+///     包含反斜杠或控制字符时返回None，由调用方使用16进制
+///
+fn escape_string(value: &str) -> Option<String> {
+    if value.chars().any(|c| c == '\\' || (c.is_control() && c != '\t')) {
+        return None;
     }
-    value_str
+    Some(value.replace("'", "''"))
 }
 
-fn get_insert_col_str(table_cols_info: &Vec<HashMap<String, String>>) -> String {
-    let mut col_str = format!("(");
-    let cols = table_cols_info.len();
-    for (idx,col_info) in table_cols_info.iter().enumerate() {
-        if idx < cols -1 {
-            col_str.push_str(&format!("`{}`,",col_info.get("COLUMN_NAME").unwrap()));
-        }else {
-            col_str.push_str(&format!("`{}`)",col_info.get("COLUMN_NAME").unwrap()));
+///
+/// 从column_type中获取enum/set的取值列表，如enum('a','b'), 取值中的单引号为两个单引号
+///
+fn type_members(column_type: &str) -> Vec<String> {
+    let mut members = vec![];
+    let lower = column_type.to_lowercase();
+    if !(lower.starts_with("enum(") || lower.starts_with("set(")) {
+        return members;
+    }
+    let start = column_type.find('(').unwrap() + 1;
+    let mut chars = column_type[start..].chars().peekable();
+    let mut cur = String::new();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if !quoted {
+            match c {
+                '\'' => quoted = true,
+                ')' => break,
+                _ => {}
+            }
+            continue;
+        }
+        if c == '\'' {
+            if chars.peek() == Some(&'\'') {
+                chars.next();
+                cur.push(c);
+            } else {
+                quoted = false;
+                members.push(cur.clone());
+                cur.clear();
+            }
+        } else {
+            cur.push(c);
         }
     }
-    col_str
+    members
+}
+
+fn hex_literal(value: &Vec<u8>) -> String {
+    format!("X'{}'", hex::encode(value))
+}
+
+///
+/// 字符类型的值，都带有字符集前缀，与连接的字符集无关
+///     utf8字符集、合法的utf8编码并且不需要反斜杠转义时使用字符串，便于查看
+///     其他情况使用16进制，不受sql_mode(NO_BACKSLASH_ESCAPES)影响
+///     字符集未知时只使用16进制
+///
+fn text_literal(value: &Vec<u8>, charset: &Option<String>) -> String {
+    match charset {
+        Some(c) => {
+            if c.starts_with("utf8") {
+                if let Some(v) = from_utf8(value).ok().and_then(escape_string) {
+                    return format!("_{}'{}'", c, v);
+                }
+            }
+            format!("_{} {}", c, hex_literal(value))
+        }
+        None => hex_literal(value)
+    }
+}
+
+///
+/// 库名、表名、字段名使用反引号包裹，名称中的反引号需要双写
+///
+fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace("`", "``"))
+}
+
+fn get_insert_col_str(values: &Vec<Option<MySQLValue>>, table_cols_info: &Vec<ColumnMeta>) -> String {
+    let cols: Vec<String> = table_cols_info.iter().enumerate()
+        .filter(|(idx, _)| values[*idx].is_some())
+        .map(|(_, col_info)| quote_identifier(&col_info.column_name))
+        .collect();
+    format!("({})", cols.join(","))
 }
 
 
 pub fn out_update(
     befor_value: &Vec<Option<MySQLValue>>,
     after_value: &Vec<Option<MySQLValue>>,
-    table_cols_info: &Vec<HashMap<String, String>>,
-    pri_info: &HashMap<String, usize>,
-    map: &TableMap) -> String {
-
-    let mut sql = format!("UPDATE {}.{} SET ", map.database_name,map.table_name);
-    let where_str = get_where_str(befor_value, table_cols_info, pri_info);
-    //String::from("{:?}",befor_value)
-    let set_str = get_set_str(after_value, table_cols_info, map);
-    sql.push_str(&set_str);
-    sql.push_str(&where_str);
-    sql
+    table_cols_info: &Vec<ColumnMeta>,
+    key_info: &Vec<usize>,
+    map: &TableMap, mode: &RenderMode) -> SqlStatement {
+
+    let mut stmt = SqlStatement::new(format!("UPDATE {}.{} SET ", quote_identifier(&map.database_name), quote_identifier(&map.table_name)));
+    //参数顺序需与sql中?的顺序一致，先set后where
+    let set_str = get_set_str(after_value, table_cols_info, map, mode, &mut stmt.params);
+    let where_str = get_where_str(befor_value, table_cols_info, key_info, map, mode, &mut stmt.params);
+    stmt.sql.push_str(&set_str);
+    stmt.sql.push_str(&where_str);
+    stmt
 }
 
-fn get_set_str(value: &Vec<Option<MySQLValue>>,table_cols_info: &Vec<HashMap<String, String>>, _map: &TableMap) -> String {
-    let mut set_str = "".to_string();
-    let value_len = value.iter().len();
+fn get_set_str(value: &Vec<Option<MySQLValue>>,table_cols_info: &Vec<ColumnMeta>, map: &TableMap, mode: &RenderMode, params: &mut Vec<Param>) -> String {
+    let mut set_list = vec![];
     for (idx, v) in value.iter().enumerate() {
-        let col = table_cols_info[idx].get("COLUMN_NAME").unwrap();
-        let col_type = table_cols_info[idx].get("COLUMN_TYPE").unwrap();
-        set_str.push_str(&get_value_str(v, col, &col_type, GetType::GetSet));
-        if idx < value_len - 1 {
-            set_str.push_str(", ");
+        //after image中只有被修改的字段时只set这些字段
+        if v.is_none() {
+            continue;
         }
+        set_list.push(get_value_str(v, &table_cols_info[idx], column_fsp(map, idx), GetType::GetSet, mode, params));
     }
-    set_str
+    set_list.join(", ")
 }
 
 
-fn get_where_str(value: &Vec<Option<MySQLValue>>,table_cols_info: &Vec<HashMap<String, String>>, pri_info: &HashMap<String, usize>) -> String {
+fn get_where_str(value: &Vec<Option<MySQLValue>>,table_cols_info: &Vec<ColumnMeta>, key_info: &Vec<usize>, map: &TableMap, mode: &RenderMode, params: &mut Vec<Param>) -> String {
     let mut where_str = " WHERE ".to_string();
-    let cols = pri_info.len();
-    if pri_info.len() > 0 {
-        let mut tmp = 1;
-        for (col, idx) in pri_info{
+    //索引字段需要都在before image中，否则使用image中的全部字段匹配
+    if key_info.len() > 0 && key_info.iter().all(|idx| value[*idx].is_some()) {
+        let mut where_list = vec![];
+        for idx in key_info{
             let value = &value[*idx];
-            let col_type = table_cols_info[*idx].get("COLUMN_TYPE").unwrap();
-            where_str.push_str(&get_value_str(value, col, col_type,GetType::GetWhere));
-            if tmp < cols{
-                where_str.push_str(" AND ");
-            }
-            else {
-                where_str.push_str(";");
-            }
-            tmp += 1;
+            where_list.push(get_value_str(value, &table_cols_info[*idx], column_fsp(map, *idx), GetType::GetWhere, mode, params));
         }
+        where_str.push_str(&where_list.join(" AND "));
+        where_str.push_str(";");
     }
 
 //    if pri.len() > 0 {
 //        let value = &value[pri_idex];
-//        let col_type = table_cols_info[pri_idex].get("COLUMN_TYPE").unwrap();
+//        let col_type = table_cols_info[pri_idex].column_type;
 //        where_str.push_str(&get_value_str(value, pri, col_type,GetType::GetWhere));
 //        where_str.push_str(";");
 //    }
     else {
-        let value_len = value.iter().len();
+        //没有主键及not null的唯一索引时使用全部字段匹配，只修改一行
+        //  float保存的是近似值，输出的字面量无法精确匹配，不作为条件(只有float字段时除外)
+        let float_col = |idx: usize| table_cols_info[idx].column_type.to_lowercase().starts_with("float");
+        let skip_float = value.iter().enumerate().any(|(idx, v)| v.is_some() && !float_col(idx));
+        let mut where_list = vec![];
         for (idx, v) in value.iter().enumerate(){
-            let col = table_cols_info[idx].get("COLUMN_NAME").unwrap();
-            let col_type = table_cols_info[idx].get("COLUMN_TYPE").unwrap();
-            where_str.push_str(get_value_str(v, col, &col_type, GetType::GetWhere).as_ref());
-            if idx < value_len - 1 {
-                where_str.push_str(" AND ");
-            } else {
-                where_str.push_str(";");
+            if v.is_none() || (skip_float && float_col(idx)) {
+                continue;
             }
+            where_list.push(get_value_str(v, &table_cols_info[idx], column_fsp(map, idx), GetType::GetNullSafe, mode, params));
         }
+        where_str.push_str(&where_list.join(" AND "));
+        where_str.push_str(" LIMIT 1;");
     }
 
     where_str
 }
 
-fn get_value_str(value: &Option<MySQLValue>,col: &String, col_type: &String, get_type: GetType) -> String {
-    let mut where_str = String::from("");
-    match value {
-        Some(MySQLValue::String(t)) => {
-            where_str.push_str(&format!("`{}`='{}'",col, t));
-        }
-        Some(MySQLValue::Blob(t)) => {
-            let col_type = col_type;
-            match col_type.find("text") {
-                Some(_) => {
-                    where_str.push_str(&format!("`{}`='{}'",col, from_utf8(t).unwrap()));
-                    return where_str;
-                }
-                None => {}
-            }
-            match col_type.find("char") {
-                Some(_) => {
-                    where_str.push_str(&format!("`{}`='{}'",col, from_utf8(t).unwrap()));
-                    return where_str;
-                }
-                None => {}
-            }
-
-            if t.len()> 0{
-                where_str.push_str(&format!("`{}`=0x{}",col,hex::encode(t)));
-            }else {
-                match get_type {
-                    GetType::GetWhere => where_str.push_str(&format!("`{}` is Null",col)),
-                    GetType::GetSet => where_str.push_str(&format!("`{}`=Null",col)),
-                }
+fn get_value_str(value: &Option<MySQLValue>, col_meta: &ColumnMeta, fsp: usize, get_type: GetType, mode: &RenderMode, params: &mut Vec<Param>) -> String {
+    let col = quote_identifier(&col_meta.column_name);
+    match get_values_info(value, col_meta, fsp, mode, params) {
+        Some(v) => {
+            match get_type {
+                //json字段与字符串比较时按字符串比较，需要转换为json
+                GetType::GetNullSafe if col_meta.column_type.to_lowercase().starts_with("json") => format!("{}<=>CAST({} AS JSON)", col, v),
+                GetType::GetNullSafe => format!("{}<=>{}", col, v),
+                _ => format!("{}={}", col, v),
             }
         }
-        Some(MySQLValue::Timestamp {unix_time, subsecond}) => {
-            where_str.push_str(&format!("`{}`=from_unixtime({}.{})", col, unix_time, subsecond));
-        }
-        Some(MySQLValue::Enum(t)) => {
-            where_str.push_str(&format!("`{}`={}",col,t));
-        }
-        Some(MySQLValue::DateTime {year, month, day, hour, minute, second, subsecond}) => {
-            where_str.push_str(&format!("`{}`='{}-{}-{} {}:{}:{}.{}'", col,year,month,day,hour,minute,second,subsecond));
-        }
-        Some(MySQLValue::Double(t)) => {
-            where_str.push_str(&format!("`{}`={}",col, t));
-        }
-        Some(MySQLValue::Float(t)) => {
-            where_str.push_str(&format!("`{}`={}",col, t));
-        }
-        Some(MySQLValue::Year(t)) => {
-            where_str.push_str(&format!("`{}`={}",col, t));
-        }
-        Some(MySQLValue::Decimal(t)) => {
-            where_str.push_str(&format!("`{}`={}",col, t.to_f64().unwrap()));
-        }
-        Some(MySQLValue::SignedInteger(t)) => {
-            where_str.push_str(&format!("`{}`={}",col, t));
-        }
-        Some(MySQLValue::Json(t)) => {
-            where_str.push_str(&format!("`{}`='{}'",col, serde_json::to_string(&t).unwrap()));
-        }
-        Some(MySQLValue::Null) => {
+        None => {
             match get_type {
-                GetType::GetWhere => where_str.push_str(&format!("`{}` is Null",col)),
-                GetType::GetSet => where_str.push_str(&format!("`{}`=Null",col)),
+                GetType::GetWhere => format!("{} is Null",col),
+                GetType::GetNullSafe => format!("{}<=>Null",col),
+                GetType::GetSet => format!("{}=Null",col),
             }
         }
-        Some(MySQLValue::Time {hours, minutes, seconds, subseconds}) => {
-            where_str.push_str(&format!("`{}`='{}:{}:{}.{}'",col, hours, minutes, seconds, subseconds));
-        }
-        Some(MySQLValue::Date {year, month, day}) => {
-            where_str.push_str(&format!("`{}`='{}-{}-{}'",col, year, month, day));
-        }
-        _ => {
-            info!("{:?}",value);
-        }
     }
-    where_str
-}
\ No newline at end of file
+}
@@ -4,326 +4,460 @@
 */
 
 use crate::meta::JsonType;
-use crate::readvalue;
-use std::io::Read;
-use byteorder::{ReadBytesExt, LittleEndian};
-use std::process;
+use crate::binlog::parsevalue::read_bytes;
+use std::io::{Read, Cursor};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use crate::binlog::error::{BinlogError, BinlogResult};
 use serde_json::Value as JsonValue;
 use serde_json::map::Map as JsonMap;
-use failure::_core::iter::FromIterator;
 
-pub fn read_binary_json<R: Read>(buf: &mut R, var_length: &usize) -> JsonValue {
-    let t = buf.read_u8().unwrap() as usize;
-    read_binary_json_type(buf, var_length, &t)
-}
+/*
+mysql binary json格式:
+    type : 1bytes
+    value :
+        object/array: element_count, size (small为2bytes, large为4bytes)
+                      key_entry: key_offset(2/4bytes) key_length(2bytes)  (object only)
+                      value_entry: type(1bytes) offset_or_inlined_value(2/4bytes)
+                      key
+                      value
+        literal: 1bytes (0x00 null, 0x01 true, 0x02 false)
+        int16/uint16/int32/uint32/int64/uint64/double: 小端
+        string: 变长长度(每个字节7bit) + data
+    object/array中的offset都是相对于该object/array开始的位置(不包含type)
+    literal/int16/uint16直接存放在value_entry中，large时int32/uint32也直接存放
+*/
 
-fn read_binary_json_type<R: Read>(buf: &mut R, var_length: &usize, m: &usize) -> JsonValue {
-    let var_length = var_length.clone() - 1;
-    let json_type_code = JsonType::from_type_code(m);
-    let mut large = false;
-    match json_type_code {
-        JsonType::JsonbTypeLargeObject |
-        JsonType::JsonbTypeLargeArray => {
-            large = true;
-        }
-        _ => {
-            large = false;
-        }
+pub fn read_binary_json<R: Read>(buf: &mut R, var_length: &usize) -> BinlogResult<JsonValue> {
+    //长度为0的json值按null处理，与mysql一致
+    if *var_length == 0 {
+        return Ok(JsonValue::Null);
     }
+    let data = read_bytes(buf, *var_length)?;
+    read_binary_json_type(data[0], &data[1..])
+}
 
-    match json_type_code {
-        JsonType::JsonbTypeLargeObject |
-        JsonType::JsonbTypeSmallObject => {
-            JsonValue::from(read_binary_json_object(buf, &var_length, &large))
-        }
-        JsonType::JsonbTypeLargeArray |
-        JsonType::JsonbTypeSmallArray => {
-            JsonValue::from(read_binary_json_array(buf, &var_length, &large))
-        }
+fn read_binary_json_type(type_code: u8, data: &[u8]) -> BinlogResult<JsonValue> {
+    let json_type_code = JsonType::from_type_code(&(type_code as usize));
+    let mut cur = Cursor::new(data);
+    let value = match json_type_code {
+        JsonType::JsonbTypeSmallObject => read_binary_json_container(data, false, true)?,
+        JsonType::JsonbTypeLargeObject => read_binary_json_container(data, true, true)?,
+        JsonType::JsonbTypeSmallArray => read_binary_json_container(data, false, false)?,
+        JsonType::JsonbTypeLargeArray => read_binary_json_container(data, true, false)?,
         JsonType::JsonbTypeString => {
-            let mut byte = 0x80 as usize;
-            let mut length = 0 as usize;
-            let mut bits_read = 0 as usize;
-            while byte & 0x80 != 0{
-                byte = buf.read_u8().unwrap() as usize;
-                length = length | ((byte & 0x7f) << bits_read);
-                bits_read = bits_read + 7;
-            }
-            JsonValue::from(readvalue::read_string_value_from_len(buf, length))
+            let length = read_variable_length(&mut cur)?;
+            let start = cur.position() as usize;
+            JsonValue::from(String::from_utf8_lossy(slice(data, start, length)?).to_string())
         }
         JsonType::JsonbTypeLiteral => {
-            let value = buf.read_u8().unwrap() as usize;
-            let type_code = JsonType::from_type_code(&value);
-            match type_code {
-                JsonType::JsonbLiteralNull => JsonValue::Null,
-                JsonType::JsonbLiteralTrue => JsonValue::from(true),
-                JsonType::JsonbLiteralFalse => JsonValue::from(false),
-                _ => {JsonValue::Null}
+            match cur.read_u8()? {
+                0x00 => JsonValue::Null,
+                0x01 => JsonValue::from(true),
+                0x02 => JsonValue::from(false),
+                v => return Err(BinlogError::corrupt(format!("invalid json literal: {}", v)))
             }
         }
-        JsonType::JsonbTypeInt16 => {
-            JsonValue::from(buf.read_i16::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeUint16 => {
-            JsonValue::from(buf.read_u16::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeDouble => {
-            JsonValue::from(buf.read_f64::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeInt32 => {
-            JsonValue::from(buf.read_i32::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeUint32 => {
-            JsonValue::from(buf.read_u32::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeInt64 =>{
-            JsonValue::from(buf.read_i64::<LittleEndian>().unwrap())
-        }
-        JsonType::JsonbTypeUint64 => {
-            JsonValue::from(buf.read_u64::<LittleEndian>().unwrap())
-        }
+        JsonType::JsonbTypeInt16 => JsonValue::from(cur.read_i16::<LittleEndian>()?),
+        JsonType::JsonbTypeUint16 => JsonValue::from(cur.read_u16::<LittleEndian>()?),
+        JsonType::JsonbTypeInt32 => JsonValue::from(cur.read_i32::<LittleEndian>()?),
+        JsonType::JsonbTypeUint32 => JsonValue::from(cur.read_u32::<LittleEndian>()?),
+        JsonType::JsonbTypeInt64 => JsonValue::from(cur.read_i64::<LittleEndian>()?),
+        JsonType::JsonbTypeUint64 => JsonValue::from(cur.read_u64::<LittleEndian>()?),
+        JsonType::JsonbTypeDouble => JsonValue::from(cur.read_f64::<LittleEndian>()?),
         _ => {
-            info!("无效的json格式:{:?}",json_type_code);
-            process::exit(1)
+            return Err(BinlogError::unsupported(format!("json type {:?}", json_type_code)));
         }
-    }
+    };
+    Ok(value)
 }
 
-fn read_binary_json_object<R: Read>(buf: &mut R, var_length: &usize, large: &bool) -> JsonValue {
-    let elements: usize;
-    let size: usize;
-
-    if *large {
-        elements = buf.read_u32::<LittleEndian>().unwrap() as usize;
-        size = buf.read_u32::<LittleEndian>().unwrap() as usize;
-    } else {
-        elements = buf.read_u16::<LittleEndian>().unwrap() as usize;
-        size = buf.read_u16::<LittleEndian>().unwrap() as usize;
-    }
-
-    if size > *var_length {
-        info!("json长度大于包长度, 现在退出程序！！！");
-        process::exit(1);
+///
+/// 取data中[start, start+length)的部分，越界时说明数据损坏
+///
+fn slice(data: &[u8], start: usize, length: usize) -> BinlogResult<&[u8]> {
+    match start.checked_add(length) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(BinlogError::corrupt(format!("json offset {} length {} out of range {}", start, length, data.len())))
     }
+}
 
-    let mut key_offset_lengths = vec![];
-    if *large{
-        for _i in 0..elements{
-            let mut tmp_dict: Vec<usize> = vec![];
-            tmp_dict.push(buf.read_u32::<LittleEndian>().unwrap() as usize);
-            tmp_dict.push(buf.read_u16::<LittleEndian>().unwrap() as usize);
-            key_offset_lengths.push(tmp_dict);
-
-        }
-    } else {
-        for _i in 0..elements{
-            let mut tmp_dict: Vec<usize> = vec![];
-            tmp_dict.push(buf.read_u16::<LittleEndian>().unwrap() as usize);
-            tmp_dict.push(buf.read_u16::<LittleEndian>().unwrap() as usize);
-            key_offset_lengths.push(tmp_dict);
+fn read_variable_length<R: Read>(buf: &mut R) -> BinlogResult<usize> {
+    let mut length = 0usize;
+    for i in 0..5 {
+        let byte = buf.read_u8()? as usize;
+        length |= (byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(length);
         }
     }
+    Err(BinlogError::corrupt("invalid json variable length"))
+}
 
-    let mut value_type_inlined_lengths: Vec<ValuesTypeInline> = vec![];
-    for _i in 0..elements {
-        let tmp = ValuesTypeInline::new(buf, large);
-        value_type_inlined_lengths.push(tmp);
+///
+/// value_entry中直接存放值的类型
+///
+fn is_inlined(type_code: u8, large: bool) -> bool {
+    match JsonType::from_type_code(&(type_code as usize)) {
+        JsonType::JsonbTypeLiteral |
+        JsonType::JsonbTypeInt16 |
+        JsonType::JsonbTypeUint16 => true,
+        JsonType::JsonbTypeInt32 |
+        JsonType::JsonbTypeUint32 => large,
+        _ => false
     }
+}
+
+fn read_offset(data: &[u8], pos: usize, large: bool) -> BinlogResult<usize> {
+    let mut cur = Cursor::new(slice(data, pos, if large { 4 } else { 2 })?);
+    Ok(match large {
+        true => cur.read_u32::<LittleEndian>()? as usize,
+        false => cur.read_u16::<LittleEndian>()? as usize
+    })
+}
 
-    let mut keys: Vec<String> = vec![];
-    for v in key_offset_lengths.iter(){
-        keys.push(readvalue::read_string_value_from_len(buf, v[1]));
+fn read_binary_json_container(data: &[u8], large: bool, object: bool) -> BinlogResult<JsonValue> {
+    let offset_size = if large { 4 } else { 2 };
+    let elements = read_offset(data, 0, large)?;
+    let size = read_offset(data, offset_size, large)?;
+    if size > data.len() {
+        return Err(BinlogError::corrupt(format!("json container size {} is larger than value length {}", size, data.len())));
     }
+    let data = &data[..size];
+    let key_entry_size = offset_size + 2;
+    let value_entry_size = offset_size + 1;
+    let key_entries = 2 * offset_size;
+    let value_entries = key_entries + if object { elements * key_entry_size } else { 0 };
 
-    let mut values = Vec::with_capacity(elements);
-    for i in 0..elements{
-        let value_inlined = &value_type_inlined_lengths[i];
-        match value_inlined.b {
-            None => {
-                let data = &value_inlined.c;
-                match data.a {
-                    None => {
-                        if data.b == 0 {
-                            values.push(JsonValue::from(false));
-                        }else if data.b == 1 {
-                            values.push(JsonValue::from(true));
-                        }else {
-                            values.push(JsonValue::Null);
-                        }
-                    }
-                    Some(v) => {
-                        values.push(JsonValue::from(v));
-                    }
-                }
-            }
-            _ => {
-                let t = value_inlined.a;
-                let data = read_binary_json_type(buf,var_length,&t);
-                values.push(JsonValue::from(data));
+    let mut map = JsonMap::new();
+    let mut values = vec![];
+    for i in 0..elements {
+        let entry = value_entries + i * value_entry_size;
+        let type_code = slice(data, entry, 1)?[0];
+        let value = if is_inlined(type_code, large) {
+            read_binary_json_type(type_code, slice(data, entry + 1, offset_size)?)?
+        } else {
+            let offset = read_offset(data, entry + 1, large)?;
+            if offset >= data.len() {
+                return Err(BinlogError::corrupt(format!("json value offset {} out of range {}", offset, data.len())));
             }
+            read_binary_json_type(type_code, &data[offset..])?
+        };
+
+        if object {
+            let entry = key_entries + i * key_entry_size;
+            let key_offset = read_offset(data, entry, large)?;
+            let key_length = read_offset(data, entry + offset_size, false)?;
+            let key = String::from_utf8_lossy(slice(data, key_offset, key_length)?).to_string();
+            map.insert(key, value);
+        } else {
+            values.push(value);
         }
     }
 
-    if keys.len() > 0 {
-        let map = JsonMap::from_iter(keys.into_iter().zip(values.into_iter()));
-        JsonValue::Object(map)
-    }else {
-        JsonValue::Array(values)
+    match object {
+        true => Ok(JsonValue::Object(map)),
+        false => Ok(JsonValue::Array(values))
     }
-
-
-
 }
-#[derive(Debug)]
-struct LiteralInline {
-    a: Option<usize>, // default： None
-    b: i8, // 0: false, 1: true, -1: default
+
+///
+/// 把json值编码为mysql binary json，partial update回滚时需要写回完整的json值
+///     object的key按长度、字节序排序，与mysql一致(mysql读取时二分查找key)
+///
+pub fn write_binary_json(value: &JsonValue) -> BinlogResult<Vec<u8>> {
+    let (type_code, data) = write_binary_json_type(value)?;
+    let mut pack = vec![type_code];
+    pack.extend(data);
+    Ok(pack)
 }
-impl LiteralInline{
-    fn new<R: Read>(buf: &mut R, type_code: &JsonType) -> LiteralInline{
-        let mut a = None;
-        let mut b = -1;
-        match type_code {
-            JsonType::JsonbTypeLiteral => {
-                let value = buf.read_u16::<LittleEndian>().unwrap() as usize;
-                let code = JsonType::from_type_code(&value);
-                match code {
-                    JsonType::JsonbLiteralNull => {
-                        a = None;
-                    },
-                    JsonType::JsonbLiteralTrue => {
-                        b = 1;
-                    },
-                    JsonType::JsonbLiteralFalse => {
-                        b = 0;
-                    },
-                    _ => {}
+
+fn write_binary_json_type(value: &JsonValue) -> BinlogResult<(u8, Vec<u8>)> {
+    let mut pack = vec![];
+    let type_code = match value {
+        JsonValue::Null => { pack.push(0x00); 0x04 }
+        JsonValue::Bool(true) => { pack.push(0x01); 0x04 }
+        JsonValue::Bool(false) => { pack.push(0x02); 0x04 }
+        JsonValue::Number(n) => {
+            if let Some(v) = n.as_i64() {
+                if v >= i16::min_value() as i64 && v <= i16::max_value() as i64 {
+                    pack.write_i16::<LittleEndian>(v as i16)?;
+                    0x05
+                } else if v >= i32::min_value() as i64 && v <= i32::max_value() as i64 {
+                    pack.write_i32::<LittleEndian>(v as i32)?;
+                    0x07
+                } else {
+                    pack.write_i64::<LittleEndian>(v)?;
+                    0x09
                 }
+            } else if let Some(v) = n.as_u64() {
+                pack.write_u64::<LittleEndian>(v)?;
+                0x0A
+            } else {
+                pack.write_f64::<LittleEndian>(n.as_f64().unwrap_or(0.0))?;
+                0x0B
             }
-            JsonType::JsonbTypeInt16 => {
-                let tmp = buf.read_i16::<LittleEndian>().unwrap() as usize;
-                a = Some(tmp);
-            },
-            JsonType::JsonbTypeUint16 => {
-                let tmp = buf.read_u16::<LittleEndian>().unwrap() as usize;
-                a = Some(tmp);
-            },
-            JsonType::JsonbTypeInt32 => {
-                let tmp = buf.read_i32::<LittleEndian>().unwrap() as usize;
-                a = Some(tmp);
-            },
-            JsonType::JsonbTypeUint32 => {
-                let tmp = buf.read_u32::<LittleEndian>().unwrap() as usize;
-                a = Some(tmp);
+        }
+        JsonValue::String(s) => {
+            let mut length = s.len();
+            loop {
+                let byte = (length & 0x7f) as u8;
+                length >>= 7;
+                if length == 0 {
+                    pack.push(byte);
+                    break;
+                }
+                pack.push(byte | 0x80);
             }
-            _ => {}
+            pack.extend(s.as_bytes());
+            0x0C
         }
+        JsonValue::Array(items) => {
+            let values: Vec<&JsonValue> = items.iter().collect();
+            return match write_binary_json_container(&vec![], &values, false)? {
+                Some(data) => Ok((0x02, data)),
+                None => Ok((0x03, write_binary_json_container(&vec![], &values, true)?.unwrap_or_default()))
+            };
+        }
+        JsonValue::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort_by(|a, b| a.len().cmp(&b.len()).then(a.as_bytes().cmp(b.as_bytes())));
+            let values: Vec<&JsonValue> = keys.iter().map(|k| &map[k.as_str()]).collect();
+            return match write_binary_json_container(&keys, &values, false)? {
+                Some(data) => Ok((0x00, data)),
+                None => Ok((0x01, write_binary_json_container(&keys, &values, true)?.unwrap_or_default()))
+            };
+        }
+    };
+    Ok((type_code, pack))
+}
 
-        LiteralInline{
-            a,
-            b,
+///
+/// 编码object/array，small格式放不下(超过64k)时返回None，由调用方改用large格式
+///
+fn write_binary_json_container(keys: &Vec<&String>, values: &Vec<&JsonValue>, large: bool) -> BinlogResult<Option<Vec<u8>>> {
+    let offset_size = if large { 4 } else { 2 };
+    let max_offset = if large { u32::max_value() as usize } else { u16::max_value() as usize };
+    let elements = values.len();
+    let mut header_size = 2 * offset_size + elements * (offset_size + 1);
+    if keys.len() > 0 {
+        header_size += elements * (offset_size + 2);
+    }
+
+    let mut key_entries = vec![];
+    let mut body = vec![];
+    for key in keys {
+        if key.len() > u16::max_value() as usize {
+            return Err(BinlogError::unsupported(format!("json key length {}", key.len())));
         }
+        write_offset(&mut key_entries, header_size + body.len(), large);
+        key_entries.write_u16::<LittleEndian>(key.len() as u16)?;
+        body.extend(key.as_bytes());
     }
 
-    fn default() -> LiteralInline {
-        LiteralInline{
-            a: None,
-            b: -1
+    let mut value_entries = vec![];
+    for value in values {
+        let (type_code, data) = write_binary_json_type(value)?;
+        value_entries.push(type_code);
+        if is_inlined(type_code, large) {
+            let mut inlined = data.clone();
+            inlined.resize(offset_size, 0);
+            value_entries.extend(inlined);
+        } else {
+            write_offset(&mut value_entries, header_size + body.len(), large);
+            body.extend(data);
         }
     }
 
+    let size = header_size + body.len();
+    if size > max_offset {
+        return match large {
+            true => Err(BinlogError::unsupported(format!("json value size {}", size))),
+            false => Ok(None)
+        };
+    }
+    let mut pack = vec![];
+    write_offset(&mut pack, elements, large);
+    write_offset(&mut pack, size, large);
+    pack.extend(key_entries);
+    pack.extend(value_entries);
+    pack.extend(body);
+    Ok(Some(pack))
 }
-#[derive(Debug)]
-struct ValuesTypeInline {
-    a: usize,
-    b: Option<usize>,
-    c: LiteralInline,
-}
-impl ValuesTypeInline{
-    fn new<R: Read>(buf: &mut R, large: &bool) -> ValuesTypeInline {
-        let a = buf.read_u8().unwrap() as usize;
-        let mut b = None;
-        let mut c = LiteralInline::default();
-        let json_type_code = JsonType::from_type_code(&a);
-        match json_type_code {
-            JsonType::JsonbTypeLiteral |
-            JsonType::JsonbTypeInt16 |
-            JsonType::JsonbTypeUint16 => {
-                c = LiteralInline::new(buf,&json_type_code);
-                return ValuesTypeInline{a, b, c};
-            }
-            JsonType::JsonbTypeInt32 |
-            JsonType::JsonbTypeUint32 => {
-                if *large{
-                    c = LiteralInline::new(buf,&json_type_code);
-                    return ValuesTypeInline{a, b, c};
-                }
-            }
-            _ => {}
-        }
-        if *large{
-            b = Some(buf.read_u32::<LittleEndian>().unwrap() as usize);
-            ValuesTypeInline{a, b, c}
-        }
-        else {
-            b = Some(buf.read_u16::<LittleEndian>().unwrap() as usize);
-            ValuesTypeInline{a, b, c}
-        }
 
+fn write_offset(pack: &mut Vec<u8>, value: usize, large: bool) {
+    match large {
+        true => pack.extend(&(value as u32).to_le_bytes()),
+        false => pack.extend(&(value as u16).to_le_bytes())
     }
+}
 
+/*
+partial json update(PARTIAL_UPDATE_ROWS_EVENT)中after image的json字段只记录修改部分:
+    length : 字段meta个bytes
+    diff :
+        operation : 1bytes (0 replace, 1 insert, 2 remove)
+        path_length : packed int
+        path : 如$.a[1]."b c"
+        value_length : packed int (remove没有)
+        value : binary json (remove没有)
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonDiffOperation {
+    Replace,
+    Insert,
+    Remove,
 }
 
-fn read_binary_json_array<R: Read>(buf: &mut R, var_length: &usize, large: &bool) -> JsonValue {
-    let elements: usize;
-    let size: usize;
-    if *large{
-        elements = buf.read_u32::<LittleEndian>().unwrap() as usize;
-        size = buf.read_u32::<LittleEndian>().unwrap() as usize;
-    }
-    else {
-        elements = buf.read_u16::<LittleEndian>().unwrap() as usize;
-        size = buf.read_u16::<LittleEndian>().unwrap() as usize;
-    }
-    if size > *var_length{
-        info!("json长度大于包长度, 现在退出程序！！！");
-        process::exit(1);
-    }
+#[derive(Debug, Clone)]
+pub struct JsonDiff {
+    pub operation: JsonDiffOperation,
+    pub path: String,
+    pub value: Option<JsonValue>,
+}
 
-    let mut value_type_inlined_lengths: Vec<ValuesTypeInline> = vec![];
-    for _i in 0..elements {
-        let tmp = ValuesTypeInline::new(buf, large);
-        value_type_inlined_lengths.push(tmp);
+pub fn read_json_diff(data: &Vec<u8>) -> BinlogResult<Vec<JsonDiff>> {
+    let mut cur = Cursor::new(data);
+    let mut diffs = vec![];
+    while (cur.position() as usize) < data.len() {
+        let operation = match cur.read_u8()? {
+            0 => JsonDiffOperation::Replace,
+            1 => JsonDiffOperation::Insert,
+            2 => JsonDiffOperation::Remove,
+            v => return Err(BinlogError::corrupt(format!("invalid json diff operation: {}", v)))
+        };
+        let path_length = crate::binlog::readevent::TableMap::read_packed_int(&mut cur)? as usize;
+        let path = String::from_utf8_lossy(&read_bytes(&mut cur, path_length)?).to_string();
+        let value = match operation {
+            JsonDiffOperation::Remove => None,
+            _ => {
+                let value_length = crate::binlog::readevent::TableMap::read_packed_int(&mut cur)? as usize;
+                Some(read_binary_json(&mut cur, &value_length)?)
+            }
+        };
+        diffs.push(JsonDiff{ operation, path, value });
     }
+    Ok(diffs)
+}
 
-    let mut values = Vec::with_capacity(elements);
-    for i in 0..elements {
-        let value_inlined = &value_type_inlined_lengths[i];
-        match value_inlined.b {
-            None => {
-                let data = &value_inlined.c;
-                match data.a {
-                    None => {
-                        if data.b == 0 {
-                            values.push(JsonValue::from(false));
-                        }else if data.b == 1 {
-                            values.push(JsonValue::from(true));
-                        }else {
-                            values.push(JsonValue::Null);
-                        }
+#[derive(Debug)]
+enum JsonPathLeg {
+    Key(String),
+    Index(usize),
+}
+
+///
+/// 解析diff中的路径，mysql写入的路径只包含$、.key、."key"、[n]
+///
+fn parse_json_path(path: &str) -> BinlogResult<Vec<JsonPathLeg>> {
+    let err = || BinlogError::corrupt(format!("invalid json path: {}", path));
+    let chars: Vec<char> = path.trim().chars().collect();
+    if chars.first() != Some(&'$') {
+        return Err(err());
+    }
+    let mut legs = vec![];
+    let mut i = 1;
+    while i < chars.len() {
+        match chars[i] {
+            '.' if i + 1 < chars.len() && chars[i + 1] == '"' => {
+                let mut key = String::new();
+                i += 2;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' {
+                        i += 1;
                     }
-                    Some(v) => {
-                        values.push(JsonValue::from(v));
+                    if i < chars.len() {
+                        key.push(chars[i]);
                     }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(err());
                 }
+                legs.push(JsonPathLeg::Key(key));
+                i += 1;
             }
-            _ => {
-                let t = value_inlined.a;
-                let data = read_binary_json_type(buf,var_length,&t);
-                values.push(JsonValue::from(data));
+            '.' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != '.' && chars[i] != '[' {
+                    i += 1;
+                }
+                if i == start {
+                    return Err(err());
+                }
+                legs.push(JsonPathLeg::Key(chars[start..i].iter().collect()));
+            }
+            '[' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != ']' {
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(err());
+                }
+                let index: String = chars[start..i].iter().collect();
+                legs.push(JsonPathLeg::Index(index.trim().parse().map_err(|_| err())?));
+                i += 1;
             }
+            _ => return Err(err())
         }
     }
+    Ok(legs)
+}
 
-    JsonValue::Array(values)
+///
+/// 在before image的json值上应用diff得到完整的after image
+///
+pub fn apply_json_diff(doc: &mut JsonValue, diffs: &Vec<JsonDiff>) -> BinlogResult<()> {
+    for diff in diffs {
+        let mut legs = parse_json_path(&diff.path)?;
+        let value = diff.value.clone().unwrap_or(JsonValue::Null);
+        let last = match legs.pop() {
+            Some(l) => l,
+            None => {
+                //路径为$，替换整个文档
+                if diff.operation != JsonDiffOperation::Remove {
+                    *doc = value;
+                }
+                continue;
+            }
+        };
+        let mut parent = &mut *doc;
+        for leg in legs.iter() {
+            let next = match (leg, parent) {
+                (JsonPathLeg::Key(k), JsonValue::Object(map)) => map.get_mut(k),
+                (JsonPathLeg::Index(i), JsonValue::Array(arr)) => arr.get_mut(*i),
+                _ => None
+            };
+            parent = match next {
+                Some(v) => v,
+                None => return Err(BinlogError::corrupt(format!("json diff path {} does not exist in before image", diff.path)))
+            };
+        }
+        match (last, parent, &diff.operation) {
+            (JsonPathLeg::Key(k), JsonValue::Object(map), JsonDiffOperation::Remove) => { map.remove(&k); }
+            (JsonPathLeg::Key(k), JsonValue::Object(map), _) => { map.insert(k, value); }
+            (JsonPathLeg::Index(i), JsonValue::Array(arr), JsonDiffOperation::Remove) => {
+                if i < arr.len() {
+                    arr.remove(i);
+                }
+            }
+            (JsonPathLeg::Index(i), JsonValue::Array(arr), JsonDiffOperation::Insert) => {
+                if i < arr.len() {
+                    arr.insert(i, value);
+                } else {
+                    arr.push(value);
+                }
+            }
+            (JsonPathLeg::Index(i), JsonValue::Array(arr), JsonDiffOperation::Replace) => {
+                match arr.get_mut(i) {
+                    Some(v) => *v = value,
+                    None => return Err(BinlogError::corrupt(format!("json diff path {} does not exist in before image", diff.path)))
+                }
+            }
+            _ => return Err(BinlogError::corrupt(format!("json diff path {} does not match before image", diff.path)))
+        }
+    }
+    Ok(())
 }
@@ -5,7 +5,7 @@
 use serde_json;
 use bigdecimal;
 use serde::{Serialize,Serializer};
-use crate::binlog::readevent::{TableMap, EventHeader, BinlogEvent, Tell};
+use crate::binlog::readevent::{TableMap, ColumnInfo, EventHeader, BinlogEvent, Tell};
 use crate::meta::ColumnTypeDict;
 use crate::{readvalue};
 use crate::binlog::jsonb;
@@ -13,6 +13,7 @@ use bigdecimal::BigDecimal;
 use std::io::{Read, Cursor, Seek};
 use byteorder::{ReadBytesExt, BigEndian, LittleEndian};
 use std::io;
+use crate::binlog::error::{BinlogError, BinlogResult};
 
 #[derive(Debug)]
 pub struct DecimalMeta{
@@ -24,7 +25,13 @@ pub struct DecimalMeta{
     pub bytes_to_read: usize
 }
 impl DecimalMeta{
-    pub fn new(precision: u8, decimal: u8) -> DecimalMeta{
+    ///
+    /// precision、decimal来自table map中的column meta，不合法时返回错误，避免计算出错误的长度导致后面的字段错位
+    ///
+    pub fn new(precision: u8, decimal: u8) -> BinlogResult<DecimalMeta>{
+        if decimal > precision || precision > 65 {
+            return Err(BinlogError::corrupt(format!("invalid decimal meta: precision {}, decimal {}", precision, decimal)));
+        }
         let decimal_digits_per_integer = 9;
         let compressed_byte_map = [0usize, 1, 1, 2, 2, 3, 3, 4, 4, 4];
         let integral = precision - decimal;
@@ -34,14 +41,14 @@ impl DecimalMeta{
         let compressed_decimals: usize = decimal as usize - (uncompressed_decimals * decimal_digits_per_integer as usize);
 
         let bytes_to_read: usize = uncompressed_integers * 4 + compressed_byte_map[compressed_integers] + uncompressed_decimals * 4 + compressed_byte_map[compressed_decimals];
-        DecimalMeta{
+        Ok(DecimalMeta{
             compressed_byte_map,
             uncompressed_integers,
             uncompressed_decimals,
             compressed_integers,
             compressed_decimals,
             bytes_to_read
-        }
+        })
 
     }
 }
@@ -74,14 +81,17 @@ impl Serialize for Blob {
 #[derive(Debug, Clone, Serialize)]
 pub enum MySQLValue {
     SignedInteger(i64),
+    UnsignedInteger(u64),
     Float(f32),
     Double(f64),
     String(String),
-    Enum(i16),
+    Enum(u16),              //enum取值的序号，从1开始，0为非法值
+    Set(u64),               //set取值的bitmask
+    Bit(u64),
     Blob(Vec<u8>),
     Year(u32),
     Date { year: u32, month: u32, day: u32 },
-    Time { hours: u32, minutes: u32, seconds: u32, subseconds: u32},
+    Time { negative: bool, hours: u32, minutes: u32, seconds: u32, subseconds: u32},
     DateTime { year: u32, month: u32, day: u32, hour: u32, minute: u32, second: u32, subsecond: u32 },
     Json(serde_json::Value),
     Decimal(bigdecimal::BigDecimal),
@@ -108,63 +118,162 @@ variable_part:
 The The data first length of the varchar type more than 255 are 2 bytes
 */
 
+///
+/// rows中每个字段为None表示该字段不在row image中(binlog_row_image为MINIMAL或NOBLOB)
+/// 为Some(MySQLValue::Null)表示字段值为NULL
+///
 #[derive(Debug, Clone, Serialize)]
 pub struct RowValue{
     pub(crate) rows: Vec<Vec<Option<MySQLValue>>>
 }
 
+///
+/// bit字段值的字节数，column meta为[M%8, M/8]
+///
+pub fn bit_bytes(col_meta: &Vec<usize>) -> usize {
+    match col_meta.as_slice() {
+        [bits, bytes] => bytes + if *bits > 0 { 1 } else { 0 },
+        _ => 0
+    }
+}
+
+///
+/// 读取指定长度的数据，长度不合法(binlog损坏)时不会预先分配内存
+///
+pub fn read_bytes<R: Read>(buf: &mut R, length: usize) -> io::Result<Vec<u8>> {
+    let mut pack = Vec::new();
+    buf.by_ref().take(length as u64).read_to_end(&mut pack)?;
+    if pack.len() < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes, got {}", length, pack.len())));
+    }
+    Ok(pack)
+}
+
 pub fn is_null(null_bytes: &Vec<u8>, pos: &usize) -> u8 {
     let idx = (pos / 8) as usize;
     let bit = null_bytes[idx];
     return bit & (1 << (pos % 8));
 }
+
+///
+/// columns-present bitmap中是否包含该字段
+///
+pub fn is_present(present: &Vec<u8>, pos: &usize) -> bool {
+    is_null(present, pos) > 0
+}
+
+///
+/// null bitmap只包含row image中存在的字段，长度由present bitmap中的字段数决定
+///
+pub fn null_bitmap_len(present: &Vec<u8>, columns: usize) -> usize {
+    let count = (0..columns).filter(|idx| is_present(present, idx)).count();
+    (count + 7) / 8
+}
+
+///
+/// partial update event的after image开头为value_options，带有PARTIAL_JSON_UPDATES(1)时
+/// 后面跟partial bits，长度由表中json字段的数量决定
+///
+pub fn read_partial_bits<R: Read>(buf: &mut R, map: &TableMap) -> io::Result<Option<Vec<u8>>> {
+    let value_options = TableMap::read_packed_int(buf)?;
+    if value_options & 1 == 0 {
+        return Ok(None);
+    }
+    let json_columns = map.column_info.iter().filter(|c| c.column_type == ColumnTypeDict::MysqlTypeJson).count();
+    Ok(Some(read_bytes(buf, (json_columns + 7) / 8)?))
+}
+
 impl RowValue{
-    pub fn read_row_value<R: Read+Seek>(buf: &mut R, map: &TableMap, header: &EventHeader, read_type: &crate::meta::ReadType) -> RowValue {
+    ///
+    /// 生成回滚语句需要完整的before image，update/delete在MINIMAL或NOBLOB时可能缺少字段
+    ///
+    pub fn has_full_before_image(&self, code: &BinlogEvent) -> bool {
+        let step = match code {
+            BinlogEvent::UpdateEvent => 2,
+            BinlogEvent::DeleteEvent => 1,
+            _ => return true
+        };
+        self.rows.iter().step_by(step).all(|row| row.iter().all(|v| v.is_some()))
+    }
+
+    pub fn read_row_value<R: Read+Seek>(buf: &mut R, map: &TableMap, header: &EventHeader, read_type: &crate::meta::ReadType) -> BinlogResult<RowValue> {
         let row_event_fix = 8;
-        buf.seek(io::SeekFrom::Current(row_event_fix)).unwrap();
-        let extra_len = buf.read_u16::<LittleEndian>().unwrap();
-        if extra_len > 2 {
-            //println!("extra_len:{}",extra_len);
-            buf.seek(io::SeekFrom::Current((extra_len - 2) as i64)).unwrap();
+        buf.seek(io::SeekFrom::Current(row_event_fix))?;
+        //v1没有extra data
+        if !header.is_rows_v1() {
+            let extra_len = buf.read_u16::<LittleEndian>()?;
+            if extra_len > 2 {
+                //println!("extra_len:{}",extra_len);
+                buf.seek(io::SeekFrom::Current((extra_len - 2) as i64))?;
+            }
         }
 
         //let col_count = map.column_info.len();
-        let col_count = buf.read_u8().unwrap();
-        let columns_length = ((col_count + 7) / 8) as i64;
-        match header.type_code {
+        let col_count = TableMap::read_packed_int(buf)?;
+        if col_count as usize != map.column_info.len() {
+            return Err(BinlogError::corrupt(format!("row event has {} columns, table map of {}.{} has {}",
+                                                    col_count, map.database_name, map.table_name, map.column_info.len())));
+        }
+        let columns_length = ((col_count as usize + 7) / 8) as usize;
+        //columns-present bitmap, update event中第二个为after image的
+        let mut present = vec![0u8; columns_length];
+        buf.read_exact(&mut present)?;
+        let present_after = match header.type_code {
             BinlogEvent::UpdateEvent => {
-                buf.seek(io::SeekFrom::Current(columns_length * 2)).unwrap();
-            }
-            _ => {
-                buf.seek(io::SeekFrom::Current(columns_length)).unwrap();
-
+                let mut tmp = vec![0u8; columns_length];
+                buf.read_exact(&mut tmp)?;
+                tmp
             }
-        }
+            _ => present.clone()
+        };
+        let columns = map.column_info.len();
         let mut rows: Vec<Vec<Option<MySQLValue>>> = vec![];
         loop {
-            let mut null_bit = vec![0u8; columns_length as usize];
-            buf.read_exact(&mut null_bit).unwrap();
+            let is_after = rows.len() % 2 == 1;
+            let image = if is_after { &present_after } else { &present };
+            let partial_bits = match is_after && header.is_partial_update() {
+                true => read_partial_bits(buf, map)?,
+                false => None
+            };
+            let mut null_bit = vec![0u8; null_bitmap_len(image, columns)];
+            buf.read_exact(&mut null_bit)?;
 
             let mut row: Vec<Option<MySQLValue>> = vec![];
-            let columns = map.column_info.len();
+            let mut null_idx = 0;
+            let mut json_idx = 0;
             for idx in 0..columns {
                 //println!("{},{:?},{},{}",idx,map.column_info[idx].column_type,buf.tell().unwrap(),header.event_length);
-                let value= if is_null(&null_bit.to_vec(), &idx) > 0{
+                if !is_present(image, &idx) {
+                    row.push(None);
+                    continue;
+                }
+                //partial bits中每个出现在after image中的json字段占1bit
+                let mut is_partial = false;
+                if let (Some(bits), ColumnTypeDict::MysqlTypeJson) = (&partial_bits, &map.column_info[idx].column_type) {
+                    is_partial = is_null(bits, &json_idx) > 0;
+                    json_idx += 1;
+                }
+                let value= if is_null(&null_bit, &null_idx) > 0{
                     MySQLValue::Null
+                } else if is_partial {
+                    let before = rows.last().and_then(|r| r[idx].clone());
+                    Self::parse_partial_json(buf, &map.column_info[idx], before)?
                 } else {
-                    Self::parsevalue(buf, &map.column_info[idx].column_type, &map.column_info[idx].column_meta)
+                    Self::parsevalue(buf, &map.column_info[idx])?
                 };
+                null_idx += 1;
                 row.push(Some(value));
             }
             rows.push(row);
             match read_type {
+                //event_length不包含末尾的checksum
                 crate::meta::ReadType::Repl => {
-                    if (buf.tell().unwrap() + 4) as usize > header.event_length as usize {
+                    if buf.tell()? as usize >= header.event_length as usize {
                         break;
                     }
                 }
                 crate::meta::ReadType::File => {
-                    if (buf.tell().unwrap() + 4) as usize >= (header.event_length as usize  - header.header_length as usize){
+                    if buf.tell()? as usize >= (header.event_length as usize  - header.header_length as usize){
                         break;
                     }
                 }
@@ -172,51 +281,82 @@ impl RowValue{
 
         };
 
-        RowValue{
+        Ok(RowValue{
             rows
+        })
+    }
+
+    ///
+    /// partial json update的after image只有修改部分，在before image的值上应用后得到完整的值
+    ///
+    fn parse_partial_json<R: Read>(buf: &mut R, info: &ColumnInfo, before: Option<MySQLValue>) -> BinlogResult<MySQLValue> {
+        let value_length = Self::read_str_value_length(buf, &info.column_meta[0])?;
+        let diffs = jsonb::read_json_diff(&read_bytes(buf, value_length)?)?;
+        match before {
+            Some(MySQLValue::Json(mut doc)) => {
+                jsonb::apply_json_diff(&mut doc, &diffs)?;
+                Ok(MySQLValue::Json(doc))
+            }
+            _ => Err(BinlogError::unsupported(format!("partial json update of {} without before image", info.name.clone().unwrap_or_default())))
         }
     }
 
-    fn parsevalue<R: Read + Tell>(buf: &mut R, type_code: &ColumnTypeDict, col_meta: &Vec<usize>) -> MySQLValue{
-        match type_code {
+    ///
+    /// 整数类型根据字段是否为unsigned分别解析为UnsignedInteger/SignedInteger
+    ///
+    fn parsevalue<R: Read + Tell>(buf: &mut R, info: &ColumnInfo) -> BinlogResult<MySQLValue>{
+        let col_meta = &info.column_meta;
+        let value = match info.column_type {
             ColumnTypeDict::MysqlTypeTiny => {
-                MySQLValue::SignedInteger(buf.read_i8().unwrap() as i64)
+                match info.unsigned {
+                    true => MySQLValue::UnsignedInteger(buf.read_u8()? as u64),
+                    false => MySQLValue::SignedInteger(buf.read_i8()? as i64)
+                }
             }
             ColumnTypeDict::MysqlTypeShort => {
-                MySQLValue::SignedInteger(buf.read_i16::<LittleEndian>().unwrap() as i64)
+                match info.unsigned {
+                    true => MySQLValue::UnsignedInteger(buf.read_u16::<LittleEndian>()? as u64),
+                    false => MySQLValue::SignedInteger(buf.read_i16::<LittleEndian>()? as i64)
+                }
             }
             ColumnTypeDict::MysqlTypeInt24 => {
-                MySQLValue::SignedInteger(buf.read_i24::<LittleEndian>().unwrap() as i64)
+                match info.unsigned {
+                    true => MySQLValue::UnsignedInteger(buf.read_u24::<LittleEndian>()? as u64),
+                    false => MySQLValue::SignedInteger(buf.read_i24::<LittleEndian>()? as i64)
+                }
             }
             ColumnTypeDict::MysqlTypeLong => {
-                MySQLValue::SignedInteger(buf.read_i32::<LittleEndian>().unwrap() as i64)
+                match info.unsigned {
+                    true => MySQLValue::UnsignedInteger(buf.read_u32::<LittleEndian>()? as u64),
+                    false => MySQLValue::SignedInteger(buf.read_i32::<LittleEndian>()? as i64)
+                }
             }
             ColumnTypeDict::MysqlTypeLonglong => {
-                MySQLValue::SignedInteger(buf.read_i64::<LittleEndian>().unwrap() as i64)
+                match info.unsigned {
+                    true => MySQLValue::UnsignedInteger(buf.read_u64::<LittleEndian>()?),
+                    false => MySQLValue::SignedInteger(buf.read_i64::<LittleEndian>()?)
+                }
             }
             ColumnTypeDict::MysqlTypeNewdecimal => {
-                let decimal_meta = DecimalMeta::new(col_meta[0] as u8, col_meta[1] as u8);
+                let decimal_meta = DecimalMeta::new(col_meta[0] as u8, col_meta[1] as u8)?;
                 let mut value_buf = vec![0u8; decimal_meta.bytes_to_read];
-                buf.read_exact(&mut value_buf).unwrap();
+                buf.read_exact(&mut value_buf)?;
                 match Self::read_new_decimal(&value_buf.to_vec(), &decimal_meta) {
                     Ok(t) => MySQLValue::Decimal(t),
-                    Err(e) => {
-                        info!("decimal 解析错误: {}",e);
-                        MySQLValue::Null
-                    }
+                    Err(e) => return Err(BinlogError::corrupt(format!("invalid decimal value: {}", e)))
                 }
             }
             ColumnTypeDict::MysqlTypeDouble |
             ColumnTypeDict::MysqlTypeFloat => {
                 match col_meta[0] {
-                    8 => MySQLValue::Double(buf.read_f64::<LittleEndian>().unwrap() as f64),
-                    4 => MySQLValue::Float(buf.read_f32::<LittleEndian>().unwrap() as f32),
-                    _ => MySQLValue::Null
+                    8 => MySQLValue::Double(buf.read_f64::<LittleEndian>()? as f64),
+                    4 => MySQLValue::Float(buf.read_f32::<LittleEndian>()? as f32),
+                    v => return Err(BinlogError::corrupt(format!("invalid float length: {}", v)))
                 }
             }
             ColumnTypeDict::MysqlTypeTimestamp2 => {
-                let whole_part = buf.read_i32::<BigEndian>().unwrap();
-                let frac_part = Self::read_datetime_fsp(buf, col_meta[0] as u8).unwrap();
+                let whole_part = buf.read_i32::<BigEndian>()?;
+                let frac_part = Self::read_datetime_fsp(buf, col_meta[0] as u8)?;
                 MySQLValue::Timestamp { unix_time: whole_part, subsecond: frac_part }
             }
             ColumnTypeDict::MysqlTypeDatetime2 => {
@@ -232,8 +372,8 @@ impl RowValue{
                 40 bits = 5 bytes
                 */
                 let mut tmp_buf = [0u8; 5];
-                buf.read_exact(&mut tmp_buf).unwrap();
-                let subsecond = Self::read_datetime_fsp(buf, col_meta[0] as u8).unwrap();
+                buf.read_exact(&mut tmp_buf)?;
+                let subsecond = Self::read_datetime_fsp(buf, col_meta[0] as u8)?;
                 tmp_buf[0] &= 0x7f;
 
                 let year_month: u32 = ((tmp_buf[2] as u32) >> 6) + ((tmp_buf[1] as u32) << 2) + ((tmp_buf[0] as u32) << 10);
@@ -248,17 +388,20 @@ impl RowValue{
                 MySQLValue::DateTime { year, month, day, hour, minute, second, subsecond }
             }
             ColumnTypeDict::MysqlTypeYear => {
-                MySQLValue::Year(buf.read_u8().unwrap() as u32 + 1900)
+                //0表示0000年
+                match buf.read_u8()? as u32 {
+                    0 => MySQLValue::Year(0),
+                    v => MySQLValue::Year(v + 1900)
+                }
             }
-            ColumnTypeDict::MysqlTypeDate => {
-                let value = buf.read_u24::<LittleEndian>().unwrap();
+            ColumnTypeDict::MysqlTypeDate |
+            ColumnTypeDict::MysqlTypeNewdate => {
+                let value = buf.read_u24::<LittleEndian>()?;
                 let year = (value & ((1 << 15) - 1) << 9) >> 9;
                 let month = (value & ((1 << 4) - 1) << 5) >> 5;
                 let day = value & ((1 << 5) - 1);
-                if year == 0 {MySQLValue::Null}
-                else if month == 0 { MySQLValue::Null }
-                else if day == 0 { MySQLValue::Null }
-                else { MySQLValue::Date {year, month, day} }
+                //0000-00-00及2020-00-00这类零值日期原样保留
+                MySQLValue::Date {year, month, day}
 
             }
             ColumnTypeDict::MysqlTypeTime2 => {
@@ -273,90 +416,122 @@ impl RowValue{
                 ---------------------
                 24 bits = 3 bytes
                 */
-                let mut tmp_buf = [0u8; 3];
-                buf.read_exact(&mut tmp_buf).unwrap();
-                let hours = (((tmp_buf[0] & 0x3f) as u32) << 4) | (((tmp_buf[1] & 0xf0) as u32) >> 4);
-                let minutes = (((tmp_buf[1] & 0x0f) as u32) << 2) | (((tmp_buf[2] & 0xb0) as u32) >> 6);
-                let seconds = (tmp_buf[2] & 0x3f) as u32;
-                let frac_part = Self::read_datetime_fsp(buf, col_meta[0] as u8).unwrap();
-                MySQLValue::Time { hours, minutes, seconds, subseconds: frac_part }
+                let (negative, packed, micro) = Self::read_time2(buf, col_meta[0] as u8)?;
+                let hours = ((packed >> 12) % (1 << 10)) as u32;
+                let minutes = ((packed >> 6) % (1 << 6)) as u32;
+                let seconds = (packed % (1 << 6)) as u32;
+                MySQLValue::Time { negative, hours, minutes, seconds, subseconds: micro }
             }
             ColumnTypeDict::MysqlTypeVarString |
             ColumnTypeDict::MysqlTypeVarchar |
             ColumnTypeDict::MysqlTypeBlob |
             ColumnTypeDict::MysqlTypeTinyBlob |
             ColumnTypeDict::MysqlTypeLongBlob |
-            ColumnTypeDict::MysqlTypeMediumBlob |
-            ColumnTypeDict::MysqlTypeBit => {
-                let var_length =  Self::read_str_value_length(buf, &col_meta[0]);
-                let mut pack = vec![0u8; var_length];
-                buf.read_exact(&mut pack).unwrap();
-                MySQLValue::Blob(pack)
+            ColumnTypeDict::MysqlTypeMediumBlob => {
+                let var_length =  Self::read_str_value_length(buf, &col_meta[0])?;
+                MySQLValue::Blob(read_bytes(buf, var_length)?)
             }
             ColumnTypeDict::MysqlTypeJson => {
-                let value_length = Self::read_str_value_length(buf, &col_meta[0]);
-                MySQLValue::Json(jsonb::read_binary_json(buf, &value_length))
+                let value_length = Self::read_str_value_length(buf, &col_meta[0])?;
+                MySQLValue::Json(jsonb::read_binary_json(buf, &value_length)?)
 
             }
             ColumnTypeDict::MysqlTypeString => {
                 let mut value_length = 0;
                 //println!("aa:{},{}",col_meta[0],buf.tell().unwrap());
                 if col_meta[0] <= 255 {
-                    value_length = buf.read_u8().unwrap() as usize;
+                    value_length = buf.read_u8()? as usize;
                 }
                 else {
-                    value_length = buf.read_u16::<LittleEndian>().unwrap() as usize;
+                    value_length = buf.read_u16::<LittleEndian>()? as usize;
+                }
+                MySQLValue::Blob(read_bytes(buf, value_length)?)
+            }
+            ColumnTypeDict::MysqlTypeBit => {
+                //大端存储，长度为(M+7)/8
+                match bit_bytes(col_meta) {
+                    0 => MySQLValue::Null,
+                    bytes => MySQLValue::Bit(buf.read_uint::<BigEndian>(bytes)?)
+                }
+            }
+            ColumnTypeDict::MysqlTypeEnum => {
+                match col_meta[0] {
+                    1 => MySQLValue::Enum(buf.read_u8()? as u16),
+                    2 => MySQLValue::Enum(buf.read_u16::<LittleEndian>()?),
+                    v => return Err(BinlogError::corrupt(format!("invalid enum length: {}", v)))
                 }
-                let mut pack = vec![0u8; value_length];
-                buf.read_exact(&mut pack).unwrap();
-                MySQLValue::Blob(pack)
             }
-            ColumnTypeDict::MysqlTypeEnum |
             ColumnTypeDict::MysqlTypeSet => {
+                //最多64个取值，占用1-8字节
                 match col_meta[0] {
-                    1 => {
-                        let v = buf.read_u8().unwrap();
-                        MySQLValue::SignedInteger(v as i64)
-                    },
-                    2 => {
-                        let v = buf.read_u16::<LittleEndian>().unwrap();
-                        MySQLValue::SignedInteger(v as i64)
-                    }
-                    _ => MySQLValue::Null
+                    1..=8 => MySQLValue::Set(buf.read_uint::<LittleEndian>(col_meta[0])?),
+                    v => return Err(BinlogError::corrupt(format!("invalid set length: {}", v)))
                 }
             }
-            _ => MySQLValue::Null
-        }
+            ColumnTypeDict::MysqlTypeGeometry => {
+                //与blob相同，长度 + 内部格式(4bytes srid + wkb)
+                let var_length =  Self::read_str_value_length(buf, &col_meta[0])?;
+                MySQLValue::Blob(read_bytes(buf, var_length)?)
+            }
+            ColumnTypeDict::MysqlTypeNull => {
+                MySQLValue::Null
+            }
+            //以下为5.6之前的时间类型，从旧版本升级的表可能还在使用
+            ColumnTypeDict::MysqlTypeTimestamp => {
+                MySQLValue::Timestamp { unix_time: buf.read_u32::<LittleEndian>()? as i32, subsecond: 0 }
+            }
+            ColumnTypeDict::MysqlTypeDatetime => {
+                //YYYYMMDDhhmmss
+                let value = buf.read_u64::<LittleEndian>()?;
+                let (date, time) = (value / 1000000, value % 1000000);
+                MySQLValue::DateTime {
+                    year: (date / 10000) as u32, month: (date % 10000 / 100) as u32, day: (date % 100) as u32,
+                    hour: (time / 10000) as u32, minute: (time % 10000 / 100) as u32, second: (time % 100) as u32, subsecond: 0
+                }
+            }
+            ColumnTypeDict::MysqlTypeTime => {
+                //±hhmmss
+                let value = buf.read_i24::<LittleEndian>()?;
+                let abs = value.abs() as u32;
+                MySQLValue::Time { negative: value < 0, hours: abs / 10000, minutes: abs % 10000 / 100, seconds: abs % 100, subseconds: 0 }
+            }
+            _ => {
+                //不认识的类型无法确定长度，继续解析会导致后面的字段全部错位
+                return Err(BinlogError::unsupported(format!("column type {:?}", info.column_type)));
+            }
+        };
+        Ok(value)
     }
 
-    fn read_str_value_length<R: Read>(buf: &mut R, meta: &usize) -> usize {
-        match meta {
-            1 => buf.read_u8().unwrap() as usize,
-            2 => buf.read_u16::<LittleEndian>().unwrap() as usize,
-            3 => buf.read_u24::<LittleEndian>().unwrap() as usize,
-            4 => buf.read_u32::<LittleEndian>().unwrap() as usize,
+    fn read_str_value_length<R: Read>(buf: &mut R, meta: &usize) -> io::Result<usize> {
+        let length = match meta {
+            1 => buf.read_u8()? as usize,
+            2 => buf.read_u16::<LittleEndian>()? as usize,
+            3 => buf.read_u24::<LittleEndian>()? as usize,
+            4 => buf.read_u32::<LittleEndian>()? as usize,
             5 => {
                 let mut pack = [0u8; 5];
-                buf.read_exact(&mut pack).unwrap();
+                buf.read_exact(&mut pack)?;
                 readvalue::read_u40(&pack) as usize
             }
             6 => {
                 let mut pack = [0u8; 6];
-                buf.read_exact(&mut pack).unwrap();
+                buf.read_exact(&mut pack)?;
                 readvalue::read_u48(&pack) as usize
             }
             7 => {
                 let mut pack = [0u8; 7];
-                buf.read_exact(&mut pack).unwrap();
+                buf.read_exact(&mut pack)?;
                 readvalue::read_u56(&pack) as usize
             }
             8 => {
                 let mut pack = [0u8; 8];
-                buf.read_exact(&mut pack).unwrap();
+                buf.read_exact(&mut pack)?;
                 readvalue::read_u64(&pack) as usize
             }
-            _ => 0 as usize
-        }
+            v => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid length bytes: {}", v)))
+        };
+        Ok(length)
     }
 
     fn read_new_decimal(buf: &Vec<u8>, meta: &DecimalMeta) -> Result<BigDecimal, failure::Error> {
@@ -380,14 +555,19 @@ impl RowValue{
             let v = r.read_i32::<BigEndian>()? ^ mask as i32;
             components.push(format!("{:09}", v));
         }
-        components.push(".".to_owned());
+        if meta.compressed_integers == 0 && meta.uncompressed_integers == 0 {
+            components.push("0".to_owned());
+        }
+        if meta.compressed_decimals != 0 || meta.uncompressed_decimals != 0 {
+            components.push(".".to_owned());
+        }
         for _ in 0..meta.uncompressed_decimals {
             let v =  r.read_u32::<BigEndian>()? ^ mask as u32;
             components.push(format!("{:09}", v));
         }
         if meta.compressed_decimals != 0 {
             let v = Self::read_int_be_by_size(&mut r, meta.compressed_byte_map[meta.compressed_decimals])? ^ mask;
-            components.push(format!("{:.*}",meta.compressed_decimals,v as usize));
+            components.push(format!("{:0width$}", v, width = meta.compressed_decimals));
         }
         components.join("").parse::<BigDecimal>().map_err(|e| failure::Error::from_boxed_compat(Box::new(e)))
     }
@@ -407,20 +587,58 @@ impl RowValue{
                 res
             },
             4 => i64::from(r.read_i32::<BigEndian>()?),
-            _ => unimplemented!(),
+            v => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid decimal bytes: {}", v))),
         })
     }
 
 
+    ///
+    /// 读取小数秒部分，统一转换为微秒
+    ///     fsp为1、2时存储单位为10毫秒，3、4时为100微秒，5、6时为微秒
+    ///
     fn read_datetime_fsp<R: Read>(r: &mut R, column: u8) -> io::Result<u32> {
         Ok(match column {
             0 => 0u32,
-            1 | 2 => Self::read_int_be_by_size(r, 1)? as u32,
-            3 | 4 => Self::read_int_be_by_size(r, 2)? as u32,
-            5 | 6 => Self::read_int_be_by_size(r, 3)? as u32,
+            1 | 2 => r.read_u8()? as u32 * 10000,
+            3 | 4 => r.read_u16::<BigEndian>()? as u32 * 100,
+            5 | 6 => r.read_u24::<BigEndian>()?,
             _ => 0u32,
         })
     }
+
+    ///
+    /// TIME2类型，与mysql源码中my_time_packed_from_binary一致
+    ///     整数部分为3字节大端，加上0x800000的偏移，负数时小数部分以补码形式存储
+    ///     返回(是否为负数, 时分秒打包值, 微秒)
+    ///
+    fn read_time2<R: Read>(r: &mut R, fsp: u8) -> io::Result<(bool, i64, u32)> {
+        let int_ofs: i64 = 0x800000;
+        //packed = (整数部分 << 24) + 小数部分, unit为小数部分转换为微秒的倍数
+        let (packed, unit) = match fsp {
+            1 | 2 => {
+                let mut int_part = r.read_u24::<BigEndian>()? as i64 - int_ofs;
+                let mut frac = r.read_u8()? as i64;
+                if int_part < 0 && frac != 0 {
+                    int_part += 1;
+                    frac -= 0x100;
+                }
+                ((int_part << 24) + frac, 10000)
+            }
+            3 | 4 => {
+                let mut int_part = r.read_u24::<BigEndian>()? as i64 - int_ofs;
+                let mut frac = r.read_u16::<BigEndian>()? as i64;
+                if int_part < 0 && frac != 0 {
+                    int_part += 1;
+                    frac -= 0x10000;
+                }
+                ((int_part << 24) + frac, 100)
+            }
+            5 | 6 => (r.read_u48::<BigEndian>()? as i64 - 0x800000000000, 1),
+            _ => ((r.read_u24::<BigEndian>()? as i64 - int_ofs) << 24, 1)
+        };
+        let value = packed.abs();
+        Ok((packed < 0, value >> 24, (value % (1 << 24)) as u32 * unit))
+    }
 }
 
 
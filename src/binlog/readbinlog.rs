@@ -10,8 +10,10 @@ use std::io::{Cursor, Read, Seek, SeekFrom};
 use std::collections::HashMap;
 use crate::binlog::rollback;
 use crate::binlog::getsql;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::error::Error;
+use crate::meta::{BinlogPos, SchemaCache};
+use crate::binlog::error::BinlogError;
 use serde::Serialize;
 
 
@@ -32,19 +34,59 @@ pub struct TractionValue{
     pub event: Vec<Traction>,
     pub cur_sql: Vec<String>,
     pub rollback_sql: Vec<String>,
+    #[serde(skip)]
+    pub cur_stmt: Vec<getsql::SqlStatement>,        //参数化的cur_sql，用于实际执行
+    #[serde(skip)]
+    pub rollback_stmt: Vec<getsql::SqlStatement>,   //参数化的rollback_sql，用于实际执行
+    #[serde(skip)]
+    pub rollback_incomplete: bool,                  //before image不完整(MINIMAL/NOBLOB)，无法生成回滚语句
+    #[serde(skip)]
+    pub rollback_events: Vec<Vec<u8>>,              //回滚的table map及row event(不包含checksum)，按回放顺序，用于生成flashback binlog
+    #[serde(skip)]
+    pub rows: Vec<getsql::RowChange>,               //每行的变更数据，只有RowsSql.keep_rows时才生成，用于cdc
+    pub timestamp: u32,         //gtid event的时间戳
+    pub server_id: u32,
+    pub position: u32,          //事务结束位置(最后一个event的next_position)
 }
 impl TractionValue{
     fn new() -> TractionValue{
         TractionValue{
             event: vec![],
             cur_sql: vec![],
-            rollback_sql: vec![]
+            rollback_sql: vec![],
+            cur_stmt: vec![],
+            rollback_stmt: vec![],
+            rollback_incomplete: false,
+            rollback_events: vec![],
+            rows: vec![],
+            timestamp: 0,
+            server_id: 0,
+            position: 0
         }
     }
+    ///
+    /// 不以xid event结尾的事务，只有最后一个event为ddl或commit语句时才是完整的
+    ///
+    fn is_complete(&self) -> bool {
+        match self.event.last() {
+            Some(Traction::XidEvent(_)) => true,
+            Some(Traction::QueryEvent(q)) => q.command.to_uppercase() != "BEGIN",
+            _ => false
+        }
+    }
+
     fn init(&mut self) {
         self.event = vec![];
         self.cur_sql = vec![];
         self.rollback_sql = vec![];
+        self.cur_stmt = vec![];
+        self.rollback_stmt = vec![];
+        self.rollback_incomplete = false;
+        self.rollback_events = vec![];
+        self.rows = vec![];
+        self.timestamp = 0;
+        self.server_id = 0;
+        self.position = 0;
     }
 }
 
@@ -53,21 +95,18 @@ pub struct RowsSql {
     pub sqls: Vec<TractionValue>,
     pub error: String,
     pub etype: String,
+    #[serde(skip)]
+    pub keep_rows: bool,        //是否在TractionValue.rows中保留每行的数据
 }
 impl RowsSql{
     pub fn new() -> RowsSql{
         RowsSql{
             sqls: vec![],
             error: "".to_string(),
-            etype: "".to_string()
+            etype: "".to_string(),
+            keep_rows: false
         }
     }
-    fn init(&mut self) {
-        self.sqls = vec![];
-        self.error = "".to_string();
-        self.etype = "".to_string();
-    }
-
     pub fn set_rollback_etype(&mut self) {
         self.etype = "rollback".to_string();
     }
@@ -78,18 +117,102 @@ impl RowsSql{
 }
 
 
+///
+/// 同时生成用于展示的sql及用于执行的参数化语句
+///
+fn get_sql_and_stmt(
+    v: &parsevalue::RowValue, code: &readevent::BinlogEvent,
+    table_cols_info: &mut HashMap<String, Vec<crate::meta::ColumnMeta>>,
+    db_tbl: &String, map: &readevent::TableMap) -> Result<(Vec<String>, Vec<getsql::SqlStatement>), Box<dyn Error>> {
+    let sqls = getsql::get_command(v, code, table_cols_info, db_tbl, map)?;
+    let stmts = getsql::get_statements(v, code, table_cols_info, db_tbl, map, &getsql::RenderMode::Param)?;
+    Ok((sqls, stmts))
+}
+
 //从文件读取binlog
 //pub fn readbinlog_fromfile<R: Read + Seek>(conf: &Arc<Config>, reader: &mut BufReader<File>, reader_size: u64) -> Result<RowsSql, Box<dyn Error>> {
-pub fn parse<R: Read + Seek>(conf: &Arc<Config>, reader: &mut R, reader_size: u64, readfile: bool) -> Result<RowsSql, Box<dyn Error>> {
+///
+/// base为reader起始位置对应的binlog文件及偏移量，用于确定每个event所在位置生效的表结构
+///
+pub fn parse<R: Read + Seek>(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, reader: &mut R, reader_size: u64, readfile: bool, base: &BinlogPos) -> Result<RowsSql, Box<dyn Error>> {
+    parse_with_format(conf, schema, reader, reader_size, readfile, base, readevent::FormatDescriptionEvent::new())
+}
+
+///
+/// 从binlog文件头读取format description event，用于解析从文件中间开始的binlog片段
+///
+pub fn read_format_description<R: Read + Seek>(reader: &mut R) -> Result<readevent::FormatDescriptionEvent, Box<dyn Error>> {
+    reader.seek(SeekFrom::Start(4))?;
+    let mut header_buf = vec![0u8; 19];
+    reader.read_exact(header_buf.as_mut())?;
+    let mut cur = Cursor::new(header_buf);
+    let event_header: EventHeader = readevent::InitHeader::new(&mut cur)?;
+    match event_header.type_code {
+        readevent::BinlogEvent::FormatDescriptionEvent => {
+            let mut payload_buf = vec![0u8; event_header.event_length as usize - event_header.header_length as usize];
+            reader.read_exact(payload_buf.as_mut())?;
+            Ok(readevent::FormatDescriptionEvent::read_event(&payload_buf)?)
+        }
+        _ => Err(String::from("the first event is not format description event").into())
+    }
+}
+
+///
+/// fde为片段所属binlog文件的format description event，读取到新的fde时会被替换
+///     解析出错时(binlog被截断或损坏等)错误信息放在RowsSql.error中，已经解析完成的事务保留在sqls中
+///
+pub fn parse_with_format<R: Read + Seek>(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, reader: &mut R, reader_size: u64, readfile: bool,
+                                        base: &BinlogPos, fde: readevent::FormatDescriptionEvent) -> Result<RowsSql, Box<dyn Error>> {
+    parse_into(conf, schema, reader, reader_size, readfile, base, fde, RowsSql::new())
+}
+
+///
+/// 与parse_with_format相同，同时保留每行的数据(TractionValue.rows)
+///
+pub fn parse_rows_with_format<R: Read + Seek>(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, reader: &mut R, reader_size: u64, readfile: bool,
+                                             base: &BinlogPos, fde: readevent::FormatDescriptionEvent) -> Result<RowsSql, Box<dyn Error>> {
+    let mut row_sql = RowsSql::new();
+    row_sql.keep_rows = true;
+    parse_into(conf, schema, reader, reader_size, readfile, base, fde, row_sql)
+}
+
+fn parse_into<R: Read + Seek>(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, reader: &mut R, reader_size: u64, readfile: bool,
+                              base: &BinlogPos, fde: readevent::FormatDescriptionEvent, mut row_sql: RowsSql) -> Result<RowsSql, Box<dyn Error>> {
+    if let Err(e) = parse_events(conf, schema, reader, reader_size, readfile, base, fde, &mut row_sql) {
+        info!("parse binlog error: {}", e);
+        row_sql.error = e.to_string();
+    }
+    //本次解析中获取的表结构统一写入缓存文件
+    if let Err(e) = schema.lock().unwrap().flush() {
+        info!("save schema cache error: {}", e);
+    }
+    Ok(row_sql)
+}
+
+///
+/// 事务的拆分规则(审计、宕机回滚及追加共用):
+///     以xid event结尾的事务在xid处结束；ddl及非事务表等没有xid event的事务在下一个gtid event或片段末尾结束，
+///     只有最后一个event为非BEGIN的query event时才是完整的，不完整的事务不返回，避免追加/回滚半个事务
+///     一个事务中有多个row event时cur_sql按顺序追加，rollback_sql倒序插入(后执行的先回滚)，
+///     每个row event在event中记录为RowEventStatic，count为对应的sql条数，用于把sql对应到所属的表
+///     无法识别的event不放入event列表，position为事务最后一个event的结束位置
+///
+fn parse_events<R: Read + Seek>(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, reader: &mut R, reader_size: u64, readfile: bool,
+                                base: &BinlogPos, fde: readevent::FormatDescriptionEvent, row_sql: &mut RowsSql) -> Result<(), Box<dyn Error>> {
     //
-    let version = crate::meta::get_version(conf)?;
+    let mut version = fde.major_version();
     //
     let mut traction_value = TractionValue::new();
 
-    let mut row_sql = RowsSql::new();
     let mut tabl_map = readevent::TableMap::new();
-    let mut table_cols_info: HashMap<String, Vec<HashMap<String, String>>> = HashMap::new();
+    //当前table map event的原始数据，写入flashback binlog时放在回滚的row event前面
+    let mut tabl_map_buf = vec![];
+    let mut table_cols_info: HashMap<String, Vec<crate::meta::ColumnMeta>> = HashMap::new();
     let mut db_tbl = String::from("");
+    //从文件头读取时会先读到format description event，确定checksum算法及各event的post header长度
+    let mut fde = fde;
+    //获取表结构使用的连接，整个解析过程共用
+    let mut schema_conn = None;
 
     //
 
@@ -98,106 +221,150 @@ pub fn parse<R: Read + Seek>(conf: &Arc<Config>, reader: &mut R, reader_size: u6
         let mut rollback_buf = vec![];
         let mut header_buf = vec![0u8; 19];
         let cur_tell = reader.tell()?;
-        if readfile{
-            if cur_tell + 19 > reader_size {
-                return Ok(row_sql);
-            }
-        }else {
-            if cur_tell >= reader_size {
-                return Ok(row_sql);
+        if (readfile && cur_tell + 19 > reader_size) || (!readfile && cur_tell >= reader_size) {
+            //最后一个不以xid event结尾的事务
+            if traction_value.is_complete() {
+                row_sql.sqls.push(traction_value);
             }
+            return Ok(());
         }
+        //出错时带上event的起始位置
+        let event_start = base.position + cur_tell;
+        let ctx = |e: BinlogError| e.context(&base.binlog, event_start);
 
         //info!("startb");
-        reader.read_exact(header_buf.as_mut())?;
+        reader.read_exact(header_buf.as_mut()).map_err(|e| ctx(e.into()))?;
         rollback_buf.extend(header_buf.clone());
         let mut cur = Cursor::new(header_buf);
-        let event_header: EventHeader = readevent::InitHeader::new(&mut cur);
-        info!("event_header: {:?}", event_header);
+        let mut event_header: EventHeader = readevent::InitHeader::new(&mut cur).map_err(ctx)?;
+        debug!("event_header: {:?}", event_header);
         let payload = event_header.event_length as usize - event_header.header_length as usize;
         let mut payload_buf = vec![0u8; payload];
 
-        reader.read_exact(payload_buf.as_mut())?;
+        reader.read_exact(payload_buf.as_mut()).map_err(|e| ctx(e.into()))?;
         rollback_buf.extend(payload_buf.clone());
+        let event_pos = BinlogPos::new(&base.binlog, base.position + reader.tell()?);
+
+        if let readevent::BinlogEvent::FormatDescriptionEvent = event_header.type_code {
+            fde = readevent::FormatDescriptionEvent::read_event(&payload_buf).map_err(ctx)?;
+            version = fde.major_version();
+            info!("format description event: {:?}", fde);
+        }
+        //校验并去掉末尾的checksum，后续解析的event_length不再包含checksum
+        let checksum_len = fde.checksum_len();
+        if checksum_len > 0 {
+            readevent::verify_checksum(&rollback_buf).map_err(ctx)?;
+            if payload_buf.len() < checksum_len {
+                return Err(ctx(BinlogError::corrupt(format!("invalid event length: {}", event_header.event_length))).into());
+            }
+            payload_buf.truncate(payload - checksum_len);
+            rollback_buf.truncate(rollback_buf.len() - checksum_len);
+            event_header.event_length -= checksum_len as u32;
+            rollback_buf[9..13].copy_from_slice(&event_header.event_length.to_le_bytes());
+        }
+        event_header.post_header_length = fde.post_header_length(event_header.type_byte);
         let mut cur = Cursor::new(payload_buf);
 
         let mut data = Traction::Unknown;
         match event_header.type_code {
             readevent::BinlogEvent::GtidEvent => {
-
-                let v = readevent::GtidEvent::read_event( &event_header, &mut cur, &version);
+                //没有xid event结尾的事务(ddl等)在下一个gtid处结束
+                if traction_value.is_complete() {
+                    row_sql.sqls.push(traction_value.clone());
+                }
+                traction_value.init();
+                let v = readevent::GtidEvent::read_event( &event_header, &mut cur, &version).map_err(ctx)?;
+                traction_value.timestamp = event_header.timestamp;
+                traction_value.server_id = event_header.server_id;
                 data = Traction::GtidEvent(v);
             },
             readevent::BinlogEvent::QueryEvent => {
-                let v = readevent::QueryEvent::read_event( &event_header, &mut cur, &version);
+                let v = readevent::QueryEvent::read_event( &event_header, &mut cur, &version).map_err(ctx)?;
+                //ddl之后的row event需要重新获取表结构
+                schema.lock().unwrap().on_ddl(&v.database, &v.command, &event_pos)?;
                 data = Traction::QueryEvent(v);
             },
             readevent::BinlogEvent::TableMapEvent => {
-                let v = readevent::TableMap::read_event( &event_header, &mut cur, &version);
+                let mut v = readevent::TableMap::read_event( &event_header, &mut cur, &version).map_err(ctx)?;
                 db_tbl = format!("{}.{}", v.database_name, v.table_name).clone();
-                let state = crate::meta::get_col(conf, &v.database_name, &v.table_name, &mut table_cols_info);
+                let state = crate::meta::get_table_meta(conf, &mut schema_conn, schema, &v, &event_pos, &mut table_cols_info);
                 match state {
-                    Ok(_t) => {}
+                    Ok(_t) => {
+                        //整数类型是否为unsigned，解析row event时使用
+                        if let Some(cols) = table_cols_info.get(&db_tbl) {
+                            v.set_unsigned(cols);
+                        }
+                    }
                     Err(e) => {
-                        info!("{:?}", e);
-                        row_sql.init();
-                        row_sql.error = (*e.to_string()).parse()?;
-                        break 'all;
+                        //已经解析完成的事务保留在row_sql中，由调用方决定是否使用
+                        return Err(ctx(BinlogError::schema(e.to_string())).into());
                     }
                 }
                 tabl_map = v.clone();
+                tabl_map_buf = rollback_buf.clone();
                 data = Traction::TableMapEvent(v);
             },
             readevent::BinlogEvent::UpdateEvent |
             readevent::BinlogEvent::DeleteEvent |
             readevent::BinlogEvent::WriteEvent => {
                 let read_type = crate::meta::ReadType::File;
-                let v = parsevalue::RowValue::read_row_value(&mut cur, &tabl_map, &event_header,&read_type);
+                let v = parsevalue::RowValue::read_row_value(&mut cur, &tabl_map, &event_header,&read_type).map_err(ctx)?;
                 //info!("cur_row_value: {:?}", &v);
                 //data = Traction::RowEvent(event_header.type_code.clone(),v);
-                let cur_sql = getsql::get_command(&v, &event_header.type_code, &mut table_cols_info, &db_tbl,&tabl_map);
+                let cur_sql = get_sql_and_stmt(&v, &event_header.type_code, &mut table_cols_info, &db_tbl,&tabl_map);
                 //info!("cur_sql: {:?}", &cur_sql);
                 match cur_sql {
-                    Ok(t) => {
-                        traction_value.cur_sql = t;
+                    Ok((t, s)) => {
+                        data = Traction::RowEventStatic{type_code: event_header.type_code.clone(), count: t.len()};
+                        traction_value.cur_sql.extend(t);
+                        traction_value.cur_stmt.extend(s);
                     }
                     Err(e) => {
-                        row_sql.init();
-                        row_sql.error = (*e.to_string()).parse()?;
-                        break 'all;
+                        return Err(ctx(BinlogError::schema(e.to_string())).into());
                     }
                 }
-                //生成回滚sql
-                //info!("rollback_cur_buf:{:?}", &rollback_buf);
-                let r = rollback::rollback_row_event(&rollback_buf, &event_header, &tabl_map);
-                let mut r_cur = Cursor::new(r);
-                let event_header: EventHeader = readevent::InitHeader::new(&mut r_cur);
-                //info!("rollback_header:{:?}", &event_header);
-                let payload = event_header.event_length as usize - event_header.header_length as usize;
-                let mut payload_buf = vec![0u8; payload];
-                r_cur.read_exact(payload_buf.as_mut())?;
-                //info!("rollback_buf:{:?}", &payload_buf);
-                let mut cur = Cursor::new(payload_buf);
-                let v = parsevalue::RowValue::read_row_value(&mut cur, &tabl_map, &event_header,&read_type);
-                //info!("rollback_row_value: {:?}", &v);
-                //rollback_data = Traction::RowEvent(event_header.type_code.clone(), v);
-                let cur_sql = getsql::get_command(&v, &event_header.type_code, &mut table_cols_info, &db_tbl,&tabl_map);
-                //info!("rollback_row_sql: {:?}", &cur_sql);
-                match cur_sql {
-                    Ok(t) => {
-                        traction_value.rollback_sql = t;
-                    }
-                    Err(e) => {
-                        row_sql.init();
-                        row_sql.error = (*e.to_string()).parse()?;
-                        break 'all;
+                if row_sql.keep_rows {
+                    let rows = getsql::get_row_changes(&v, &event_header.type_code, &table_cols_info, &db_tbl, &tabl_map)
+                        .map_err(|e| ctx(BinlogError::schema(e.to_string())))?;
+                    traction_value.rows.extend(rows);
+                }
+                //生成回滚sql, update/delete的before image不完整时无法回滚，只做标记由回滚时报错
+                if !v.has_full_before_image(&event_header.type_code) {
+                    traction_value.rollback_incomplete = true;
+                } else {
+                    //info!("rollback_cur_buf:{:?}", &rollback_buf);
+                    let r = rollback::rollback_row_event(&rollback_buf, &event_header, &tabl_map).map_err(ctx)?;
+                    traction_value.rollback_events.splice(0..0, vec![tabl_map_buf.clone(), r.clone()]);
+                    let mut r_cur = Cursor::new(r);
+                    let event_header: EventHeader = readevent::InitHeader::new(&mut r_cur).map_err(ctx)?;
+                    //info!("rollback_header:{:?}", &event_header);
+                    let payload = event_header.event_length as usize - event_header.header_length as usize;
+                    let mut payload_buf = vec![0u8; payload];
+                    r_cur.read_exact(payload_buf.as_mut())?;
+                    //info!("rollback_buf:{:?}", &payload_buf);
+                    let mut cur = Cursor::new(payload_buf);
+                    let v = parsevalue::RowValue::read_row_value(&mut cur, &tabl_map, &event_header,&read_type).map_err(ctx)?;
+                    //info!("rollback_row_value: {:?}", &v);
+                    //rollback_data = Traction::RowEvent(event_header.type_code.clone(), v);
+                    let cur_sql = get_sql_and_stmt(&v, &event_header.type_code, &mut table_cols_info, &db_tbl,&tabl_map);
+                    //info!("rollback_row_sql: {:?}", &cur_sql);
+                    match cur_sql {
+                        Ok((t, s)) => {
+                            //同一事务中后面的event需要先回滚
+                            traction_value.rollback_sql.splice(0..0, t);
+                            traction_value.rollback_stmt.splice(0..0, s);
+                        }
+                        Err(e) => {
+                            return Err(ctx(BinlogError::schema(e.to_string())).into());
+                        }
                     }
                 }
                 //
 
             },
             readevent::BinlogEvent::XidEvent => {
-                data = Traction::XidEvent(readevent::XidEvent::read_event(&event_header,&mut cur, &version));
+                data = Traction::XidEvent(readevent::XidEvent::read_event(&event_header,&mut cur, &version).map_err(ctx)?);
+                traction_value.position = event_header.next_position;
                 traction_value.event.push(data);
                 row_sql.sqls.push(traction_value.clone());
                 traction_value.init();
@@ -208,29 +375,37 @@ pub fn parse<R: Read + Seek>(conf: &Arc<Config>, reader: &mut R, reader_size: u6
                 continue 'all;
             }
             readevent::BinlogEvent::RotateLogEvent => {
-                let a = readevent::RotateLog::read_event(&event_header, &mut cur, &version);
-                let c_row_sql = rotate_readbinlog(conf, readfile, &a.binlog_file)?;
+                let a = readevent::RotateLog::read_event(&event_header, &mut cur, &version).map_err(ctx)?;
+                let c_row_sql = rotate_readbinlog(conf, schema, readfile, &a.binlog_file)?;
                 for trac_sql in c_row_sql.sqls {
                     row_sql.sqls.push(trac_sql);
                 }
+                if c_row_sql.error.len() > 0 {
+                    row_sql.error = c_row_sql.error;
+                    break 'all;
+                }
                 continue 'all;
             }
             _ => {}
         }
+        if let Traction::Unknown = data {
+            continue 'all;
+        }
+        traction_value.position = event_header.next_position;
         traction_value.event.push(data);
 
     }
-    return Ok(row_sql);
+    return Ok(());
 }
 
-fn rotate_readbinlog(conf: &Arc<Config>, readfile: bool, logname: &String) -> Result<RowsSql , Box<dyn Error>> {
+fn rotate_readbinlog(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, readfile: bool, logname: &String) -> Result<RowsSql , Box<dyn Error>> {
     info!("rotate to new log: {}", logname);
     let path = format!("{}/{}",conf.binlogdir,logname);
     let mut r = crate::binlog::open_file(&path)?;
-    r.seek(SeekFrom::End(0)).unwrap();
-    let r_size = r.tell().unwrap();
-    r.seek(SeekFrom::Start(4)).unwrap();
-    let r_sqls = parse(conf, &mut r, r_size, readfile)?;
+    r.seek(SeekFrom::End(0))?;
+    let r_size = r.tell()?;
+    r.seek(SeekFrom::Start(4))?;
+    let r_sqls = parse(conf, schema, &mut r, r_size, readfile, &BinlogPos::new(logname, 0))?;
     Ok(r_sqls)
 }
 
@@ -7,7 +7,8 @@ use std::{io};
 use uuid;
 use uuid::Uuid;
 use std::io::{Read, Seek, SeekFrom, Result};
-use crate::meta::ColumnTypeDict;
+use crate::meta::{ColumnTypeDict, ColumnMeta};
+use crate::binlog::error::{BinlogError, BinlogResult};
 use byteorder::{ReadBytesExt, LittleEndian};
 use serde::Serialize;
 
@@ -36,12 +37,12 @@ pub enum BinlogEvent{
     CreateFileEvent
 }
 
-pub trait InitHeader{
-    fn new<R: Read+Seek>(buf: &mut R) -> Self;
+pub trait InitHeader: Sized {
+    fn new<R: Read+Seek>(buf: &mut R) -> BinlogResult<Self>;
 }
 
-pub trait InitValue{
-    fn read_event<R: Read+Seek>(header: &EventHeader, buf: &mut R, version: &u8) -> Self;
+pub trait InitValue: Sized {
+    fn read_event<R: Read+Seek>(header: &EventHeader, buf: &mut R, version: &u8) -> BinlogResult<Self>;
 }
 
 
@@ -60,44 +61,69 @@ pub struct EventHeader{
     //19bytes 包头部分
     pub timestamp: u32,
     pub type_code: BinlogEvent,
+    pub type_byte: u8,
     pub server_id: u32,
     pub event_length: u32,
     pub next_position: u32,
     pub flags: u16,
     pub header_length: u8,
+    pub post_header_length: u8,     //由format description event中获取，0表示未知，使用默认长度
 }
 
 impl InitHeader for EventHeader {
-    fn new<R: Read + Seek>(buf: &mut R) -> EventHeader{
+    fn new<R: Read + Seek>(buf: &mut R) -> BinlogResult<EventHeader>{
         let header_length: u8 = 19;
-        let timestamp = buf.read_u32::<LittleEndian>().unwrap();
-        let type_code = Self::get_type_code_event(&Some(buf.read_u8().unwrap() as u8));
-        let server_id = buf.read_u32::<LittleEndian>().unwrap();
-        let event_length = buf.read_u32::<LittleEndian>().unwrap();
-        let next_position = buf.read_u32::<LittleEndian>().unwrap();
-        let flags = buf.read_u16::<LittleEndian>().unwrap();
-        EventHeader{
+        let timestamp = buf.read_u32::<LittleEndian>()?;
+        let type_byte = buf.read_u8()? as u8;
+        let type_code = Self::get_type_code_event(&Some(type_byte));
+        let server_id = buf.read_u32::<LittleEndian>()?;
+        let event_length = buf.read_u32::<LittleEndian>()?;
+        let next_position = buf.read_u32::<LittleEndian>()?;
+        let flags = buf.read_u16::<LittleEndian>()?;
+        if event_length < header_length as u32 {
+            return Err(BinlogError::corrupt(format!("invalid event length: {}", event_length)));
+        }
+        Ok(EventHeader{
             timestamp,
             type_code,
+            type_byte,
             server_id,
             event_length,
             next_position,
             flags,
-            header_length
-        }
+            header_length,
+            post_header_length: 0
+        })
     }
 }
 
 impl EventHeader{
+    ///
+    /// v1的row event(type 23-25)固定部分没有extra data
+    ///
+    pub fn is_rows_v1(&self) -> bool {
+        match self.type_byte {
+            23 | 24 | 25 => true,
+            _ => false
+        }
+    }
+
+    ///
+    /// binlog_row_value_options=PARTIAL_JSON时的update event(type 39)，after image中的json字段可能只有修改部分
+    ///
+    pub fn is_partial_update(&self) -> bool {
+        self.type_byte == 39
+    }
+
     fn get_type_code_event(type_code: &Option<u8>) -> BinlogEvent{
         match type_code {
             Some(4) => BinlogEvent::RotateLogEvent,
             Some(2) => BinlogEvent::QueryEvent,
             Some(33) => BinlogEvent::GtidEvent,
             Some(19) => BinlogEvent::TableMapEvent,
-            Some(30) => BinlogEvent::WriteEvent,
-            Some(31) => BinlogEvent::UpdateEvent,
-            Some(32) => BinlogEvent::DeleteEvent,
+            Some(23) | Some(30) => BinlogEvent::WriteEvent,
+            Some(24) | Some(31) | Some(39) => BinlogEvent::UpdateEvent,
+            Some(25) | Some(32) => BinlogEvent::DeleteEvent,
             Some(16) => BinlogEvent::XidEvent,
             Some(38) => BinlogEvent::XAPREPARELOGEVENT,
             Some(15) => BinlogEvent::FormatDescriptionEvent,
@@ -108,6 +134,140 @@ impl EventHeader{
     }
 }
 
+/*
+format_description_event:
+    binlog_version : 2bytes
+    server_version : 50bytes
+    create_timestamp : 4bytes
+    header_length : 1bytes
+    post_header_length : 每种event类型1bytes
+    checksum_alg : 1bytes (5.6.1及以上版本)
+    crc : 4bytes (5.6.1及以上版本，checksum_alg为off时也保留)
+*/
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum ChecksumAlg {
+    Off,
+    Crc32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatDescriptionEvent{
+    pub binlog_version: u16,
+    pub server_version: String,
+    pub create_timestamp: u32,
+    pub header_length: u8,
+    pub post_header_len: Vec<u8>,
+    pub checksum_alg: ChecksumAlg,
+}
+
+impl FormatDescriptionEvent {
+    ///
+    /// 没有读取到format description event时(如服务端推送的binlog片段)，与mysql默认的crc32一致
+    ///
+    pub fn new() -> FormatDescriptionEvent {
+        FormatDescriptionEvent{
+            binlog_version: 4,
+            server_version: "".to_string(),
+            create_timestamp: 0,
+            header_length: 19,
+            post_header_len: vec![],
+            checksum_alg: ChecksumAlg::Crc32
+        }
+    }
+
+    ///
+    /// payload为去掉19字节header后的全部数据
+    ///
+    pub fn read_event(payload: &Vec<u8>) -> BinlogResult<FormatDescriptionEvent> {
+        let fix_length = 2 + 50 + 4 + 1;
+        if payload.len() < fix_length {
+            return Err(BinlogError::corrupt(format!("invalid format description event length: {}", payload.len())));
+        }
+        let binlog_version = readvalue::read_u16(&payload[0..2]);
+        let server_version = readvalue::read_string_value(&payload[2..52]).trim_end_matches('\0').to_string();
+        let create_timestamp = readvalue::read_u32(&payload[52..56]);
+        let header_length = payload[56];
+
+        //5.6.1之后的版本在末尾有1字节checksum_alg及4字节crc
+        let has_checksum_alg = Self::version_split(&server_version) >= (5, 6, 1);
+        let mut end = payload.len();
+        let mut checksum_alg = ChecksumAlg::Off;
+        if has_checksum_alg {
+            if end < fix_length + 5 {
+                return Err(BinlogError::corrupt(format!("invalid format description event length: {}", payload.len())));
+            }
+            end -= 5;
+            checksum_alg = match payload[end] {
+                0 => ChecksumAlg::Off,
+                1 => ChecksumAlg::Crc32,
+                v => return Err(BinlogError::unsupported(format!("binlog checksum algorithm: {}", v)))
+            };
+        }
+        let post_header_len = payload[fix_length..end].to_vec();
+        Ok(FormatDescriptionEvent{
+            binlog_version,
+            server_version,
+            create_timestamp,
+            header_length,
+            post_header_len,
+            checksum_alg
+        })
+    }
+
+    fn version_split(version: &String) -> (u32, u32, u32) {
+        let mut v = [0u32; 3];
+        for (idx, part) in version.split(|c: char| c == '.' || c == '-').take(3).enumerate() {
+            v[idx] = part.parse::<u32>().unwrap_or(0);
+        }
+        (v[0], v[1], v[2])
+    }
+
+    ///
+    /// 服务端主版本号，如5、8
+    ///
+    pub fn major_version(&self) -> u8 {
+        Self::version_split(&self.server_version).0 as u8
+    }
+
+    pub fn checksum_len(&self) -> usize {
+        match self.checksum_alg {
+            ChecksumAlg::Crc32 => 4,
+            ChecksumAlg::Off => 0
+        }
+    }
+
+    ///
+    /// 对应event类型的post header长度，type_code从1开始
+    ///
+    pub fn post_header_length(&self, type_byte: u8) -> u8 {
+        if type_byte == 0 {
+            return 0;
+        }
+        match self.post_header_len.get(type_byte as usize - 1) {
+            Some(v) => *v,
+            None => 0
+        }
+    }
+}
+
+///
+/// 校验event的crc32, event包含header及末尾4字节的crc
+///
+pub fn verify_checksum(event: &Vec<u8>) -> BinlogResult<()> {
+    if event.len() < 4 {
+        return Err(BinlogError::corrupt(format!("invalid event length: {}", event.len())));
+    }
+    let (data, crc) = event.split_at(event.len() - 4);
+    let expected = readvalue::read_u32(crc);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    let actual = hasher.finalize();
+    if expected != actual {
+        return Err(BinlogError::corrupt(format!("event checksum mismatch, expected: {:x}, actual: {:x}", expected, actual)));
+    }
+    Ok(())
+}
+
 /*
 query_event:
     fix_part = 13:
@@ -130,36 +290,34 @@ pub struct QueryEvent{
 }
 
 impl InitValue for QueryEvent{
-    fn read_event<R: Read+Seek>(header: &EventHeader, buf: &mut R, version: &u8) -> QueryEvent{
-        let thread_id = buf.read_u32::<LittleEndian>().unwrap();
-        let execute_seconds = buf.read_u32::<LittleEndian>().unwrap();
-        let database_length = buf.read_u8().unwrap();
-        let _error_code = buf.read_u16::<LittleEndian>().unwrap();
-        let variable_block_length = buf.read_u16::<LittleEndian>().unwrap();
-        buf.seek(io::SeekFrom::Current(variable_block_length as i64)).unwrap();
+    fn read_event<R: Read+Seek>(header: &EventHeader, buf: &mut R, _version: &u8) -> BinlogResult<QueryEvent>{
+        let thread_id = buf.read_u32::<LittleEndian>()?;
+        let execute_seconds = buf.read_u32::<LittleEndian>()?;
+        let database_length = buf.read_u8()?;
+        let _error_code = buf.read_u16::<LittleEndian>()?;
+        let variable_block_length = buf.read_u16::<LittleEndian>()?;
+        if header.post_header_length > 13 {
+            buf.seek(io::SeekFrom::Current(header.post_header_length as i64 - 13))?;
+        }
+        buf.seek(io::SeekFrom::Current(variable_block_length as i64))?;
         let mut database_pack = vec![0u8; database_length as usize];
-        buf.read_exact(&mut database_pack).unwrap();
+        buf.read_exact(&mut database_pack)?;
         let database = readvalue::read_string_value(&database_pack);
-        buf.seek(io::SeekFrom::Current(1)).unwrap();
+        buf.seek(io::SeekFrom::Current(1))?;
 
 
+        //payload已去掉末尾的checksum
         let mut command_pak = vec![];
-        if *version == 5 {
-            let command_length = header.event_length as usize - header.header_length as usize - buf.tell().unwrap() as usize - 4;
-            command_pak = vec![0u8; command_length];
-            buf.read_exact(&mut command_pak).unwrap();
-        }else {
-            buf.read_to_end(&mut command_pak).unwrap();
-        }
+        buf.read_to_end(&mut command_pak)?;
 
         let command = readvalue::read_string_value(&command_pak);
 
-        QueryEvent{
+        Ok(QueryEvent{
             thread_id,
             execute_seconds,
             database,
             command
-        }
+        })
 
     }
 }
@@ -170,11 +328,11 @@ pub struct XidEvent{
 }
 
 impl InitValue for XidEvent{
-    fn read_event<R: Read>(_header: &EventHeader, buf: &mut R, _version: &u8) -> XidEvent{
-        let xid = buf.read_u64::<LittleEndian>().unwrap();
-        XidEvent{
+    fn read_event<R: Read>(_header: &EventHeader, buf: &mut R, _version: &u8) -> BinlogResult<XidEvent>{
+        let xid = buf.read_u64::<LittleEndian>()?;
+        Ok(XidEvent{
             xid
-        }
+        })
     }
 }
 
@@ -189,19 +347,14 @@ pub struct RotateLog{
 }
 
 impl InitValue for RotateLog{
-    fn read_event<R: Read+Seek>(header: &EventHeader, buf: &mut R, version: &u8) -> RotateLog{
-        let mut offset = 8 as usize;
-        if version == &5{
-            offset += 4;
-        }
-        buf.seek(io::SeekFrom::Current(8)).unwrap();
-        let len_gg = header.event_length as usize - header.header_length as usize - offset;
-        let mut tmp_buf = vec![0u8; len_gg];
-        buf.read_exact(&mut tmp_buf).unwrap();
+    fn read_event<R: Read+Seek>(_header: &EventHeader, buf: &mut R, _version: &u8) -> BinlogResult<RotateLog>{
+        buf.seek(io::SeekFrom::Current(8))?;
+        let mut tmp_buf = vec![];
+        buf.read_to_end(&mut tmp_buf)?;
         let binlog_file = String::from_utf8_lossy(&tmp_buf).to_string();
-        RotateLog{
+        Ok(RotateLog{
             binlog_file
-        }
+        })
     }
 }
 
@@ -220,14 +373,23 @@ table_map_event:
         mmetadata_lenth : 1bytes
         metadata : .....(only available in the variable length field，varchar:2bytes，text、blob:1bytes,time、timestamp、datetime: 1bytes
                         blob、float、decimal : 1bytes, char、enum、binary、set: 2bytes(column type id :1bytes metadatea: 1bytes))
-        bit_filed : 1bytes
+        null_bitmap : int((n+7)/8)
+        optional_metadata : 8.0.1及以上版本, 由binlog_row_metadata控制, 每项为 type(1bytes) + length(packed int) + value
+            1 signedness, 2 default_charset, 3 column_charset, 4 column_name, 5 set_str_value,
+            6 enum_str_value, 7 geometry_type, 8 simple_primary_key, 9 primary_key_with_prefix,
+            10 enum_and_set_default_charset, 11 enum_and_set_column_charset, 12 column_visibility
         crc : 4bytes
         .........
 */
 #[derive(Debug, Clone, Serialize)]
 pub struct ColumnInfo {
     pub column_type: ColumnTypeDict,
-    pub column_meta: Vec<usize>
+    pub column_meta: Vec<usize>,
+    pub nullable: bool,
+    pub unsigned: bool,             //optional metadata中的signedness
+    pub collation: Option<usize>,   //optional metadata中字符类型字段的collation id
+    pub name: Option<String>,       //binlog_row_metadata=FULL时才有
+    pub members: Vec<String>,       //enum/set字段的取值列表，binlog_row_metadata=FULL时才有
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -236,6 +398,7 @@ pub struct TableMap{
     pub table_name: String,
     pub column_count: u8,
     pub column_info: Vec<ColumnInfo>,
+    pub primary_key: Vec<usize>,    //optional metadata中的主键字段下标，按主键中的顺序
 }
 impl TableMap{
     pub fn new() -> TableMap {
@@ -243,108 +406,267 @@ impl TableMap{
             database_name: "".to_string(),
             table_name: "".to_string(),
             column_count: 0,
-            column_info: vec![]
+            column_info: vec![],
+            primary_key: vec![]
         }
     }
 
-    fn read_column_meta<R: Read>(buf: &mut R,col_type: &u8) -> (Vec<usize>, u8) {
+    ///
+    /// optional metadata中是否带有字段名，有时可以不连接mysql直接生成sql
+    ///
+    pub fn has_column_name(&self) -> bool {
+        self.column_info.len() > 0 && self.column_info.iter().all(|c| c.name.is_some())
+    }
+
+    pub fn read_packed_int<R: Read>(buf: &mut R) -> io::Result<u64> {
+        let v = buf.read_u8()?;
+        Ok(match v {
+            0xfc => buf.read_u16::<LittleEndian>()? as u64,
+            0xfd => buf.read_u24::<LittleEndian>()? as u64,
+            0xfe => buf.read_u64::<LittleEndian>()?,
+            _ => v as u64
+        })
+    }
+
+    fn is_numeric(col: &ColumnInfo) -> bool {
+        match col.column_type {
+            ColumnTypeDict::MysqlTypeTiny |
+            ColumnTypeDict::MysqlTypeShort |
+            ColumnTypeDict::MysqlTypeInt24 |
+            ColumnTypeDict::MysqlTypeLong |
+            ColumnTypeDict::MysqlTypeLonglong |
+            ColumnTypeDict::MysqlTypeFloat |
+            ColumnTypeDict::MysqlTypeDouble |
+            ColumnTypeDict::MysqlTypeDecimal |
+            ColumnTypeDict::MysqlTypeNewdecimal => true,
+            _ => false
+        }
+    }
+
+    ///
+    /// 根据information_schema中的字段信息补充signedness，table map中没有该信息时使用
+    ///
+    pub fn set_unsigned(&mut self, cols: &Vec<ColumnMeta>) {
+        for (info, col) in self.column_info.iter_mut().zip(cols.iter()) {
+            if col.is_unsigned {
+                info.unsigned = true;
+            }
+        }
+    }
+
+    fn is_character(col: &ColumnInfo) -> bool {
+        match col.column_type {
+            ColumnTypeDict::MysqlTypeString |
+            ColumnTypeDict::MysqlTypeVarchar |
+            ColumnTypeDict::MysqlTypeVarString |
+            ColumnTypeDict::MysqlTypeBlob |
+            ColumnTypeDict::MysqlTypeTinyBlob |
+            ColumnTypeDict::MysqlTypeMediumBlob |
+            ColumnTypeDict::MysqlTypeLongBlob => true,
+            _ => false
+        }
+    }
+
+    ///
+    /// 解析optional metadata, 不认识的类型根据长度跳过
+    ///
+    fn read_optional_metadata(&mut self, buf: &Vec<u8>) -> io::Result<()> {
+        let mut cur = io::Cursor::new(buf);
+        while (cur.position() as usize) < buf.len() {
+            let meta_type = cur.read_u8()?;
+            let length = Self::read_packed_int(&mut cur)? as usize;
+            let start = cur.position() as usize;
+            if start + length > buf.len() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "invalid table map optional metadata"));
+            }
+            let mut value = io::Cursor::new(buf[start..start + length].to_vec());
+            match meta_type {
+                1 => {
+                    //每个数字类型字段1bit, 高位在前
+                    let numeric: Vec<usize> = (0..self.column_info.len()).filter(|idx| Self::is_numeric(&self.column_info[*idx])).collect();
+                    for (n, idx) in numeric.iter().enumerate() {
+                        if let Some(byte) = buf.get(start + n / 8) {
+                            self.column_info[*idx].unsigned = byte & (0x80 >> (n % 8)) > 0;
+                        }
+                    }
+                }
+                2 => {
+                    //默认collation + (字符类型字段序号, collation)
+                    let character: Vec<usize> = (0..self.column_info.len()).filter(|idx| Self::is_character(&self.column_info[*idx])).collect();
+                    let default = Self::read_packed_int(&mut value)? as usize;
+                    for idx in &character {
+                        self.column_info[*idx].collation = Some(default);
+                    }
+                    while (value.position() as usize) < length {
+                        let n = Self::read_packed_int(&mut value)? as usize;
+                        let collation = Self::read_packed_int(&mut value)? as usize;
+                        if let Some(idx) = character.get(n) {
+                            self.column_info[*idx].collation = Some(collation);
+                        }
+                    }
+                }
+                3 => {
+                    let character: Vec<usize> = (0..self.column_info.len()).filter(|idx| Self::is_character(&self.column_info[*idx])).collect();
+                    for idx in &character {
+                        if (value.position() as usize) >= length {
+                            break;
+                        }
+                        self.column_info[*idx].collation = Some(Self::read_packed_int(&mut value)? as usize);
+                    }
+                }
+                4 => {
+                    for idx in 0..self.column_info.len() {
+                        if (value.position() as usize) >= length {
+                            break;
+                        }
+                        let name_length = Self::read_packed_int(&mut value)? as usize;
+                        let name = String::from_utf8_lossy(&readvalue::read_nbytes(&mut value, name_length)?).to_string();
+                        self.column_info[idx].name = Some(name);
+                    }
+                }
+                5 | 6 => {
+                    //每个set(5)/enum(6)字段: 取值个数 + (长度, 值)
+                    let real_type = if meta_type == 5 { ColumnTypeDict::MysqlTypeSet } else { ColumnTypeDict::MysqlTypeEnum };
+                    let columns: Vec<usize> = (0..self.column_info.len()).filter(|idx| self.column_info[*idx].column_type == real_type).collect();
+                    for idx in &columns {
+                        if (value.position() as usize) >= length {
+                            break;
+                        }
+                        let count = Self::read_packed_int(&mut value)? as usize;
+                        let mut members = vec![];
+                        for _ in 0..count {
+                            let member_length = Self::read_packed_int(&mut value)? as usize;
+                            members.push(String::from_utf8_lossy(&readvalue::read_nbytes(&mut value, member_length)?).to_string());
+                        }
+                        self.column_info[*idx].members = members;
+                    }
+                }
+                8 => {
+                    while (value.position() as usize) < length {
+                        self.primary_key.push(Self::read_packed_int(&mut value)? as usize);
+                    }
+                }
+                9 => {
+                    while (value.position() as usize) < length {
+                        self.primary_key.push(Self::read_packed_int(&mut value)? as usize);
+                        let _prefix = Self::read_packed_int(&mut value)?;
+                    }
+                }
+                _ => {}
+            }
+            cur.set_position((start + length) as u64);
+        }
+        Ok(())
+    }
+
+    fn read_column_meta<R: Read>(buf: &mut R,col_type: &u8) -> io::Result<(Vec<usize>, u8)> {
         let mut value: Vec<usize> = vec![];
         //let mut offset = offset;
         let mut col_type = col_type.clone();
         let column_type_info = ColumnTypeDict::from_type_code(&col_type);
         match column_type_info {
             ColumnTypeDict::MysqlTypeVarString => {
-                value = Self::read_string_meta(buf);
+                value = Self::read_string_meta(buf)?;
             }
             ColumnTypeDict::MysqlTypeVarchar => {
-                value = Self::read_string_meta(buf);
+                value = Self::read_string_meta(buf)?;
             }
             ColumnTypeDict::MysqlTypeBlob => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeMediumBlob => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeLongBlob => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeTinyBlob => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
-            ColumnTypeDict::MysqlTypeJson => {
-                value = Self::read_one_bytes(buf);
+            ColumnTypeDict::MysqlTypeJson |
+            ColumnTypeDict::MysqlTypeGeometry => {
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeTimestamp2 => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeDatetime2 => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeTime2 => {
                 //value = vec![buf[offset] as usize];
                 //offset += 1;
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeNewdecimal => {
-                value.extend(Self::read_newdecimal(buf).to_owned().to_vec());
+                value.extend(Self::read_newdecimal(buf)?.to_vec());
             }
             ColumnTypeDict::MysqlTypeFloat => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
             }
             ColumnTypeDict::MysqlTypeDouble => {
-                value = Self::read_one_bytes(buf);
+                value = Self::read_one_bytes(buf)?;
+            }
+            ColumnTypeDict::MysqlTypeBit => {
+                //bit(M): M%8, M/8
+                let bits = buf.read_u8()? as usize;
+                let bytes = buf.read_u8()? as usize;
+                value = vec![bits, bytes];
             }
             ColumnTypeDict::MysqlTypeString => {
-                let (a, b) = Self::read_string_type(buf);
+                let (a, b) = Self::read_string_type(buf)?;
                 value = a;
                 col_type = b;
             }
+            ColumnTypeDict::UnknowType => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported column type: {}", col_type)));
+            }
             _ => {
                 value = vec![0];
             }
         }
-        return (value, col_type);
+        return Ok((value, col_type));
     }
 
-    fn read_one_bytes<R: Read>(buf: &mut R) -> Vec<usize> {
-        let v = buf.read_u8().unwrap() as usize;
-        vec![v]
+    fn read_one_bytes<R: Read>(buf: &mut R) -> io::Result<Vec<usize>> {
+        let v = buf.read_u8()? as usize;
+        Ok(vec![v])
     }
 
 
-    fn read_string_meta<R: Read>(buf: &mut R) -> Vec<usize> {
-        let metadata = buf.read_u16::<LittleEndian>().unwrap();
+    fn read_string_meta<R: Read>(buf: &mut R) -> io::Result<Vec<usize>> {
+        let metadata = buf.read_u16::<LittleEndian>()?;
         let mut v = vec![];
         if metadata > 255 {
             v.push(2);
         }else {
             v.push(1);
         }
-        v
+        Ok(v)
     }
 
-    fn read_newdecimal<R: Read>(buf: &mut R) -> [usize;2] {
-        let precision = buf.read_u8().unwrap() as usize;
-        let decimals = buf.read_u8().unwrap() as usize;
-        [precision,decimals]
+    fn read_newdecimal<R: Read>(buf: &mut R) -> io::Result<[usize;2]> {
+        let precision = buf.read_u8()? as usize;
+        let decimals = buf.read_u8()? as usize;
+        Ok([precision,decimals])
     }
 
-    fn read_string_type<R: Read>(buf: &mut R) -> (Vec<usize>, u8) {
-        let _type = buf.read_u8().unwrap();
+    fn read_string_type<R: Read>(buf: &mut R) -> io::Result<(Vec<usize>, u8)> {
+        let _type = buf.read_u8()?;
         let code = ColumnTypeDict::from_type_code(&_type);
 
-        let metadata = buf.read_u8().unwrap() as usize;
+        let metadata = buf.read_u8()? as usize;
         match code {
             ColumnTypeDict::MysqlTypeEnum |
             ColumnTypeDict::MysqlTypeSet |
             ColumnTypeDict::MysqlTypeString => {
-                return (vec! [metadata], _type);
+                return Ok((vec! [metadata], _type));
             }
             ColumnTypeDict::UnknowType => {
-                return (vec! [65535], 254);
+                return Ok((vec! [65535], 254));
             }
             _ => {
-                return (vec! [65535], 254);
+                return Ok((vec! [65535], 254));
             }
         }
     }
@@ -352,32 +674,61 @@ impl TableMap{
 }
 
 impl InitValue for TableMap{
-    fn read_event<R: Read+Seek>( _header: &EventHeader,buf: &mut R, _version: &u8) -> TableMap{
-        buf.seek(io::SeekFrom::Current(8)).unwrap();
-        let database_length = buf.read_u8().unwrap() as usize;
-        let database_name = readvalue::read_string_value_from_len(buf, database_length);
-        buf.seek(io::SeekFrom::Current(1)).unwrap();
-        let table_length = buf.read_u8().unwrap() as usize;
-        let table_name = readvalue::read_string_value_from_len(buf, table_length);
-        buf.seek(io::SeekFrom::Current(1)).unwrap();
-
-        let column_count = buf.read_u8().unwrap();
+    fn read_event<R: Read+Seek>( header: &EventHeader,buf: &mut R, _version: &u8) -> BinlogResult<TableMap>{
+        //post header长度为6时table_id为4bytes
+        match header.post_header_length {
+            0 => buf.seek(io::SeekFrom::Current(8))?,
+            v => buf.seek(io::SeekFrom::Current(v as i64))?
+        };
+        let database_length = buf.read_u8()? as usize;
+        let database_name = String::from_utf8_lossy(&readvalue::read_nbytes(buf, database_length)?).to_string();
+        buf.seek(io::SeekFrom::Current(1))?;
+        let table_length = buf.read_u8()? as usize;
+        let table_name = String::from_utf8_lossy(&readvalue::read_nbytes(buf, table_length)?).to_string();
+        buf.seek(io::SeekFrom::Current(1))?;
+
+        let column_count = buf.read_u8()?;
         let mut column_info: Vec<ColumnInfo> = vec![];
         let mut column_type_list = vec![0u8; column_count as usize];
-        buf.read_exact(&mut column_type_list).unwrap();
-        buf.seek(io::SeekFrom::Current(1)).unwrap(); //跳过mmetadata_lenth,直接用字段数据进行判断
+        buf.read_exact(&mut column_type_list)?;
+        //metadata部分单独读取，避免个别类型解析不正确时影响后面的null bitmap及optional metadata
+        let metadata_length = Self::read_packed_int(buf)? as usize;
+        let mut metadata = vec![0u8; metadata_length];
+        buf.read_exact(&mut metadata)?;
+        let mut meta_cur = io::Cursor::new(metadata);
         for col_type in column_type_list.iter() {
-            let (col_meta, col_type) = Self::read_column_meta(buf, col_type);
-            column_info.push(ColumnInfo{column_type: ColumnTypeDict::from_type_code(&col_type),column_meta: col_meta});
+            let (col_meta, col_type) = Self::read_column_meta(&mut meta_cur, col_type)?;
+            column_info.push(ColumnInfo{
+                column_type: ColumnTypeDict::from_type_code(&col_type),
+                column_meta: col_meta,
+                nullable: false,
+                unsigned: false,
+                collation: None,
+                name: None,
+                members: vec![]
+            });
+        }
+        let mut null_bitmap = vec![0u8; (column_count as usize + 7) / 8];
+        if buf.read_exact(&mut null_bitmap).is_ok() {
+            for (idx, col) in column_info.iter_mut().enumerate() {
+                col.nullable = crate::binlog::parsevalue::is_null(&null_bitmap, &idx) > 0;
+            }
         }
 
-
-        TableMap{
+        let mut map = TableMap{
             database_name,
             table_name,
             column_count,
-            column_info
+            column_info,
+            primary_key: vec![]
+        };
+        //payload已去掉checksum, 剩余部分都是optional metadata
+        let mut optional = vec![];
+        buf.read_to_end(&mut optional)?;
+        if let Err(e) = map.read_optional_metadata(&optional) {
+            info!("table map optional metadata error: {}", e);
         }
+        Ok(map)
     }
 }
 
@@ -413,22 +764,25 @@ pub struct GtidEvent{
 }
 
 impl InitValue for GtidEvent {
-    fn read_event<R: Read+Seek>(_header: &EventHeader, buf: &mut R, _version: &u8) -> GtidEvent {
-        buf.seek(io::SeekFrom::Current(1)).unwrap();
+    fn read_event<R: Read+Seek>(_header: &EventHeader, buf: &mut R, _version: &u8) -> BinlogResult<GtidEvent> {
+        buf.seek(io::SeekFrom::Current(1))?;
         let mut sid = [0 as u8; 16];
-        buf.read_exact(&mut sid).unwrap();
+        buf.read_exact(&mut sid)?;
 
         let gtid = uuid::Uuid::from_bytes(sid);
-        let gno_id = buf.read_u64::<LittleEndian>().unwrap();
+        let gno_id = buf.read_u64::<LittleEndian>()?;
 
-        let last_committed = buf.read_u64::<LittleEndian>().unwrap();
-        let sequence_number = buf.read_u64::<LittleEndian>().unwrap();
+        //lt_type(1bytes)为2时后面是last_committed、sequence_number, 5.6没有这部分
+        let (last_committed, sequence_number) = match buf.read_u8() {
+            Ok(2) => (buf.read_u64::<LittleEndian>()?, buf.read_u64::<LittleEndian>()?),
+            _ => (0, 0)
+        };
 
-        GtidEvent{
+        Ok(GtidEvent{
             gtid,
             gno_id,
             last_committed,
             sequence_number
-        }
+        })
     }
 }
@@ -9,158 +9,318 @@ use crate::binlog::readevent::{EventHeader, BinlogEvent, TableMap, Tell};
 use byteorder::{ReadBytesExt, LittleEndian};
 use crate::meta::ColumnTypeDict;
 use crate::{readvalue};
+use std::io;
+use crate::binlog::error::{BinlogError, BinlogResult};
+use crate::binlog::parsevalue::{read_bytes, read_partial_bits, is_null, is_present, null_bitmap_len};
+use crate::binlog::jsonb;
 
+/*
+row event:
+    header : 19bytes
+    table_id : 6bytes
+    flags : 2bytes
+    extra_len : 2bytes (v2, 包含自身的长度)
+    extra_data : extra_len - 2 bytes (v2)
+    columns : packed int
+    columns-present bitmap : int((n+7)/8)
+    columns-present bitmap : int((n+7)/8) (update only, after image)
+    rows : null bitmap + 字段值，update中before/after image成对出现
+           partial update的after image在null bitmap前面还有value_options和partial bits
 
-pub fn rollback_row_event(event: &Vec<u8>, event_header: &EventHeader, map: &TableMap) -> Vec<u8> {
-    let mut new_row_event: Vec<u8> = event.clone();
-    match event_header.type_code {
-        BinlogEvent::UpdateEvent => {
-            let mut cur = Cursor::new(new_row_event);
-            update_event(&mut cur, map, event_header)
-        }
-        BinlogEvent::DeleteEvent => {
-            new_row_event[4] = 30;
-            new_row_event
-        }
-        BinlogEvent::WriteEvent => {
-            new_row_event[4] = 32;
-            new_row_event
-        }
-        _ => {
-            event.clone()
-        }
+回滚:
+    write <-> delete 互换类型
+    update 互换before/after image及两个columns-present bitmap
+    partial update 的json diff应用到before image上得到完整的值，转换为普通的update event后再互换
+    一个event中有多行时行的顺序也要反过来，后修改的先回滚
+*/
+const WRITE_ROWS_EVENT_V1: u8 = 23;
+const UPDATE_ROWS_EVENT_V1: u8 = 24;
+const DELETE_ROWS_EVENT_V1: u8 = 25;
+const WRITE_ROWS_EVENT: u8 = 30;
+const UPDATE_ROWS_EVENT: u8 = 31;
+const DELETE_ROWS_EVENT: u8 = 32;
+const PARTIAL_UPDATE_ROWS_EVENT: u8 = 39;
+
+///
+/// 生成回滚的row event
+///     event为包含19bytes header的完整event，不包含末尾的checksum
+///     返回的event同样不包含checksum，header中的event_length为新event的长度
+///
+pub fn rollback_row_event(event: &Vec<u8>, event_header: &EventHeader, map: &TableMap) -> BinlogResult<Vec<u8>> {
+    if event.len() != event_header.event_length as usize {
+        return Err(BinlogError::corrupt(format!("row event length {} does not match header {}", event.len(), event_header.event_length)));
     }
+    let mut cur = Cursor::new(event);
+    let mut rows_event = RowsEvent::read(&mut cur, event_header, map)?;
+    rows_event.reverse(map)?;
+    Ok(rows_event.write())
 }
 
-fn tmp_var() -> (Vec<u8>, Vec<u8>){
-    (vec![],vec![])
+///
+/// 一个row image，values为每个字段的原始字节(包含长度部分)，不在image中或为NULL时为None
+///     partial为after image中只记录了json diff的字段
+///
+struct RowImage {
+    nulls: Vec<u8>,
+    values: Vec<Option<Vec<u8>>>,
+    partial: Vec<usize>,
 }
 
-fn update_event<R: Read+Seek>(event: &mut R, map: &TableMap, event_header: &EventHeader) -> Vec<u8> {
-    let mut new_row_event: Vec<u8> = vec![];
-    let mut header = [0u8; 19];
-    event.read_exact(&mut header).unwrap();
-    new_row_event.extend(&header);
+impl RowImage {
+    fn read<R: Read + Seek>(event: &mut R, map: &TableMap, image: &Vec<u8>, partial_bits: &Option<Vec<u8>>) -> BinlogResult<RowImage> {
+        let columns = map.column_info.len();
+        let nulls = read_bytes(event, null_bitmap_len(image, columns))?;
+        let mut values = vec![];
+        let mut partial = vec![];
+        let mut null_idx = 0;
+        let mut json_idx = 0;
+        for idx in 0..columns {
+            if !is_present(image, &idx) {
+                values.push(None);
+                continue;
+            }
+            let col = &map.column_info[idx];
+            if let (Some(bits), ColumnTypeDict::MysqlTypeJson) = (partial_bits, &col.column_type) {
+                if is_null(bits, &json_idx) > 0 {
+                    partial.push(idx);
+                }
+                json_idx += 1;
+            }
+            if is_null(&nulls, &null_idx) == 0 {
+                values.push(Some(parese_row_bytes(event, &col.column_type, &col.column_meta)?));
+            } else {
+                values.push(None);
+            }
+            null_idx += 1;
+        }
+        Ok(RowImage{ nulls, values, partial })
+    }
 
-    let mut fix_buf = [0u8; 8];
-    event.read_exact(&mut fix_buf).unwrap();
-    new_row_event.extend(&fix_buf);
+    fn write(&self, pack: &mut Vec<u8>) {
+        pack.extend(&self.nulls);
+        for value in self.values.iter() {
+            if let Some(v) = value {
+                pack.extend(v);
+            }
+        }
+    }
+}
 
-    let mut extra = [0u8; 2];
-    event.read_exact(&mut extra).unwrap();
-    new_row_event.extend(&extra);
-    let a = crate::readvalue::read_u16(&extra);
+struct RowsEvent {
+    header: Vec<u8>,
+    post_header: Vec<u8>,   //table_id、flags及v2的extra data，原样保留
+    columns: usize,
+    present: Vec<u8>,
+    present_after: Vec<u8>,
+    rows: Vec<RowImage>,
+}
 
-    if a > 2{
-        let tmp = a -2;
-        let mut extra_o = vec![0u8; tmp as usize];
-        event.read_exact(&mut extra_o).unwrap();
-        new_row_event.extend(&extra_o);
-    }
+impl RowsEvent {
+    fn read<R: Read + Seek>(event: &mut R, event_header: &EventHeader, map: &TableMap) -> BinlogResult<RowsEvent> {
+        let header = read_bytes(event, event_header.header_length as usize)?;
+        let mut post_header = read_bytes(event, 8)?;
+        if !event_header.is_rows_v1() {
+            let extra = read_bytes(event, 2)?;
+            let extra_len = readvalue::read_u16(&extra) as usize;
+            post_header.extend(&extra);
+            if extra_len > 2 {
+                post_header.extend(read_bytes(event, extra_len - 2)?);
+            }
+        }
 
-    let cols = event.read_u8().unwrap();
-    new_row_event.push(cols);
+        let columns = TableMap::read_packed_int(event)? as usize;
+        if columns != map.column_info.len() {
+            return Err(BinlogError::corrupt(format!("row event has {} columns, table map of {}.{} has {}",
+                                                    columns, map.database_name, map.table_name, map.column_info.len())));
+        }
+        let present = read_bytes(event, (columns + 7) / 8)?;
+        let is_update = match event_header.type_code {
+            BinlogEvent::UpdateEvent => true,
+            _ => false
+        };
+        let present_after = match is_update {
+            true => read_bytes(event, (columns + 7) / 8)?,
+            false => present.clone()
+        };
 
-    let cols_var = ((cols + 7) / 8) as usize ;
-    let mut vars = vec![0u8; (cols_var * 2) as usize];
-    event.read_exact(&mut vars).unwrap();
-    new_row_event.extend(&vars);
-    let (mut before_bytes , mut after_bytes)= tmp_var();
-    loop {
-        let mut nulls = vec![0u8; cols_var];
-        event.read_exact(&mut nulls).unwrap();
-        //new_row_event.extend(&nulls);
+        let mut rows = vec![];
+        //event为完整的event，位置和event_length都从header开始算
+        while event.tell()? < event_header.event_length as u64 {
+            let is_after = is_update && rows.len() % 2 == 1;
+            let partial_bits = match is_after && event_header.is_partial_update() {
+                true => read_partial_bits(event, map)?,
+                false => None
+            };
+            let image = if is_after { &present_after } else { &present };
+            rows.push(RowImage::read(event, map, image, &partial_bits)?);
+        }
+        if is_update && rows.len() % 2 == 1 {
+            return Err(BinlogError::corrupt("update event has a before image without after image"));
+        }
+        Ok(RowsEvent{ header, post_header, columns, present, present_after, rows })
+    }
 
-        let columns = map.column_info.len();
-        let mut row_bytes: Vec<u8> = vec![];
-        for idx in 0..columns {
-            if crate::binlog::parsevalue::is_null(&nulls, &idx) > 0{
+    fn reverse(&mut self, map: &TableMap) -> BinlogResult<()> {
+        let type_byte = match self.header[4] {
+            WRITE_ROWS_EVENT_V1 => DELETE_ROWS_EVENT_V1,
+            DELETE_ROWS_EVENT_V1 => WRITE_ROWS_EVENT_V1,
+            WRITE_ROWS_EVENT => DELETE_ROWS_EVENT,
+            DELETE_ROWS_EVENT => WRITE_ROWS_EVENT,
+            UPDATE_ROWS_EVENT_V1 => UPDATE_ROWS_EVENT_V1,
+            UPDATE_ROWS_EVENT => UPDATE_ROWS_EVENT,
+            PARTIAL_UPDATE_ROWS_EVENT => {
+                self.fill_partial_json(map)?;
+                UPDATE_ROWS_EVENT
+            }
+            v => return Err(BinlogError::unsupported(format!("rollback of event type {}", v)))
+        };
+        self.header[4] = type_byte;
 
-            } else {
-                let col_bytes = parese_row_bytes(event, &map.column_info[idx].column_type, &map.column_info[idx].column_meta);
-                row_bytes.extend(col_bytes);
+        match type_byte {
+            UPDATE_ROWS_EVENT_V1 | UPDATE_ROWS_EVENT => {
+                std::mem::swap(&mut self.present, &mut self.present_after);
+                //行的顺序和每行的before/after image同时反过来
+                self.rows.reverse();
+            }
+            _ => {
+                self.rows.reverse();
             }
         }
+        Ok(())
+    }
 
-        if before_bytes.len() == 0 {
-            before_bytes.extend(nulls);
-            before_bytes.extend(row_bytes);
-        }else {
-            after_bytes.extend(nulls);
-            after_bytes.extend(row_bytes);
-            new_row_event.extend(after_bytes);
-            new_row_event.extend(before_bytes);
-            before_bytes = vec![];
-            after_bytes = vec![];
-        }
+    ///
+    /// 把partial json update中after image的diff应用到before image上，替换为完整的json值
+    ///
+    fn fill_partial_json(&mut self, map: &TableMap) -> BinlogResult<()> {
+        for pair in self.rows.chunks_mut(2) {
+            let (before, after) = pair.split_at_mut(1);
+            let (before, after) = (&before[0], &mut after[0]);
+            for idx in after.partial.drain(..) {
+                let meta = map.column_info[idx].column_meta[0];
+                let diff = match &after.values[idx] {
+                    Some(v) if v.len() >= meta => v[meta..].to_vec(),
+                    Some(_) => return Err(BinlogError::corrupt(format!("invalid json diff of column {}", idx))),
+                    //after image中为NULL，不需要处理
+                    None => continue
+                };
+                let mut doc = match &before.values[idx] {
+                    Some(v) if v.len() >= meta => jsonb::read_binary_json(&mut Cursor::new(&v[meta..]), &(v.len() - meta))?,
+                    _ => return Err(BinlogError::unsupported(format!("partial json update of column {} without before image", idx)))
+                };
+                jsonb::apply_json_diff(&mut doc, &jsonb::read_json_diff(&diff)?)?;
 
-        if (event.tell().unwrap() + 4) as usize >= event_header.event_length as usize {
-            let mut a = vec![];
-            event.read_to_end(&mut a).unwrap();
-            if a.len() > 0 {
-                new_row_event.extend(a);
+                let data = jsonb::write_binary_json(&doc)?;
+                if meta > 8 || (meta < 8 && data.len() as u64 >= 1u64 << (meta * 8)) {
+                    return Err(BinlogError::unsupported(format!("json value of {} bytes is too long for column {}", data.len(), idx)));
+                }
+                let mut value = (data.len() as u64).to_le_bytes()[..meta].to_vec();
+                value.extend(data);
+                after.values[idx] = Some(value);
             }
+        }
+        Ok(())
+    }
 
-            break;
+    fn write(&self) -> Vec<u8> {
+        let mut pack = self.header.clone();
+        pack.extend(&self.post_header);
+        write_packed_int(&mut pack, self.columns as u64);
+        pack.extend(&self.present);
+        match self.header[4] {
+            UPDATE_ROWS_EVENT_V1 | UPDATE_ROWS_EVENT => pack.extend(&self.present_after),
+            _ => {}
+        }
+        for row in self.rows.iter() {
+            row.write(&mut pack);
         }
+        let event_length = pack.len() as u32;
+        pack[9..13].copy_from_slice(&event_length.to_le_bytes());
+        pack
     }
+}
 
-    new_row_event
+fn write_packed_int(pack: &mut Vec<u8>, value: u64) {
+    if value < 251 {
+        pack.push(value as u8);
+    } else if value < (1 << 16) {
+        pack.push(0xfc);
+        pack.extend(&(value as u16).to_le_bytes());
+    } else if value < (1 << 24) {
+        pack.push(0xfd);
+        pack.extend(&(value as u32).to_le_bytes()[..3]);
+    } else {
+        pack.push(0xfe);
+        pack.extend(&value.to_le_bytes());
+    }
 }
 
 
-fn parese_row_bytes<R: Read + Tell>(buf: &mut R, type_code: &ColumnTypeDict, col_meta: &Vec<usize>) -> Vec<u8> {
+///
+/// 读取单个字段值的原始字节，只需要确定长度，不解析值
+///     不认识的类型返回错误，避免后面的字段错位
+///
+fn parese_row_bytes<R: Read + Tell>(buf: &mut R, type_code: &ColumnTypeDict, col_meta: &Vec<usize>) -> BinlogResult<Vec<u8>> {
     let mut row_bytes= vec![];
-    let mut tmp = vec![];
+    let mut length = 0;
     match type_code {
         ColumnTypeDict::MysqlTypeTiny => {
-            tmp = vec![0u8; 1];
+            length = 1;
             //row_bytes.push(buf.read(row_bytes.as_mut()).unwrap() as u8);
         }
         ColumnTypeDict::MysqlTypeShort => {
-            tmp = vec![0u8; 2];
+            length = 2;
         }
         ColumnTypeDict::MysqlTypeInt24 => {
-            tmp = vec![0u8; 3];
+            length = 3;
         }
         ColumnTypeDict::MysqlTypeLong => {
-            tmp = vec![0u8; 4];
+            length = 4;
         }
         ColumnTypeDict::MysqlTypeLonglong => {
-            tmp = vec![0u8; 8];
+            length = 8;
         }
         ColumnTypeDict::MysqlTypeNewdecimal => {
-            let decimal_meta = crate::binlog::parsevalue::DecimalMeta::new(col_meta[0] as u8, col_meta[1] as u8);
-            tmp = vec![0u8; decimal_meta.bytes_to_read];
+            let decimal_meta = crate::binlog::parsevalue::DecimalMeta::new(col_meta[0] as u8, col_meta[1] as u8)?;
+            length = decimal_meta.bytes_to_read;
         }
         ColumnTypeDict::MysqlTypeDouble |
         ColumnTypeDict::MysqlTypeFloat => {
             match col_meta[0] {
                 8 => {
-                    tmp = vec![0u8; 8];
+                    length = 8;
                 },
-                4 => tmp = vec![0u8; 4],
-                _ => {}
+                4 => length = 4,
+                v => return Err(BinlogError::corrupt(format!("invalid float length: {}", v)))
             }
         }
         ColumnTypeDict::MysqlTypeTimestamp2 => {
             let frac_part = read_datetime_fsp(col_meta[0] as u8);
-            tmp = vec![0u8; (4 + frac_part) as usize];
+            length = (4 + frac_part) as usize;
         }
         ColumnTypeDict::MysqlTypeDatetime2 => {
             let subsecond = read_datetime_fsp(col_meta[0] as u8);
-            tmp = vec![0u8; (5 + subsecond) as usize];
+            length = (5 + subsecond) as usize;
         }
         ColumnTypeDict::MysqlTypeYear => {
-            tmp = vec![0u8; 1];
+            length = 1;
         }
-        ColumnTypeDict::MysqlTypeDate => {
-            tmp = vec![0u8; 3];
+        ColumnTypeDict::MysqlTypeDate |
+        ColumnTypeDict::MysqlTypeNewdate |
+        ColumnTypeDict::MysqlTypeTime => {
+            length = 3;
 
         }
+        ColumnTypeDict::MysqlTypeTimestamp => {
+            length = 4;
+        }
+        ColumnTypeDict::MysqlTypeDatetime => {
+            length = 8;
+        }
+        ColumnTypeDict::MysqlTypeNull => {}
         ColumnTypeDict::MysqlTypeTime2 => {
             let frac_part = read_datetime_fsp(col_meta[0] as u8);
-            tmp = vec![0u8; (3 + frac_part) as usize];
+            length = (3 + frac_part) as usize;
         }
         ColumnTypeDict::MysqlTypeVarString |
         ColumnTypeDict::MysqlTypeVarchar |
@@ -168,54 +328,58 @@ fn parese_row_bytes<R: Read + Tell>(buf: &mut R, type_code: &ColumnTypeDict, col
         ColumnTypeDict::MysqlTypeTinyBlob |
         ColumnTypeDict::MysqlTypeLongBlob |
         ColumnTypeDict::MysqlTypeMediumBlob |
-        ColumnTypeDict::MysqlTypeBit => {
-            let (var_bytes,var_length) =  read_str_value_length(buf, &col_meta[0]);
-            tmp = vec![0u8; var_length];
+        ColumnTypeDict::MysqlTypeGeometry => {
+            let (var_bytes,var_length) =  read_str_value_length(buf, &col_meta[0])?;
+            length = var_length;
             row_bytes.extend(var_bytes);
 
         }
+        ColumnTypeDict::MysqlTypeBit => {
+            length = crate::binlog::parsevalue::bit_bytes(col_meta);
+        }
         ColumnTypeDict::MysqlTypeJson => {
-            let (var_bytes,var_length) =  read_str_value_length(buf, &col_meta[0]);
-            tmp = vec![0u8; var_length];
+            let (var_bytes,var_length) =  read_str_value_length(buf, &col_meta[0])?;
+            length = var_length;
             row_bytes.extend(var_bytes);
         }
         ColumnTypeDict::MysqlTypeString => {
             //let mut value_length = 0;
             //println!("aa:{},{}",col_meta[0],buf.tell().unwrap());
             if col_meta[0] <= 255 {
-                let value_length = buf.read_u8().unwrap() as usize;
+                let value_length = buf.read_u8()? as usize;
                 row_bytes.push(value_length as u8);
-                tmp = vec![0u8; value_length];
+                length = value_length;
             }
             else {
                 let mut var_bytes = [0u8; 2];
-                buf.read_exact(&mut var_bytes).unwrap();
+                buf.read_exact(&mut var_bytes)?;
                 row_bytes.extend(&var_bytes);
-                buf.seek(SeekFrom::Current(-2)).unwrap();
-                let value_length = buf.read_u16::<LittleEndian>().unwrap() as usize;
-                tmp = vec![0u8; value_length];
+                buf.seek(SeekFrom::Current(-2))?;
+                let value_length = buf.read_u16::<LittleEndian>()? as usize;
+                length = value_length;
             }
             //tmp = vec![0u8; value_length];
         }
-        ColumnTypeDict::MysqlTypeEnum |
+        ColumnTypeDict::MysqlTypeEnum => {
+            match col_meta[0] {
+                1 | 2 => length = col_meta[0],
+                v => return Err(BinlogError::corrupt(format!("invalid enum length: {}", v)))
+            }
+        }
         ColumnTypeDict::MysqlTypeSet => {
             match col_meta[0] {
-                1 => {
-                    tmp = vec![0u8; 1];
-                },
-                2 => {
-                    tmp = vec![0u8; 2];
-                }
-                _ => {}
+                1..=8 => length = col_meta[0],
+                v => return Err(BinlogError::corrupt(format!("invalid set length: {}", v)))
             }
         }
-        _ => {}
+        _ => {
+            return Err(BinlogError::unsupported(format!("column type {:?}", type_code)));
+        }
     }
-    if tmp.len() > 0 {
-        buf.read_exact(tmp.as_mut()).unwrap();
+    if length > 0 {
+        row_bytes.extend(read_bytes(buf, length)?);
     }
-    row_bytes.extend(tmp);
-    row_bytes
+    Ok(row_bytes)
 }
 
 fn read_datetime_fsp(column: u8) -> u8 {
@@ -228,59 +392,59 @@ fn read_datetime_fsp(column: u8) -> u8 {
     }
 }
 
-fn read_str_value_length<R: Read + Seek>(buf: &mut R, meta: &usize) -> (Vec<u8>,usize) {
+fn read_str_value_length<R: Read + Seek>(buf: &mut R, meta: &usize) -> io::Result<(Vec<u8>,usize)> {
     let mut var_bytes = vec![];
-    let mut var_len: usize = 0;
+    let var_len: usize;
     match meta {
         1 => {
-            var_len = buf.read_u8().unwrap() as usize;
+            var_len = buf.read_u8()? as usize;
             var_bytes.push(var_len as u8);
         },
         2 => {
             var_bytes = vec![0u8; 2];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
-            buf.seek(SeekFrom::Current(-2)).unwrap();
-            var_len = buf.read_u16::<LittleEndian>().unwrap() as usize;
+            buf.read_exact(var_bytes.as_mut())?;
+            buf.seek(SeekFrom::Current(-2))?;
+            var_len = buf.read_u16::<LittleEndian>()? as usize;
         },
         3 => {
             var_bytes = vec![0u8; 3];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
-            buf.seek(SeekFrom::Current(-3)).unwrap();
-            var_len = buf.read_u24::<LittleEndian>().unwrap() as usize;
+            buf.read_exact(var_bytes.as_mut())?;
+            buf.seek(SeekFrom::Current(-3))?;
+            var_len = buf.read_u24::<LittleEndian>()? as usize;
         },
         4 => {
             var_bytes = vec![0u8; 4];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
-            buf.seek(SeekFrom::Current(-4)).unwrap();
-            var_len = buf.read_u32::<LittleEndian>().unwrap() as usize
+            buf.read_exact(var_bytes.as_mut())?;
+            buf.seek(SeekFrom::Current(-4))?;
+            var_len = buf.read_u32::<LittleEndian>()? as usize
         },
         5 => {
             var_bytes = vec![0u8; 5];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
+            buf.read_exact(var_bytes.as_mut())?;
             let tmp = var_bytes.clone();
             var_len = readvalue::read_u40(&tmp) as usize;
         }
         6 => {
             var_bytes = vec![0u8; 6];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
+            buf.read_exact(var_bytes.as_mut())?;
             let tmp = var_bytes.clone();
             var_len= readvalue::read_u48(&tmp) as usize;
         }
         7 => {
             var_bytes = vec![0u8; 7];
-            buf.read_exact(var_bytes.as_mut()).unwrap();
+            buf.read_exact(var_bytes.as_mut())?;
             let tmp = var_bytes.clone();
             var_len = readvalue::read_u56(&tmp) as usize;
         }
         8 => {
             var_bytes = vec![0u8; 8];
+            buf.read_exact(var_bytes.as_mut())?;
             let tmp = var_bytes.clone();
-            buf.read_exact(var_bytes.as_mut()).unwrap();
             var_len = readvalue::read_u64(&tmp) as usize;
         }
-        _ => {}
+        v => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid length bytes: {}", v)))
     }
-    (var_bytes, var_len)
+    Ok((var_bytes, var_len))
 }
 
 
@@ -0,0 +1,432 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/25
+*/
+
+use structopt::StructOpt;
+use std::sync::{Arc, Mutex};
+use std::io::{Seek, SeekFrom};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use chrono::prelude::*;
+use serde::Serialize;
+use crate::Config;
+use crate::meta::{BinlogPos, SchemaCache};
+use crate::binlog::readevent::{BinlogEvent, Tell};
+use crate::binlog::readbinlog::{Traction, TractionValue, RowsSql};
+
+///
+/// 离线解析binlog的子命令，不需要server端，mysql宕机时可以直接查看binlog
+///
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    #[structopt(name = "events", about = "输出binlog中的event")]
+    Events(BinlogArgs),
+
+    #[structopt(name = "sql", about = "输出binlog对应的sql")]
+    Sql(BinlogArgs),
+
+    #[structopt(name = "rollback", about = "输出回滚sql, 事务顺序反过来")]
+    Rollback(BinlogArgs),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BinlogArgs {
+    #[structopt(help = "binlog文件, 遇到rotate event时继续解析同目录下的下一个文件")]
+    pub file: String,
+
+    #[structopt(long = "start-position", help = "开始解析的位置, 需为event的起始位置, 默认为4")]
+    pub start_position: Option<u64>,
+
+    #[structopt(long = "stop-position", help = "在该位置之前结束解析, 默认为文件末尾")]
+    pub stop_position: Option<u64>,
+
+    #[structopt(long = "gtids", help = "只输出gtid在该集合中的事务, 如uuid:1-10:15,uuid2:3")]
+    pub gtids: Option<String>,
+
+    #[structopt(long = "start-datetime", help = "只输出该时间之后的事务, 格式为2019-11-25 10:00:00")]
+    pub start_datetime: Option<String>,
+
+    #[structopt(long = "stop-datetime", help = "只输出该时间之前的事务, 格式为2019-11-25 10:00:00")]
+    pub stop_datetime: Option<String>,
+
+    #[structopt(long = "database", help = "只输出该库的row event")]
+    pub database: Option<String>,
+
+    #[structopt(long = "table", help = "只输出该表的row event")]
+    pub table: Option<String>,
+
+    #[structopt(long = "event-type", help = "只输出该类型的row event, insert/update/delete, 多个使用逗号分隔")]
+    pub event_type: Option<String>,
+
+    #[structopt(long = "output", default_value = "text", possible_values = &["text", "json"], help = "输出格式")]
+    pub output: String,
+
+    #[structopt(long = "host", help = "获取表结构的mysql地址, ip:port, 不配置时只使用表结构缓存及binlog中的字段信息")]
+    pub host: Option<String>,
+
+    #[structopt(long = "user", help = "mysql用户名")]
+    pub user: Option<String>,
+
+    #[structopt(long = "password", help = "mysql密码")]
+    pub password: Option<String>,
+
+    #[structopt(long = "socket", help = "本地mysql的unix socket文件")]
+    pub socket: Option<String>,
+
+    #[structopt(long = "schema-cache", help = "表结构缓存文件, 默认为当前目录下的schema_cache.json, 只读取不修改")]
+    pub schema_cache: Option<String>,
+}
+
+///
+/// 事务及row event的过滤条件
+///
+struct Filter {
+    gtids: Option<HashMap<String, Vec<(u64, u64)>>>,
+    start_time: Option<u32>,
+    stop_time: Option<u32>,
+    database: Option<String>,
+    table: Option<String>,
+    event_type: Vec<String>,
+}
+
+impl Filter {
+    fn new(args: &BinlogArgs) -> Result<Filter, Box<dyn Error>> {
+        let mut gtids = None;
+        match &args.gtids {
+            None => {},
+            Some(t) => gtids = Some(crate::meta::parse_gtid_set(t)?),
+        }
+        let mut event_type = vec![];
+        match &args.event_type {
+            None => {},
+            Some(t) => {
+                for v in t.split(",") {
+                    match v.trim().to_lowercase().as_str() {
+                        "" => {}
+                        "insert" | "update" | "delete" => event_type.push(v.trim().to_lowercase()),
+                        _ => return Err(format!("unknown event type: {}", v).into())
+                    }
+                }
+            }
+        }
+        Ok(Filter{
+            gtids,
+            start_time: parse_datetime(&args.start_datetime)?,
+            stop_time: parse_datetime(&args.stop_datetime)?,
+            database: args.database.clone(),
+            table: args.table.clone(),
+            event_type
+        })
+    }
+
+    ///
+    /// 事务级别的过滤: gtid及时间
+    ///
+    fn match_traction(&self, traction: &TractionValue) -> bool {
+        if let Some(set) = &self.gtids {
+            let matched = match traction_gtid(traction) {
+                Some((sid, gno)) => match set.get(&sid) {
+                    Some(ranges) => ranges.iter().any(|(start, end)| gno >= *start && gno <= *end),
+                    None => false
+                },
+                None => false
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(t) = self.start_time {
+            if traction.timestamp < t {
+                return false;
+            }
+        }
+        if let Some(t) = self.stop_time {
+            if traction.timestamp >= t {
+                return false;
+            }
+        }
+        true
+    }
+
+    ///
+    /// row event级别的过滤: 库、表及类型
+    ///
+    fn match_rows(&self, rows: &RowStatements) -> bool {
+        let (db, tbl) = (&rows.database, &rows.table);
+        if let Some(d) = &self.database {
+            if d != db {
+                return false;
+            }
+        }
+        if let Some(t) = &self.table {
+            if t != tbl {
+                return false;
+            }
+        }
+        self.event_type.len() == 0 || self.event_type.contains(&event_type_name(&rows.type_code).to_string())
+    }
+
+    fn has_rows_filter(&self) -> bool {
+        self.database.is_some() || self.table.is_some() || self.event_type.len() > 0
+    }
+}
+
+fn parse_datetime(value: &Option<String>) -> Result<Option<u32>, Box<dyn Error>> {
+    match value {
+        None => Ok(None),
+        Some(t) => {
+            let dt = Local.datetime_from_str(t, "%Y-%m-%d %H:%M:%S")
+                .map_err(|e| format!("invalid datetime {}: {}", t, e))?;
+            Ok(Some(dt.timestamp() as u32))
+        }
+    }
+}
+
+fn traction_gtid(traction: &TractionValue) -> Option<(String, u64)> {
+    for event in traction.event.iter() {
+        if let Traction::GtidEvent(g) = event {
+            return Some((g.gtid.to_string(), g.gno_id));
+        }
+    }
+    None
+}
+
+fn event_type_name(code: &BinlogEvent) -> &'static str {
+    match code {
+        BinlogEvent::WriteEvent => "insert",
+        BinlogEvent::UpdateEvent => "update",
+        BinlogEvent::DeleteEvent => "delete",
+        _ => "unknown"
+    }
+}
+
+///
+/// 一个row event对应的sql，cur为正向语句，rollback为回滚语句
+///
+struct RowStatements<'a> {
+    database: String,
+    table: String,
+    type_code: BinlogEvent,
+    cur: &'a [String],
+    rollback: &'a [String],
+}
+
+///
+/// 按event拆分事务中的sql，每个row event生成的语句数记录在RowEventStatic中
+///     rollback_sql中后面的event在前，before image不完整时没有回滚语句
+///
+fn split_statements(traction: &TractionValue) -> Vec<RowStatements<'_>> {
+    let mut rows = vec![];
+    let mut database = String::from("");
+    let mut table = String::from("");
+    let mut cur_idx = 0;
+    let mut rollback_end = traction.rollback_sql.len();
+    for event in traction.event.iter() {
+        match event {
+            Traction::TableMapEvent(m) => {
+                database = m.database_name.clone();
+                table = m.table_name.clone();
+            }
+            Traction::RowEventStatic{type_code, count} => {
+                let cur_end = (cur_idx + count).min(traction.cur_sql.len());
+                let cur = &traction.cur_sql[cur_idx..cur_end];
+                cur_idx = cur_end;
+                let mut rollback: &[String] = &[];
+                if !traction.rollback_incomplete {
+                    let start = rollback_end.saturating_sub(*count);
+                    rollback = &traction.rollback_sql[start..rollback_end];
+                    rollback_end = start;
+                }
+                rows.push(RowStatements{ database: database.clone(), table: table.clone(), type_code: type_code.clone(), cur, rollback });
+            }
+            _ => {}
+        }
+    }
+    rows
+}
+
+#[derive(Serialize)]
+struct SqlRecord<'a> {
+    gtid: String,
+    timestamp: u32,
+    server_id: u32,
+    position: u32,
+    sql: Vec<&'a String>,
+}
+
+fn format_time(timestamp: u32) -> String {
+    Local.timestamp(timestamp as i64, 0).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn sql_line(sql: &String) -> String {
+    format!("{};", sql.trim_end().trim_end_matches(";"))
+}
+
+fn traction_header(traction: &TractionValue) -> String {
+    let gtid = match traction_gtid(traction) {
+        Some((sid, gno)) => format!("{}:{}", sid, gno),
+        None => String::from("")
+    };
+    format!("# end_log_pos: {}  gtid: {}  time: {}  server_id: {}", traction.position, gtid, format_time(traction.timestamp), traction.server_id)
+}
+
+fn event_line(event: &Traction) -> String {
+    match event {
+        Traction::GtidEvent(g) => format!("Gtid {}:{} last_committed={} sequence_number={}", g.gtid, g.gno_id, g.last_committed, g.sequence_number),
+        Traction::QueryEvent(q) => format!("Query {}: {}", q.database, q.command),
+        Traction::TableMapEvent(m) => format!("Table_map {}.{} columns={}", m.database_name, m.table_name, m.column_info.len()),
+        Traction::RowEventStatic{type_code, count} => format!("{:?} rows={}", type_code, count),
+        Traction::RowEvent(type_code, v) => format!("{:?} rows={}", type_code, v.rows.len()),
+        Traction::XidEvent(x) => format!("Xid {}", x.xid),
+        Traction::RotateLogEvent(r) => format!("Rotate {}", r.binlog_file),
+        Traction::Unknown => String::from("Unknown"),
+    }
+}
+
+fn output(cmd: &Command, args: &BinlogArgs, filter: &Filter, rows_sql: &RowsSql) -> Result<(), Box<dyn Error>> {
+    let json = args.output == "json";
+    let tractions: Vec<&TractionValue> = match cmd {
+        Command::Rollback(_) => rows_sql.sqls.iter().rev().collect(),
+        _ => rows_sql.sqls.iter().collect()
+    };
+    //时间类型以utc输出，回放前需要先设置会话时区，只在第一个事务前输出一次
+    let mut time_zone = false;
+    for traction in tractions {
+        if !filter.match_traction(traction) {
+            continue;
+        }
+        let rows: Vec<RowStatements> = split_statements(traction).into_iter().filter(|r| filter.match_rows(r)).collect();
+        if filter.has_rows_filter() && rows.len() == 0 {
+            continue;
+        }
+        match cmd {
+            Command::Events(_) => {
+                if json {
+                    println!("{}", serde_json::to_string(traction)?);
+                } else {
+                    println!("{}", traction_header(traction));
+                    for event in traction.event.iter() {
+                        println!("{}", event_line(event));
+                    }
+                }
+            }
+            Command::Sql(_) | Command::Rollback(_) => {
+                let rollback = match cmd {
+                    Command::Rollback(_) => true,
+                    _ => false
+                };
+                if rollback && traction.rollback_incomplete {
+                    println!("# {} binlog row image is not full, can not rollback", traction_header(traction));
+                    continue;
+                }
+                //回滚时同一事务中后面的event先回滚
+                let sqls: Vec<&String> = match rollback {
+                    true => rows.iter().rev().flat_map(|r| r.rollback.iter()).collect(),
+                    false => rows.iter().flat_map(|r| r.cur.iter()).collect()
+                };
+                if sqls.len() == 0 {
+                    continue;
+                }
+                if json {
+                    let record = SqlRecord{
+                        gtid: traction_gtid(traction).map(|(sid, gno)| format!("{}:{}", sid, gno)).unwrap_or_default(),
+                        timestamp: traction.timestamp,
+                        server_id: traction.server_id,
+                        position: traction.position,
+                        sql: sqls
+                    };
+                    println!("{}", serde_json::to_string(&record)?);
+                } else {
+                    if !time_zone {
+                        println!("{}", sql_line(&String::from(crate::binlog::getsql::SESSION_TIME_ZONE)));
+                        time_zone = true;
+                    }
+                    println!("{}", traction_header(traction));
+                    println!("BEGIN;");
+                    for sql in sqls {
+                        println!("{}", sql_line(sql));
+                    }
+                    println!("COMMIT;");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// 不连接server端时使用的配置，只有获取表结构相关的参数
+///
+fn offline_config(args: &BinlogArgs, binlogdir: String) -> Config {
+    Config{
+        slowlog: false,
+        audit: false,
+        cdc: false,
+        monitor: false,
+        port: 0,
+        bind: String::from(""),
+        max_packet_size: crate::MAX_PACKET_SIZE,
+        host_info: args.host.clone().unwrap_or_default(),
+        user_name: args.user.clone().unwrap_or_default(),
+        password: args.password.clone().unwrap_or_default(),
+        database: String::from(""),
+        program_name: String::from("mymha_client"),
+        repl_user: String::from(""),
+        repl_passwd: String::from(""),
+        binlogdir,
+        auditdir: String::from(""),
+        cdcdir: String::from(""),
+        cdc_output: String::from(""),
+        flashbackdir: String::from(""),
+        dump_server_id: 65535,
+        schema_cache: args.schema_cache.clone().unwrap_or(String::from("schema_cache.json")),
+        secret: String::from(""),
+        allow_hosts: vec![],
+        socket: args.socket.clone().unwrap_or_default(),
+        ssl: false,
+        ssl_ca: String::from(""),
+        ssl_cert: String::from(""),
+        ssl_key: String::from(""),
+        ssl_skip_hostname: false
+    }
+}
+
+///
+/// 执行子命令，解析出错时已经解析的事务照常输出，错误返回给调用方
+///
+pub fn run(cmd: Command) -> Result<(), Box<dyn Error>> {
+    let args = match &cmd {
+        Command::Events(a) | Command::Sql(a) | Command::Rollback(a) => a
+    };
+    let filter = Filter::new(args)?;
+    let path = Path::new(&args.file);
+    let binlog = match path.file_name() {
+        Some(v) => v.to_string_lossy().to_string(),
+        None => return Err(format!("invalid binlog file: {}", args.file).into())
+    };
+    let binlogdir = match path.parent() {
+        Some(v) if v.as_os_str().len() > 0 => v.to_string_lossy().to_string(),
+        _ => String::from(".")
+    };
+    let conf = Arc::new(offline_config(args, binlogdir));
+    //只查看binlog，不修改本地的表结构缓存
+    let schema = Arc::new(Mutex::new(SchemaCache::new_read_only(&conf.schema_cache)));
+
+    let mut reader = crate::binlog::open_file(&args.file)?;
+    reader.seek(SeekFrom::End(0))?;
+    let mut end_pos = reader.tell()?;
+    if let Some(v) = args.stop_position {
+        end_pos = end_pos.min(v);
+    }
+    let fde = crate::binlog::readbinlog::read_format_description(&mut reader)?;
+    reader.seek(SeekFrom::Start(args.start_position.unwrap_or(4)))?;
+    let rows_sql = crate::binlog::readbinlog::parse_with_format(&conf, &schema, &mut reader, end_pos, true,
+                                                               &BinlogPos::new(&binlog, 0), fde)?;
+    output(&cmd, args, &filter, &rows_sql)?;
+    if rows_sql.error.len() > 0 {
+        return Err(rows_sql.error.into());
+    }
+    Ok(())
+}
@@ -12,6 +12,8 @@ pub mod response;
 pub mod pack;
 pub mod scramble;
 pub mod command;
+pub mod stream;
+pub mod replication;
 
 pub fn get_network_packet(tcp: &mut TcpStream) -> Result<Vec<u8>,Box<dyn Error>> {
     let mut header = [0u8; 8];
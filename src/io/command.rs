@@ -3,31 +3,50 @@
 @datetime: 2019/9/24
 */
 
-use std::net::TcpStream;
+use crate::io::stream::MysqlStream;
 use crate::io::response;
 use crate::io::socketio;
 use crate::io::pack;
 use crate::readvalue;
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
+use crate::meta::ColumnTypeDict;
 
 trait ColInit {
     fn new(buf: &Vec<u8>) -> Self;
 }
 
 //查询数据时mysql返回的字段元数据
-#[derive(Debug)]
-struct MetaColumn{
-    catalog: String,
-    schema: String,
-    table: String,
-    org_table: String,
-    name: String,
-    org_name: String,
-    character_set: u16,
-    column_length: u32,
-    column_type: u8,
-    flag: u16
+#[derive(Debug, Clone)]
+pub struct MetaColumn{
+    pub catalog: String,
+    pub schema: String,
+    pub table: String,
+    pub org_table: String,
+    pub name: String,
+    pub org_name: String,
+    pub character_set: u16,
+    pub column_length: u32,
+    pub column_type: ColumnTypeDict,
+    pub flag: u16,
+    pub decimals: u8
+}
+
+impl MetaColumn {
+    pub fn is_not_null(&self) -> bool {
+        self.flag & 1 > 0
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        self.flag & 32 > 0
+    }
+
+    ///
+    /// 字符集为binary(63)
+    pub fn is_binary(&self) -> bool {
+        self.character_set == 63
+    }
 }
 
 impl ColInit for MetaColumn{
@@ -71,10 +90,13 @@ impl ColInit for MetaColumn{
         let column_length = readvalue::read_u32(&buf[offset..offset+4]);
         offset += 4;
 
-        let column_type = buf[offset];
+        let column_type = ColumnTypeDict::from_type_code(&buf[offset]);
         offset +=1;
 
         let flag = readvalue::read_u16(&buf[offset..offset+2]);
+        offset += 2;
+
+        let decimals = buf[offset];
 
         MetaColumn{
             catalog,
@@ -86,13 +108,148 @@ impl ColInit for MetaColumn{
             character_set,
             column_length,
             column_type,
-            flag
+            flag,
+            decimals
         }
     }
 }
 
 
-pub fn execute(conn: &mut TcpStream,sql: &String) -> Result<Vec<HashMap<String,String>>, Box<dyn Error>>{
+///
+/// 查询返回的一行数据，按字段顺序保存，NULL为None
+///
+#[derive(Debug, Clone)]
+pub struct Row {
+    columns: Arc<Vec<MetaColumn>>,
+    values: Vec<Option<String>>,
+}
+
+impl Row {
+    pub fn columns(&self) -> &Vec<MetaColumn> {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+
+    ///
+    /// 根据下标获取值，NULL或下标不存在时返回None
+    pub fn get_by_index(&self, idx: usize) -> Option<&String> {
+        match self.values.get(idx) {
+            Some(v) => v.as_ref(),
+            None => None
+        }
+    }
+
+    ///
+    /// 根据字段名获取值，NULL或字段不存在时返回None
+    pub fn get(&self, name: &str) -> Option<&String> {
+        match self.index_of(name) {
+            Some(idx) => self.get_by_index(idx),
+            None => None
+        }
+    }
+
+    pub fn is_null(&self, name: &str) -> bool {
+        match self.index_of(name) {
+            Some(idx) => self.values[idx].is_none(),
+            None => false
+        }
+    }
+
+    ///
+    /// 根据字段名获取值并转换为对应类型，字段不存在时返回错误
+    pub fn take<T: FromValue>(&self, name: &str) -> Result<T, Box<dyn Error>> {
+        match self.index_of(name) {
+            Some(idx) => {
+                match T::from_value(self.values[idx].as_ref()) {
+                    Ok(v) => Ok(v),
+                    Err(e) => Err(format!("column {}: {}", name, e.to_string()).into())
+                }
+            }
+            None => Err(format!("column {} does not exist", name).into())
+        }
+    }
+
+    pub fn to_map(&self) -> HashMap<String, Option<String>> {
+        let mut map = HashMap::new();
+        for (idx, col) in self.columns.iter().enumerate() {
+            map.insert(col.name.clone(), self.values[idx].clone());
+        }
+        map
+    }
+}
+
+///
+/// 从文本协议的值转换为rust类型，非Option类型遇到NULL时返回错误
+///
+pub trait FromValue: Sized {
+    fn from_value(value: Option<&String>) -> Result<Self, Box<dyn Error>>;
+}
+
+impl FromValue for String {
+    fn from_value(value: Option<&String>) -> Result<String, Box<dyn Error>> {
+        match value {
+            Some(v) => Ok(v.clone()),
+            None => Err(String::from("unexpected NULL value").into())
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Option<&String>) -> Result<bool, Box<dyn Error>> {
+        match value {
+            Some(v) => {
+                match v.to_uppercase().as_ref() {
+                    "1" | "ON" | "YES" | "TRUE" => Ok(true),
+                    "0" | "OFF" | "NO" | "FALSE" => Ok(false),
+                    _ => Err(format!("invalid bool value: {}", v).into())
+                }
+            }
+            None => Err(String::from("unexpected NULL value").into())
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Option<&String>) -> Result<Option<T>, Box<dyn Error>> {
+        match value {
+            Some(_) => Ok(Some(T::from_value(value)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+macro_rules! from_value_parse {
+    ($($t:ty),*) => {
+        $(
+            impl FromValue for $t {
+                fn from_value(value: Option<&String>) -> Result<$t, Box<dyn Error>> {
+                    match value {
+                        Some(v) => Ok(v.parse::<$t>()?),
+                        None => Err(String::from("unexpected NULL value").into())
+                    }
+                }
+            }
+        )*
+    };
+}
+
+from_value_parse!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+///
+/// 把一行数据转换为结构体
+///
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, Box<dyn Error>>;
+}
+
+pub fn execute(conn: &mut MysqlStream,sql: &String) -> Result<Vec<Row>, Box<dyn Error>>{
     let pack = commquery(sql);
     socketio::write_value(conn,&pack)?;
 
@@ -100,7 +257,19 @@ pub fn execute(conn: &mut TcpStream,sql: &String) -> Result<Vec<HashMap<String,S
     return Ok(a);
 }
 
-pub fn execute_update(conn: &mut TcpStream,sql: &String) -> Result<(), Box<dyn Error>>{
+///
+/// 执行查询并把每行数据转换为T
+///
+pub fn query<T: FromRow>(conn: &mut MysqlStream, sql: &String) -> Result<Vec<T>, Box<dyn Error>> {
+    let rows = execute(conn, sql)?;
+    let mut values = vec![];
+    for row in rows.iter() {
+        values.push(T::from_row(row)?);
+    }
+    Ok(values)
+}
+
+pub fn execute_update(conn: &mut MysqlStream,sql: &String) -> Result<(), Box<dyn Error>>{
     let pack = commquery(sql);
     socketio::write_value(conn,&pack)?;
 
@@ -114,7 +283,7 @@ pub fn execute_update(conn: &mut TcpStream,sql: &String) -> Result<(), Box<dyn E
 
 }
 
-pub fn close(conn: &mut TcpStream) {
+pub fn close(conn: &mut MysqlStream) {
     let mut pack = vec![];
     let mut payload = vec![];
     payload.push(0x01);
@@ -138,7 +307,7 @@ fn commquery(sql: &String) -> Vec<u8>{
     return pack;
 }
 
-fn unpack_text_packet(conn: &mut TcpStream) -> Result<Vec<HashMap<String,String>>,Box<dyn Error>> {
+fn unpack_text_packet(conn: &mut MysqlStream) -> Result<Vec<Row>,Box<dyn Error>> {
     let (buf,_) = socketio::get_packet_from_stream(conn)?;
 
     if pack::check_pack(&buf){
@@ -151,6 +320,7 @@ fn unpack_text_packet(conn: &mut TcpStream) -> Result<Vec<HashMap<String,String>
             let column = MetaColumn::new(&buf);
             column_info.push(column);
         }
+        let column_info = Arc::new(column_info);
         //开始获取返回数据
         loop {
             let (buf,header) = socketio::get_packet_from_stream(conn)?;
@@ -163,7 +333,7 @@ fn unpack_text_packet(conn: &mut TcpStream) -> Result<Vec<HashMap<String,String>
                 break;
             }
             let values = unpack_text_value(&buf, &column_info);
-            values_info.push(values);
+            values_info.push(Row{ columns: Arc::clone(&column_info), values });
         }
         Ok(values_info)
     }else {
@@ -173,47 +343,375 @@ fn unpack_text_packet(conn: &mut TcpStream) -> Result<Vec<HashMap<String,String>
     }
 }
 
-fn unpack_text_value(buf: &Vec<u8>,column_info: &Vec<MetaColumn>) -> HashMap<String,String> {
+fn unpack_text_value(buf: &Vec<u8>,column_info: &Vec<MetaColumn>) -> Vec<Option<String>> {
     //解析每行数据
-    let mut values_info = HashMap::new();
+    let mut values_info = vec![];
     let mut offset = 0;
-    for cl in column_info.iter(){
-        let mut value = String::from("");
-        let cl_name = cl.name.clone();
+    for _ in column_info.iter(){
         let mut var_len = buf[offset] as usize;
         offset += 1;
+        if var_len == 0xfb {
+            values_info.push(None);
+            continue;
+        }
         if var_len == 0xfc {
             var_len = readvalue::read_u16(&buf[offset..offset + 2]) as usize;
             offset += 2;
-            value = readvalue::read_string_value(&buf[offset..offset + var_len]);
-            offset += var_len;
         }
         else if var_len == 0xfd {
             var_len = readvalue::read_u24(&buf[offset..offset + 3]) as usize;
             offset += 3;
-            value = readvalue::read_string_value(&buf[offset..offset + var_len]);
-            offset += var_len;
         }
         else if var_len == 0xfe {
             var_len = readvalue::read_u64(&buf[offset..offset + 8]) as usize;
             offset += 8;
-            value = readvalue::read_string_value(&buf[offset..offset + var_len]);
-            offset += var_len;
         }
-        else if var_len == 0xfb {
-            value = String::from("");
+        values_info.push(Some(readvalue::read_string_value(&buf[offset..offset + var_len])));
+        offset += var_len;
+    }
+
+    return values_info;
+}
+
+///
+/// 预处理语句的参数
+///
+#[derive(Debug, Clone)]
+pub enum Param {
+    Null,
+    Int(i64),
+    UInt(u64),
+    Double(f64),
+    Bytes(Vec<u8>),
+}
+
+///
+/// COM_STMT_PREPARE返回的预处理语句信息
+///
+#[derive(Debug)]
+pub struct Statement {
+    pub id: u32,
+    pub params: u16,
+    pub columns: u16,
+}
+
+fn write_lenenc_int(num: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    if num < 251 {
+        buf.push(num as u8);
+    } else if num < 1 << 16 {
+        buf.push(0xfc);
+        buf.extend(readvalue::write_u16(num as u16));
+    } else if num < 1 << 24 {
+        buf.push(0xfd);
+        buf.extend(readvalue::write_u24(num as u32));
+    } else {
+        buf.push(0xfe);
+        buf.extend(readvalue::write_u64(num));
+    }
+    buf
+}
+
+fn read_lenenc_int(buf: &[u8], offset: &mut usize) -> u64 {
+    let first = buf[*offset];
+    *offset += 1;
+    match first {
+        0xfc => {
+            let v = readvalue::read_u16(&buf[*offset..*offset + 2]) as u64;
+            *offset += 2;
+            v
+        }
+        0xfd => {
+            let v = readvalue::read_u24(&buf[*offset..*offset + 3]) as u64;
+            *offset += 3;
+            v
         }
-        else {
-            value = readvalue::read_string_value(&buf[offset..offset + var_len]);
-            offset += var_len;
+        0xfe => {
+            let v = readvalue::read_u64(&buf[*offset..*offset + 8]);
+            *offset += 8;
+            v
         }
+        _ => first as u64
+    }
+}
 
+fn err_packet(buf: &Vec<u8>) -> Box<dyn Error> {
+    let err = readvalue::read_string_value(&buf[3..]);
+    err.into()
+}
 
-        values_info.insert(cl_name,value);
+fn command_packet(code: u8, value: &[u8]) -> Vec<u8> {
+    let mut pack = vec![];
+    let mut payload = vec![];
+    payload.push(code);
+    payload.extend(value);
+    pack.extend(response::pack_header(&payload,0));
+    pack.extend(payload);
+    pack
+}
+
+///
+/// COM_STMT_PREPARE
+///     返回的参数及字段定义包只做读取，字段信息以执行时返回的为准
+///
+pub fn prepare(conn: &mut MysqlStream, sql: &String) -> Result<Statement, Box<dyn Error>> {
+    socketio::write_value(conn, &command_packet(0x16, sql.as_bytes()))?;
+    let (buf,_) = socketio::get_packet_from_stream(conn)?;
+    if buf[0] == 0xff {
+        return Err(err_packet(&buf));
     }
+    let stmt = Statement{
+        id: readvalue::read_u32(&buf[1..5]),
+        columns: readvalue::read_u16(&buf[5..7]),
+        params: readvalue::read_u16(&buf[7..9]),
+    };
+    //连接使用了CLIENT_DEPRECATE_EOF，定义包之后没有eof包
+    for _ in 0..(stmt.params as usize + stmt.columns as usize) {
+        socketio::get_packet_from_stream(conn)?;
+    }
+    Ok(stmt)
+}
 
-    return values_info;
+///
+/// 执行预处理语句并读取返回的结果集
+///
+pub fn execute_stmt(conn: &mut MysqlStream, stmt: &Statement, params: &Vec<Param>) -> Result<Vec<Row>, Box<dyn Error>> {
+    send_execute(conn, stmt, params)?;
+    unpack_binary_packet(conn)
 }
 
+///
+/// COM_STMT_EXECUTE
+///     参数类型: NULL(6)、LONGLONG(8, 无符号时flag为0x80)、DOUBLE(5)、BLOB(0xfc)
+///     字节参数使用BLOB类型，服务端按binary字符集处理，不按连接字符集转换，字符类型需要在sql中使用CONVERT(? USING charset)
+///
+fn send_execute(conn: &mut MysqlStream, stmt: &Statement, params: &Vec<Param>) -> Result<(), Box<dyn Error>> {
+    if params.len() != stmt.params as usize {
+        return Err(format!("statement needs {} params, got {}", stmt.params, params.len()).into());
+    }
+    let mut value = vec![];
+    value.extend(readvalue::write_u32(stmt.id));
+    value.push(0);                                  //flags: CURSOR_TYPE_NO_CURSOR
+    value.extend(readvalue::write_u32(1));          //iteration_count
+    if params.len() > 0 {
+        let mut null_bitmap = vec![0u8; (params.len() + 7) / 8];
+        let mut types: Vec<u8> = vec![];
+        let mut values: Vec<u8> = vec![];
+        for (idx, param) in params.iter().enumerate() {
+            match param {
+                Param::Null => {
+                    null_bitmap[idx / 8] |= 1 << (idx % 8);
+                    types.extend(&[6, 0]);
+                }
+                Param::Int(v) => {
+                    types.extend(&[8, 0]);
+                    values.extend(&v.to_le_bytes());
+                }
+                Param::UInt(v) => {
+                    types.extend(&[8, 0x80]);
+                    values.extend(&v.to_le_bytes());
+                }
+                Param::Double(v) => {
+                    types.extend(&[5, 0]);
+                    values.extend(&v.to_le_bytes());
+                }
+                Param::Bytes(v) => {
+                    types.extend(&[0xfc, 0]);
+                    values.extend(write_lenenc_int(v.len() as u64));
+                    values.extend(v);
+                }
+            }
+        }
+        value.extend(null_bitmap);
+        value.push(1);                              //new_params_bound_flag
+        value.extend(types);
+        value.extend(values);
+    }
+    socketio::write_value(conn, &command_packet(0x17, &value))?;
+    Ok(())
+}
+
+///
+/// COM_STMT_CLOSE, 服务端不返回数据
+///
+pub fn close_stmt(conn: &mut MysqlStream, stmt: &Statement) -> Result<(), Box<dyn Error>> {
+    socketio::write_value(conn, &command_packet(0x19, &readvalue::write_u32(stmt.id)))?;
+    Ok(())
+}
 
+///
+/// 预处理、执行并关闭语句，用于只执行一次的参数化语句
+///
+pub fn execute_params(conn: &mut MysqlStream, sql: &String, params: &Vec<Param>) -> Result<Vec<Row>, Box<dyn Error>> {
+    let stmt = prepare(conn, sql)?;
+    let result = execute_stmt(conn, &stmt, params);
+    close_stmt(conn, &stmt)?;
+    result
+}
 
+///
+/// 预处理、执行并关闭insert/update/delete语句，返回ok包中的affected rows
+///     连接带有CLIENT_FOUND_ROWS，update返回的是匹配的行数
+///
+pub fn execute_params_affected(conn: &mut MysqlStream, sql: &String, params: &Vec<Param>) -> Result<u64, Box<dyn Error>> {
+    let stmt = prepare(conn, sql)?;
+    let result = send_execute(conn, &stmt, params).and_then(|_| {
+        let (buf, _) = socketio::get_packet_from_stream(conn)?;
+        match buf[0] {
+            0x00 => {
+                let mut offset = 1;
+                Ok(read_lenenc_int(&buf, &mut offset))
+            }
+            0xff => Err(err_packet(&buf)),
+            _ => Err(format!("unexpected result set: {}", sql).into())
+        }
+    });
+    close_stmt(conn, &stmt)?;
+    result
+}
+
+///
+/// 使用预处理语句执行查询并把每行数据转换为T
+///
+pub fn query_params<T: FromRow>(conn: &mut MysqlStream, sql: &String, params: &Vec<Param>) -> Result<Vec<T>, Box<dyn Error>> {
+    let rows = execute_params(conn, sql, params)?;
+    let mut values = vec![];
+    for row in rows.iter() {
+        values.push(T::from_row(row)?);
+    }
+    Ok(values)
+}
+
+fn unpack_binary_packet(conn: &mut MysqlStream) -> Result<Vec<Row>, Box<dyn Error>> {
+    let (buf,_) = socketio::get_packet_from_stream(conn)?;
+    match buf[0] {
+        0x00 => return Ok(vec![]),
+        0xff => return Err(err_packet(&buf)),
+        _ => {}
+    }
+    let mut offset = 0;
+    let column_count = read_lenenc_int(&buf, &mut offset);
+    let mut column_info = vec![];
+    for _ in 0..column_count {
+        let (buf,_) = socketio::get_packet_from_stream(conn)?;
+        column_info.push(MetaColumn::new(&buf));
+    }
+    let column_info = Arc::new(column_info);
+    let mut values_info = vec![];
+    loop {
+        let (buf,_) = socketio::get_packet_from_stream(conn)?;
+        if buf[0] == 0xfe {
+            break;
+        } else if buf[0] == 0xff {
+            return Err(err_packet(&buf));
+        }
+        let values = unpack_binary_value(&buf, &column_info);
+        values_info.push(Row{ columns: Arc::clone(&column_info), values });
+    }
+    Ok(values_info)
+}
+
+///
+/// 解析binary协议的行数据，转换为与文本协议相同的字符串格式
+///     行数据以0x00开头，null bitmap从第2位开始
+///
+fn unpack_binary_value(buf: &Vec<u8>, column_info: &Vec<MetaColumn>) -> Vec<Option<String>> {
+    let mut values_info = vec![];
+    let null_bitmap_len = (column_info.len() + 7 + 2) / 8;
+    let null_bitmap = &buf[1..1 + null_bitmap_len];
+    let mut offset = 1 + null_bitmap_len;
+    for (idx, col) in column_info.iter().enumerate() {
+        let bit = idx + 2;
+        if null_bitmap[bit / 8] & (1 << (bit % 8)) > 0 {
+            values_info.push(None);
+            continue;
+        }
+        let unsigned = col.is_unsigned();
+        let value = match col.column_type {
+            ColumnTypeDict::MysqlTypeTiny => {
+                offset += 1;
+                if unsigned { buf[offset - 1].to_string() } else { (buf[offset - 1] as i8).to_string() }
+            }
+            ColumnTypeDict::MysqlTypeShort |
+            ColumnTypeDict::MysqlTypeYear => {
+                offset += 2;
+                let v = &buf[offset - 2..offset];
+                if unsigned { readvalue::read_u16(v).to_string() } else { readvalue::read_i16(v).to_string() }
+            }
+            ColumnTypeDict::MysqlTypeLong |
+            ColumnTypeDict::MysqlTypeInt24 => {
+                offset += 4;
+                let v = &buf[offset - 4..offset];
+                if unsigned { readvalue::read_u32(v).to_string() } else { readvalue::read_i32(v).to_string() }
+            }
+            ColumnTypeDict::MysqlTypeLonglong => {
+                offset += 8;
+                let v = &buf[offset - 8..offset];
+                if unsigned { readvalue::read_u64(v).to_string() } else { readvalue::read_i64(v).to_string() }
+            }
+            ColumnTypeDict::MysqlTypeFloat => {
+                offset += 4;
+                readvalue::read_f32(&buf[offset - 4..offset]).to_string()
+            }
+            ColumnTypeDict::MysqlTypeDouble => {
+                offset += 8;
+                readvalue::read_f64(&buf[offset - 8..offset]).to_string()
+            }
+            ColumnTypeDict::MysqlTypeDate |
+            ColumnTypeDict::MysqlTypeDatetime |
+            ColumnTypeDict::MysqlTypeTimestamp => {
+                let len = buf[offset] as usize;
+                let v = &buf[offset + 1..offset + 1 + len];
+                offset += 1 + len;
+                let (mut year, mut month, mut day, mut hour, mut minute, mut second, mut micro) = (0, 0, 0, 0, 0, 0, 0);
+                if len >= 4 {
+                    year = readvalue::read_u16(&v[0..2]);
+                    month = v[2];
+                    day = v[3];
+                }
+                if len >= 7 {
+                    hour = v[4];
+                    minute = v[5];
+                    second = v[6];
+                }
+                if len >= 11 {
+                    micro = readvalue::read_u32(&v[7..11]);
+                }
+                let date = format!("{:04}-{:02}-{:02}", year, month, day);
+                match col.column_type {
+                    ColumnTypeDict::MysqlTypeDate => date,
+                    _ if micro > 0 => format!("{} {:02}:{:02}:{:02}.{:06}", date, hour, minute, second, micro),
+                    _ => format!("{} {:02}:{:02}:{:02}", date, hour, minute, second)
+                }
+            }
+            ColumnTypeDict::MysqlTypeTime => {
+                let len = buf[offset] as usize;
+                let v = &buf[offset + 1..offset + 1 + len];
+                offset += 1 + len;
+                let (mut negative, mut hours, mut minute, mut second, mut micro) = (false, 0, 0, 0, 0);
+                if len >= 8 {
+                    negative = v[0] == 1;
+                    hours = readvalue::read_u32(&v[1..5]) * 24 + v[5] as u32;
+                    minute = v[6];
+                    second = v[7];
+                }
+                if len >= 12 {
+                    micro = readvalue::read_u32(&v[8..12]);
+                }
+                let sign = if negative { "-" } else { "" };
+                if micro > 0 {
+                    format!("{}{:02}:{:02}:{:02}.{:06}", sign, hours, minute, second, micro)
+                } else {
+                    format!("{}{:02}:{:02}:{:02}", sign, hours, minute, second)
+                }
+            }
+            _ => {
+                let len = read_lenenc_int(buf, &mut offset) as usize;
+                offset += len;
+                readvalue::read_string_value(&buf[offset - len..offset])
+            }
+        };
+        values_info.push(Some(value));
+    }
+    values_info
+}
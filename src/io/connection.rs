@@ -4,7 +4,6 @@
 */
 
 use crate::{Config, readvalue};
-use std::net::TcpStream;
 use std::process;
 use crate::meta;
 use crate::io::socketio;
@@ -16,6 +15,7 @@ use std::io::{Cursor, Read, Seek};
 use byteorder::ReadBytesExt;
 use crate::io::response::pack_header;
 use crate::io::socketio::write_value;
+use crate::io::stream::{self, MysqlStream};
 use mysql_common::crypto::encrypt;
 use byteorder::LittleEndian;
 
@@ -32,7 +32,7 @@ pub enum MysqlPacketType {
 /// 记录每个mysql连接信息
 #[derive(Debug)]
 pub struct MysqlConnection {
-    pub conn: TcpStream,
+    pub conn: MysqlStream,
     pub packet_type: MysqlPacketType,
     pub status: bool,
     pub errorcode: usize,
@@ -41,7 +41,7 @@ pub struct MysqlConnection {
 
 impl MysqlConnection{
     pub fn new(conf: &Config) -> Result<MysqlConnection, Box<dyn Error>> {
-        let conn = crate::mysql::conn(&conf.host_info)?;
+        let conn = stream::connect(conf)?;
         Ok(MysqlConnection{
             conn,
             packet_type: MysqlPacketType::Unknown,
@@ -52,7 +52,7 @@ impl MysqlConnection{
     }
 
     pub fn create(&mut self, conf: &Config) -> Result<(), Box<dyn Error>>{
-        let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
+        let (packet_buf,header) = socketio::get_packet_from_stream(&mut self.conn)?;
         let mut read_buf = Cursor::new(packet_buf);
         self.get_packet_type(&mut read_buf)?;
         match self.packet_type{
@@ -60,7 +60,7 @@ impl MysqlConnection{
                 let mut packet_buf = vec![];
                 read_buf.read_to_end(&mut packet_buf)?;
                 let handshake = pack::HandshakePacket::new(&packet_buf)?;
-                self.create_conn(conf, &handshake)?;
+                self.create_conn(conf, &handshake, header.seq_id + 1)?;
             }
             MysqlPacketType::Error => {
                 self.errorcode = read_buf.read_u16::<LittleEndian>()?.into();
@@ -73,34 +73,62 @@ impl MysqlConnection{
         Ok(())
     }
 
-    pub fn create_conn(&mut self, conf: &Config, handshake: &pack::HandshakePacket) -> Result<(), Box<dyn Error>>{
+    ///
+    /// 发送ssl request包并将连接升级为tls
+    ///     ssl request包为handshake response的前32字节
+    ///
+    fn ssl_request(&mut self, conf: &Config, handshake: &pack::HandshakePacket, local_info: &response::LocalInfo, seq_id: u8) -> Result<(), Box<dyn Error>> {
+        let flags_meta = meta::FlagsMeta::new();
+        if handshake.capability_flags & (flags_meta.client_ssl as u32) == 0 {
+            return Err(String::from("ssl is enabled, but the mysql server does not support ssl").into());
+        }
+        let payload = local_info.pack_ssl_request(handshake);
+        let mut packet: Vec<u8> = vec![];
+        packet.extend(pack_header(&payload, seq_id));
+        packet.extend(payload);
+        write_value(&mut self.conn, &packet)?;
+
+        self.conn = self.conn.upgrade(conf)?;
+        Ok(())
+    }
+
+    pub fn create_conn(&mut self, conf: &Config, handshake: &pack::HandshakePacket, seq_id: u8) -> Result<(), Box<dyn Error>>{
         //根据服务端发送的hand_shake包组回报并发送
-        let handshake_response = response::LocalInfo::new(conf.program_name.borrow(), conf.database.len() as u8);
+        let mut seq_id = seq_id;
+        //unix socket连接不需要加密
+        let ssl = conf.ssl && !self.conn.is_secure();
+        let handshake_response = response::LocalInfo::new(conf.program_name.borrow(), conf.database.len() as u8, ssl);
+        if ssl {
+            self.ssl_request(conf, handshake, &handshake_response, seq_id)?;
+            seq_id += 1;
+        }
         let packet_type = meta::PackType::HandShakeResponse;
         let v = response::LocalInfo::pack_payload(
-            &handshake_response,&handshake,&packet_type,conf)?;
+            &handshake_response,&handshake,&packet_type,conf, seq_id)?;
 
         socketio::write_value(&mut self.conn, v.as_ref())?;
 
         //检查服务端回包情况
-        let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
-
+        let (mut packet_buf, mut header) = socketio::get_packet_from_stream(&mut self.conn)?;
 
-        let mut tmp_auth_data = vec![];
+        let mut tmp_auth_data = handshake.auth_plugin_data.clone();
         if packet_buf[0] == 0xFE {
             //重新验证密码
-            let (auth_data, tmp) = response::authswitchrequest(&handshake, packet_buf.as_ref(), conf);
+            let (auth_data, tmp) = response::authswitchrequest(&handshake, packet_buf.as_ref(), conf, header.seq_id + 1);
             tmp_auth_data = tmp;
             socketio::write_value(&mut self.conn, auth_data.as_ref())?;
+            let (buf, h) = socketio::get_packet_from_stream(&mut self.conn)?;
+            packet_buf = buf;
+            header = h;
         }
 
-        let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
         if pack::check_pack(&packet_buf) {
-            if packet_buf[1] == 4{
-                if self.sha2_auth( &tmp_auth_data, conf)?{
+            //0x01为caching_sha2_password的auth more data包，3为快速验证成功, 4为需要完整验证
+            if packet_buf[0] == 0x01 && packet_buf.len() > 1 && packet_buf[1] == 4{
+                if self.sha2_auth( &tmp_auth_data, conf, header.seq_id + 1)?{
                     self.status = true;
                 }
-            }else if packet_buf[1] == 3 {
+            }else if packet_buf[0] == 0x01 && packet_buf.len() > 1 && packet_buf[1] == 3 {
                 let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
                 if !pack::check_pack(&packet_buf) {
                     self.erro_pack(&packet_buf);
@@ -117,26 +145,38 @@ impl MysqlConnection{
         Ok(())
     }
 
-    pub fn sha2_auth(&mut self, auth_data: &Vec<u8>, conf: &Config) -> Result<bool, Box<dyn Error>> {
-        let (payload, seq_id) = ([0x02],5);
-        let mut packet: Vec<u8> = vec![];
-        packet.extend(pack_header(&payload, seq_id));
-        packet.extend(payload.iter());
-        write_value(&mut self.conn, &packet)?;
-
-        let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
-
-        let key = &packet_buf[1..];
+    ///
+    /// caching_sha2_password完整验证
+    ///     tls及unix socket连接直接发送明文密码
+    ///     非tls连接获取服务端公钥，使用公钥加密后发送
+    ///
+    pub fn sha2_auth(&mut self, auth_data: &Vec<u8>, conf: &Config, seq_id: u8) -> Result<bool, Box<dyn Error>> {
         let mut password = conf.password.as_bytes().to_vec();
         password.push(0);
-        for i in 0..password.len() {
-            password[i] ^= auth_data[i % auth_data.len()];
+        if self.conn.is_secure() {
+            let mut packet: Vec<u8> = vec![];
+            packet.extend(pack_header(&password, seq_id));
+            packet.extend(password.iter());
+            write_value(&mut self.conn, &packet)?;
+        } else {
+            let payload = [0x02];
+            let mut packet: Vec<u8> = vec![];
+            packet.extend(pack_header(&payload, seq_id));
+            packet.extend(payload.iter());
+            write_value(&mut self.conn, &packet)?;
+
+            let (packet_buf,header) = socketio::get_packet_from_stream(&mut self.conn)?;
+
+            let key = &packet_buf[1..];
+            for i in 0..password.len() {
+                password[i] ^= auth_data[i % auth_data.len()];
+            }
+            let encrypted_pass = encrypt(&password, &key);
+            let mut packet: Vec<u8> = vec![];
+            packet.extend(pack_header(&encrypted_pass, header.seq_id + 1));
+            packet.extend(encrypted_pass.iter());
+            write_value(&mut self.conn, &packet)?;
         }
-        let encrypted_pass = encrypt(&password, &key);
-        let mut packet: Vec<u8> = vec![];
-        packet.extend(pack_header(&encrypted_pass, 7));
-        packet.extend(encrypted_pass.iter());
-        write_value(&mut self.conn, &packet)?;
 
         let (packet_buf,_) = socketio::get_packet_from_stream(&mut self.conn)?;
         if pack::check_pack(&packet_buf) {
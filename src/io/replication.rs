@@ -0,0 +1,177 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/27
+*/
+
+use crate::io::stream::MysqlStream;
+use crate::io::response;
+use crate::io::socketio;
+use crate::readvalue;
+use crate::Config;
+use std::error::Error;
+use byteorder::{WriteBytesExt, LittleEndian};
+use uuid::Uuid;
+
+const COM_BINLOG_DUMP: u8 = 0x12;
+const COM_REGISTER_SLAVE: u8 = 0x15;
+const COM_BINLOG_DUMP_GTID: u8 = 0x1e;
+const BINLOG_DUMP_NON_BLOCK: u16 = 1;
+const BINLOG_THROUGH_GTID: u16 = 4;
+//阻塞读取时的心跳间隔，需小于连接的读超时(10s)
+const HEARTBEAT_PERIOD_NS: u64 = 5_000_000_000;
+
+///
+/// 以slave身份连接mysql拉取binlog
+///     register之后使用COM_BINLOG_DUMP(按位置)或COM_BINLOG_DUMP_GTID(按gtid)开始读取
+///     non_block为true时读取到最后一个binlog的末尾返回EOF，否则一直等待新的event
+///
+pub struct ReplicationStream {
+    conn: MysqlStream,
+    server_id: u32,
+}
+
+impl ReplicationStream {
+    pub fn connect(conf: &Config, server_id: u32) -> Result<ReplicationStream, Box<dyn Error>> {
+        let conn = crate::create_conn(conf)?;
+        let mut stream = ReplicationStream{ conn, server_id };
+        stream.prepare()?;
+        stream.register_slave(conf)?;
+        Ok(stream)
+    }
+
+    ///
+    /// 告诉master可以处理checksum，否则开启了binlog_checksum的master会拒绝dump
+    ///
+    fn prepare(&mut self) -> Result<(), Box<dyn Error>> {
+        let sqls = vec![
+            String::from("set @master_binlog_checksum = @@global.binlog_checksum"),
+            format!("set @master_heartbeat_period = {}", HEARTBEAT_PERIOD_NS),
+            format!("set @slave_uuid = '{}'", Uuid::new_v4()),
+        ];
+        for sql in sqls.iter() {
+            //低版本没有对应的变量，不影响dump
+            if let Err(e) = crate::io::command::execute_update(&mut self.conn, sql) {
+                info!("{}: {}", sql, e);
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// COM_REGISTER_SLAVE: server_id(4bytes), hostname, user, password(1bytes长度 + 值),
+    ///                     port(2bytes), replication_rank(4bytes), master_id(4bytes)
+    ///
+    fn register_slave(&mut self, conf: &Config) -> Result<(), Box<dyn Error>> {
+        let mut payload = vec![COM_REGISTER_SLAVE];
+        payload.write_u32::<LittleEndian>(self.server_id)?;
+        //hostname、user、password只用于show slave hosts展示
+        for value in &[String::from(""), conf.user_name.clone(), String::from("")] {
+            let value = value.as_bytes();
+            let length = value.len().min(255);
+            payload.push(length as u8);
+            payload.extend(&value[..length]);
+        }
+        payload.write_u16::<LittleEndian>(0)?;
+        payload.write_u32::<LittleEndian>(0)?;
+        payload.write_u32::<LittleEndian>(0)?;
+        self.command(&payload)?;
+        let (buf, _) = socketio::get_packet_from_stream(&mut self.conn)?;
+        check_error(&buf)?;
+        Ok(())
+    }
+
+    ///
+    /// COM_BINLOG_DUMP: binlog_pos(4bytes), flags(2bytes), server_id(4bytes), binlog_filename
+    ///
+    pub fn dump(&mut self, binlog: &String, position: u64, non_block: bool) -> Result<(), Box<dyn Error>> {
+        let mut payload = vec![COM_BINLOG_DUMP];
+        payload.write_u32::<LittleEndian>(position as u32)?;
+        payload.write_u16::<LittleEndian>(if non_block { BINLOG_DUMP_NON_BLOCK } else { 0 })?;
+        payload.write_u32::<LittleEndian>(self.server_id)?;
+        payload.extend(binlog.as_bytes());
+        info!("binlog dump from {}:{}", binlog, position);
+        self.command(&payload)
+    }
+
+    ///
+    /// COM_BINLOG_DUMP_GTID: flags(2bytes), server_id(4bytes), binlog_name_len(4bytes), binlog_name,
+    ///                       binlog_pos(8bytes), gtid_data_len(4bytes), gtid_data
+    ///     从master上不在gtid_set中的第一个事务开始读取
+    ///
+    pub fn dump_gtid(&mut self, gtid_set: &String, non_block: bool) -> Result<(), Box<dyn Error>> {
+        let gtid_data = encode_gtid_set(gtid_set)?;
+        let mut flags = BINLOG_THROUGH_GTID;
+        if non_block {
+            flags |= BINLOG_DUMP_NON_BLOCK;
+        }
+        let mut payload = vec![COM_BINLOG_DUMP_GTID];
+        payload.write_u16::<LittleEndian>(flags)?;
+        payload.write_u32::<LittleEndian>(self.server_id)?;
+        payload.write_u32::<LittleEndian>(0)?;
+        payload.write_u64::<LittleEndian>(4)?;
+        payload.write_u32::<LittleEndian>(gtid_data.len() as u32)?;
+        payload.extend(gtid_data);
+        info!("binlog dump from gtid set: {}", gtid_set);
+        self.command(&payload)
+    }
+
+    ///
+    /// 读取一个event(包含header，开启checksum时包含末尾的crc32)，non_block读取到末尾时返回None
+    ///
+    pub fn read_event(&mut self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let (buf, _) = socketio::get_packet_from_stream(&mut self.conn)?;
+        match buf.first() {
+            Some(0x00) => Ok(Some(buf[1..].to_vec())),
+            Some(0xfe) if buf.len() < 9 => Ok(None),
+            _ => {
+                check_error(&buf)?;
+                Err(format!("unexpected replication packet: {:?}", buf.first()).into())
+            }
+        }
+    }
+
+    ///
+    /// 每个命令都是新的请求，sequence id从0开始
+    ///
+    fn command(&mut self, payload: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let mut pack = response::pack_header(payload, 0);
+        pack.extend(payload);
+        socketio::write_value(&mut self.conn, &pack)
+    }
+
+    pub fn close(&mut self) {
+        crate::io::command::close(&mut self.conn);
+    }
+}
+
+fn check_error(buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+    match buf.first() {
+        Some(0xff) if buf.len() > 3 => Err(readvalue::read_string_value(&buf[3..]).into()),
+        Some(0xff) => Err(String::from("replication error").into()),
+        _ => Ok(())
+    }
+}
+
+///
+/// gtid集合(uuid:1-10:15,uuid2:3)编码为dump使用的格式:
+///     n_sids(8bytes), 每个sid: uuid(16bytes), n_intervals(8bytes), (start, end+1)(各8bytes)
+///
+pub fn encode_gtid_set(gtid_set: &String) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sids: Vec<(Uuid, Vec<(u64, u64)>)> = vec![];
+    for (sid, ranges) in crate::meta::parse_gtid_set(gtid_set)? {
+        sids.push((Uuid::parse_str(&sid)?, ranges.iter().map(|(start, end)| (*start, end + 1)).collect()));
+    }
+    sids.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut data = vec![];
+    data.write_u64::<LittleEndian>(sids.len() as u64)?;
+    for (sid, intervals) in sids.iter() {
+        data.extend(sid.as_bytes());
+        data.write_u64::<LittleEndian>(intervals.len() as u64)?;
+        for (start, end) in intervals.iter() {
+            data.write_u64::<LittleEndian>(*start)?;
+            data.write_u64::<LittleEndian>(*end)?;
+        }
+    }
+    Ok(data)
+}
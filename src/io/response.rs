@@ -33,19 +33,23 @@ pub struct LocalInfo{
 }
 
 impl LocalInfo {
-    pub fn new(program: &String,database: u8) -> Self{
+    pub fn new(program: &String,database: u8, ssl: bool) -> Self{
         let mut client_flag = 0;
         let flags_meta = meta::FlagsMeta::new();
         let capabilities = flags_meta.long_password|
             flags_meta.long_flag|flags_meta.protocol_41|flags_meta.transactions|
             flags_meta.secure_connection|flags_meta.multi_results|
             flags_meta.client_plugin_auth| flags_meta.client_plugin_auth_lenenc_client_data|
-            flags_meta.client_connect_attrs|flags_meta.client_deprecate_eof;
+            flags_meta.client_connect_attrs|flags_meta.client_deprecate_eof|
+            flags_meta.client_found_rows;       //update返回匹配的行数而不是修改的行数，回滚时用于校验
         client_flag |= capabilities;
         if database > 0{
             client_flag |= flags_meta.client_connect_with_db
         }
         client_flag |= flags_meta.multi_results;
+        if ssl {
+            client_flag |= flags_meta.client_ssl;
+        }
         Self{
             client_name: String::from("rust_client"),
             pid:123,
@@ -56,6 +60,18 @@ impl LocalInfo {
         }
     }
 
+    ///
+    /// ssl request包: client_flag + max_packet_size + character_set + 23字节填充
+    ///
+    pub fn pack_ssl_request(&self, buf: &HandshakePacket) -> Vec<u8> {
+        let mut rdr = Vec::new();
+        rdr.extend(readvalue::write_i32(self.client_flag));
+        rdr.extend(readvalue::write_u32(self.max_packet_size));
+        rdr.push(buf.character_set_id);
+        rdr.extend(vec![0u8; 23]);
+        return rdr;
+    }
+
     pub unsafe fn pack_handshake(&self, buf: &HandshakePacket, config: &Config, database: u8) -> Vec<u8>{
         //组包
         let mut rdr = Vec::new();
@@ -126,7 +142,7 @@ impl LocalInfo {
 
 impl LocalInfo {
 
-    pub fn pack_payload(&self, buf: &HandshakePacket, pack_type: &PackType, config: &Config) -> Result<Vec<u8> ,&'static str> {
+    pub fn pack_payload(&self, buf: &HandshakePacket, pack_type: &PackType, config: &Config, seq_id: u8) -> Result<Vec<u8> ,&'static str> {
         //组装handshakeresponse包从这里开始，返回一个完整的回包
         //所有数据都放于verctor类型中
         let mut _payload = Vec::new();
@@ -145,7 +161,7 @@ impl LocalInfo {
         }
 
         if payload_value.len() > 0{
-            packet_header = pack_header(&payload_value,1)
+            packet_header = pack_header(&payload_value,seq_id)
         }else {
             return Err("paket payload is failed!!");
         }
@@ -156,7 +172,7 @@ impl LocalInfo {
 }
 
 //auth_switch需再次验证密码方式
-pub fn authswitchrequest(handshake: &HandshakePacket,buf: &Vec<u8>,conf: &Config) -> (Vec<u8>,Vec<u8>) {
+pub fn authswitchrequest(handshake: &HandshakePacket,buf: &Vec<u8>,conf: &Config, seq_id: u8) -> (Vec<u8>,Vec<u8>) {
     let mut packet: Vec<u8> = vec![];
     let mut payload: Vec<u8> = vec![];
     let mut offset = 1;
@@ -177,7 +193,7 @@ pub fn authswitchrequest(handshake: &HandshakePacket,buf: &Vec<u8>,conf: &Config
         }
     }
 
-    packet.extend(pack_header(payload.as_ref(), 3));
+    packet.extend(pack_header(payload.as_ref(), seq_id));
     packet.extend(payload);
     return (packet, auth_plugin_data.to_vec());
 }
@@ -3,7 +3,6 @@
 @datetime: 2019/9/21
 */
 use crate::readvalue;
-use std::net::TcpStream;
 use std::io::{Read, Write};
 use std::error::Error;
 
@@ -25,7 +24,7 @@ impl PacketHeader{
     }
 }
 
-fn get_from_stream(stream: &mut TcpStream) -> Result<(Vec<u8>, PacketHeader), Box<dyn Error>>{
+fn get_from_stream<S: Read>(stream: &mut S) -> Result<(Vec<u8>, PacketHeader), Box<dyn Error>>{
     //获取一个数据包
     //定义4个u8的vector接收包头4bytes数据
 
@@ -63,18 +62,20 @@ fn get_from_stream(stream: &mut TcpStream) -> Result<(Vec<u8>, PacketHeader), Bo
     return Ok((packet_buf,header));
 }
 
-pub fn get_packet_from_stream(stream: &mut TcpStream) -> Result<(Vec<u8>, PacketHeader), Box<dyn Error>>{
+pub fn get_packet_from_stream<S: Read>(stream: &mut S) -> Result<(Vec<u8>, PacketHeader), Box<dyn Error>>{
     let (mut buf,header) = get_from_stream(stream)?;
-    while header.payload == 0xffffff{
-        info!("{}",header.payload);
-        let (buf_tmp,_) = get_from_stream(stream)?;
+    //payload为0xffffff时数据被拆分为多个包，直到最后一个小于0xffffff的包
+    let mut payload = header.payload;
+    while payload == 0xffffff{
+        let (buf_tmp,tmp_header) = get_from_stream(stream)?;
+        payload = tmp_header.payload;
         buf.extend(buf_tmp);
     }
     Ok((buf, header))
 }
 
 //向连接写入数据
-pub fn write_value(stream: &mut TcpStream, buf: &Vec<u8>) -> Result<(),Box<dyn Error>> {
+pub fn write_value<S: Write>(stream: &mut S, buf: &Vec<u8>) -> Result<(),Box<dyn Error>> {
     stream.write_all(buf)?;
     Ok(())
 }
@@ -10,12 +10,15 @@ pub mod readvalue;
 pub mod meta;
 pub mod binlog;
 pub mod storage;
+pub mod cli;
 
 use pool::ThreadPool;
 use structopt::StructOpt;
 use std::sync::{Arc, Mutex};
 use std::net::{TcpListener, TcpStream};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use crate::mysql::ReponseErr;
 use chrono::prelude::*;
 use chrono;
@@ -32,6 +35,11 @@ use log4rs::config::{Appender, Root};
 use std::error::Error;
 use std::thread;
 use crate::mysql::state_check::{MysqlState, LastCheckTime};
+use crate::mysql::slowlog::{SlowLogState, SlowLogTail};
+use crate::mysql::audit::{AuditLog, AuditTail};
+use crate::mysql::cdc::{CdcLog, CdcTail};
+use crate::meta::SchemaCache;
+use crate::io::stream::MysqlStream;
 
 fn init_log() {
     let stdout = ConsoleAppender::builder().build();
@@ -63,6 +71,11 @@ pub fn timestamp() -> i64 {
     ms
 }
 
+/// 共享密钥的环境变量
+const SECRET_ENV: &str = "MYMHA_SECRET";
+/// 接收请求数据包的默认最大长度
+pub const MAX_PACKET_SIZE: u64 = 64 * 1024 * 1024;
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
 pub struct Opt {
@@ -72,19 +85,28 @@ pub struct Opt {
     #[structopt(long = "audit", help="开启审计日志功能")]
     pub audit: bool,
 
+    #[structopt(long = "cdc", help="开启cdc, 输出每行数据的变更记录")]
+    pub cdc: bool,
+
     #[structopt(long = "monitor", help="实时监控")]
     pub monitor: bool,
 
     #[structopt(long = "port", help="程序运行端口, 默认9011")]
     pub port: Option<String>,
 
+    #[structopt(long = "bind", help="监听地址, 默认为[::](同时接受ipv4及ipv6连接, 不支持ipv6时为0.0.0.0)")]
+    pub bind: Option<String>,
+
+    #[structopt(long = "max-packet-size", help="接收请求数据包的最大长度(bytes), 超过时断开连接, 默认64MB")]
+    pub max_packet_size: Option<u64>,
+
     #[structopt(short = "u", long = "user",help = "mysql用户名")]
     pub user: Option<String>,
 
     #[structopt(short = "p", long = "password",help = "mysql密码")]
     pub password: Option<String>,
 
-    #[structopt(short = "h",long = "host", help="ip地址加端口, ip:port 例如127.0.0.1:3306")]
+    #[structopt(short = "h",long = "host", help="ip地址加端口, ip:port 例如127.0.0.1:3306, 也可使用hostname:port或[ipv6]:port")]
     pub host: Option<String>,
 
     #[structopt(long = "repluser",help = "主从同步用户名")]
@@ -96,14 +118,65 @@ pub struct Opt {
     #[structopt(long = "binlogdir",help = "binlog文件保存路径,默认为/usr/local/mysql/data")]
     pub binlogdir: Option<String>,
 
+    #[structopt(long = "secret",help = "与server端通信验证使用的共享密钥，为空时不进行验证, 命令行参数可通过ps看到, 建议使用secret-file或环境变量MYMHA_SECRET")]
+    pub secret: Option<String>,
+
+    #[structopt(long = "secret-file",help = "从文件读取共享密钥(去掉首尾空白)")]
+    pub secret_file: Option<String>,
+
+    #[structopt(long = "allow-hosts",help = "允许连接的server端及其他client的ip, 多个使用逗号分隔，为空时不限制")]
+    pub allow_hosts: Option<String>,
+
+    #[structopt(long = "socket",help = "本地mysql的unix socket文件, 配置后连接本地mysql时不使用tcp")]
+    pub socket: Option<String>,
+
+    #[structopt(long = "ssl",help = "连接mysql时使用ssl加密, 配置了ssl-ca/ssl-cert时自动开启")]
+    pub ssl: bool,
+
+    #[structopt(long = "ssl-ca",help = "校验mysql服务端证书的ca文件(pem)")]
+    pub ssl_ca: Option<String>,
+
+    #[structopt(long = "ssl-cert",help = "客户端证书文件(pem), 需同时配置ssl-key")]
+    pub ssl_cert: Option<String>,
+
+    #[structopt(long = "ssl-key",help = "客户端私钥文件(pem, pkcs8或pkcs1)")]
+    pub ssl_key: Option<String>,
+
+    #[structopt(long = "ssl-skip-hostname",help = "配置ssl-ca时不校验服务端证书中的主机名, 用于证书不包含连接地址的情况(如mysql自动生成的证书)")]
+    pub ssl_skip_hostname: bool,
+
+    #[structopt(long = "auditdir",help = "审计日志保存路径,默认为当前目录下的audit")]
+    pub auditdir: Option<String>,
+
+    #[structopt(long = "cdcdir",help = "cdc记录及checkpoint保存路径,默认为当前目录下的cdc")]
+    pub cdcdir: Option<String>,
+
+    #[structopt(long = "cdc-output",help = "cdc记录同时写入的文件或命名管道")]
+    pub cdc_output: Option<String>,
+
+    #[structopt(long = "flashbackdir",help = "回滚时生成的flashback binlog保存路径,默认为当前目录下的flashback")]
+    pub flashbackdir: Option<String>,
+
+    #[structopt(long = "dump-server-id",help = "从mysql拉取binlog时注册的server_id,不能与复制集群中的其他server_id重复,默认为65535")]
+    pub dump_server_id: Option<u32>,
+
+    #[structopt(long = "schema-cache",help = "表结构缓存文件, mysql无法连接时解析binlog使用, 默认为当前目录下的schema_cache.json")]
+    pub schema_cache: Option<String>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<cli::Command>,
+
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub slowlog: bool,
     pub audit: bool,
+    pub cdc: bool,
     pub monitor: bool,
     pub port: u32,
+    pub bind: String,
+    pub max_packet_size: u64,
     pub host_info: String,
     pub user_name: String,
     pub password: String,
@@ -111,7 +184,21 @@ pub struct Config {
     pub program_name: String,
     pub repl_user: String,
     pub repl_passwd: String,
-    pub binlogdir: String
+    pub binlogdir: String,
+    pub auditdir: String,
+    pub cdcdir: String,
+    pub cdc_output: String,
+    pub flashbackdir: String,
+    pub dump_server_id: u32,
+    pub schema_cache: String,
+    pub secret: String,
+    pub allow_hosts: Vec<String>,
+    pub socket: String,
+    pub ssl: bool,
+    pub ssl_ca: String,
+    pub ssl_cert: String,
+    pub ssl_key: String,
+    pub ssl_skip_hostname: bool
 }
 
 impl Config{
@@ -122,9 +209,23 @@ impl Config{
         let mut repl_user = String::from("");
         let mut repl_passwd = String::from("");
         let mut binlogdir = String::from("/usr/local/mysql/data");
+        let mut auditdir = String::from("audit");
+        let mut cdcdir = String::from("cdc");
+        let mut cdc_output = String::from("");
+        let mut flashbackdir = String::from("flashback");
+        let mut dump_server_id: u32 = 65535;
+        let mut schema_cache = String::from("schema_cache.json");
+        let mut secret = String::from("");
+        let mut allow_hosts = vec![];
+        let mut socket = String::from("");
+        let mut ssl = args.ssl;
+        let mut ssl_ca = String::from("");
+        let mut ssl_cert = String::from("");
+        let mut ssl_key = String::from("");
         let database = String::from("");
         let slowlog = args.slowlog;
         let audit = args.audit;
+        let cdc = args.cdc;
         let monitor = args.monitor;
         let mut port : u32 = 9011;
 
@@ -134,6 +235,101 @@ impl Config{
             Some(t) => binlogdir = t,
         }
 
+        match args.auditdir {
+            None => {
+            },
+            Some(t) => auditdir = t,
+        }
+
+        match args.cdcdir {
+            None => {
+            },
+            Some(t) => cdcdir = t,
+        }
+
+        match args.cdc_output {
+            None => {
+            },
+            Some(t) => cdc_output = t,
+        }
+
+        match args.flashbackdir {
+            None => {
+            },
+            Some(t) => flashbackdir = t,
+        }
+
+        match args.dump_server_id {
+            None => {
+            },
+            Some(t) => dump_server_id = t,
+        }
+
+        match args.schema_cache {
+            None => {
+            },
+            Some(t) => schema_cache = t,
+        }
+
+        //优先级: --secret > --secret-file > 环境变量MYMHA_SECRET
+        match args.secret {
+            None => {
+                match args.secret_file {
+                    None => {
+                        if let Ok(t) = std::env::var(SECRET_ENV) {
+                            secret = t;
+                        }
+                    },
+                    Some(t) => {
+                        secret = match std::fs::read_to_string(&t) {
+                            Ok(v) => v.trim().to_string(),
+                            Err(_) => return Err("read secret file failed")
+                        };
+                    }
+                }
+            },
+            Some(t) => secret = t,
+        }
+
+        match args.allow_hosts {
+            None => {
+            },
+            Some(t) => {
+                for host in t.split(",") {
+                    if host.trim().len() > 0 {
+                        allow_hosts.push(host.trim().to_string());
+                    }
+                }
+            }
+        }
+
+        match args.socket {
+            None => {
+            },
+            Some(t) => socket = t,
+        }
+
+        match args.ssl_ca {
+            None => {
+            },
+            Some(t) => {
+                ssl = true;
+                ssl_ca = t;
+            }
+        }
+
+        match (args.ssl_cert, args.ssl_key) {
+            (None, None) => {},
+            (Some(c), Some(k)) => {
+                ssl = true;
+                ssl_cert = c;
+                ssl_key = k;
+            }
+            _ => {
+                return Err("ssl-cert 和 ssl-key 需同时配置！！");
+            }
+        }
+
         match args.repluser {
             None => {
                 return Err("repluser 不能为空！！");
@@ -159,7 +355,12 @@ impl Config{
             None => {
                 return Err("host 不能为空！！");
             },
-            Some(t) => host_info = t,
+            Some(t) => {
+                if let Err(_) = mysql::split_host(&t) {
+                    return Err("host 格式错误, 需为ip:port、hostname:port或[ipv6]:port");
+                }
+                host_info = t;
+            }
         }
 
         match args.password {
@@ -177,8 +378,11 @@ impl Config{
         Ok(Config{
             slowlog,
             audit,
+            cdc,
             monitor,
             port,
+            bind: args.bind.unwrap_or_default(),
+            max_packet_size: args.max_packet_size.unwrap_or(MAX_PACKET_SIZE),
             user_name,
             host_info,
             password,
@@ -186,7 +390,21 @@ impl Config{
             repl_user,
             repl_passwd,
             program_name:String::from("rust_test"),
-            binlogdir
+            binlogdir,
+            auditdir,
+            cdcdir,
+            cdc_output,
+            flashbackdir,
+            dump_server_id,
+            schema_cache,
+            secret,
+            allow_hosts,
+            socket,
+            ssl,
+            ssl_ca,
+            ssl_cert,
+            ssl_key,
+            ssl_skip_hostname: args.ssl_skip_hostname
         })
     }
 
@@ -194,8 +412,11 @@ impl Config{
         Config{
             slowlog: self.slowlog.clone(),
             audit: self.audit.clone(),
+            cdc: self.cdc.clone(),
             monitor: self.monitor.clone(),
             port: self.port.clone(),
+            bind: self.bind.clone(),
+            max_packet_size: self.max_packet_size.clone(),
             host_info: self.host_info.clone(),
             user_name: self.user_name.clone(),
             password: self.password.clone(),
@@ -203,12 +424,28 @@ impl Config{
             program_name: self.program_name.clone(),
             repl_user: self.repl_user.clone(),
             repl_passwd: self.repl_passwd.clone(),
-            binlogdir: self.binlogdir.clone()
+            binlogdir: self.binlogdir.clone(),
+            auditdir: self.auditdir.clone(),
+            cdcdir: self.cdcdir.clone(),
+            cdc_output: self.cdc_output.clone(),
+            flashbackdir: self.flashbackdir.clone(),
+            dump_server_id: self.dump_server_id.clone(),
+            schema_cache: self.schema_cache.clone(),
+            secret: self.secret.clone(),
+            allow_hosts: self.allow_hosts.clone(),
+            socket: self.socket.clone(),
+            ssl: self.ssl.clone(),
+            ssl_ca: self.ssl_ca.clone(),
+            ssl_cert: self.ssl_cert.clone(),
+            ssl_key: self.ssl_key.clone(),
+            ssl_skip_hostname: self.ssl_skip_hostname.clone()
         }
     }
 
     pub fn alter_host(&mut self, host_info: String) {
         self.host_info = host_info;
+        //连接其他节点时只能使用tcp
+        self.socket = String::from("");
         self.user_name = self.repl_user.clone();
         self.password = self.repl_passwd.clone();
     }
@@ -217,12 +454,20 @@ impl Config{
 
 pub fn start(conf: Config) {
     init_log();
-    let listen_info = format!("0.0.0.0:{}",conf.port);
-    let listener = TcpListener::bind(listen_info).unwrap_or_else(|err|{
+    let listener = bind_listener(&conf).unwrap_or_else(|err|{
         info!("{:?}",err);
         std::process::exit(1)
     });
+    if conf.secret.len() == 0 {
+        match listener.local_addr() {
+            Ok(addr) if !addr.ip().is_loopback() => {
+                warn!("listening on {} without secret, any host that can reach this port can send commands, please configure secret/secret-file or allow-hosts", addr);
+            }
+            _ => {}
+        }
+    }
 
+    let start_time = Instant::now();
     let default_mysql_state = Arc::new(Mutex::new(mysql::state_check::MysqlState::new()));
     let now_time = Local::now().timestamp_millis() as usize;
     let default_check_time = Arc::new(Mutex::new(mysql::state_check::LastCheckTime{last_time:now_time}));
@@ -234,25 +479,87 @@ pub fn start(conf: Config) {
         health_conn.loop_state_check().unwrap();
     });
 
+    //慢日志采集线程
+    let slowlog_state = Arc::new(Mutex::new(SlowLogState::new()));
+    if conf.slowlog {
+        let mut slowlog_tail = SlowLogTail::new(Arc::clone(&conf), Arc::clone(&slowlog_state));
+        thread::spawn(move|| {
+            slowlog_tail.loop_tail();
+        });
+    }
+
+    //表结构缓存，binlog解析时共用
+    let schema = Arc::new(Mutex::new(SchemaCache::new(&conf.schema_cache)));
+
+    //审计日志线程
+    let audit_log = Arc::new(Mutex::new(AuditLog::new(&conf.auditdir)));
+    if conf.audit {
+        let mut audit_tail = AuditTail::new(Arc::clone(&conf), Arc::clone(&audit_log), Arc::clone(&schema));
+        thread::spawn(move|| {
+            audit_tail.loop_tail();
+        });
+    }
+
+    //cdc线程
+    let cdc_log = Arc::new(Mutex::new(CdcLog::new(&conf.cdcdir, &conf.cdc_output)));
+    if conf.cdc {
+        let mut cdc_tail = CdcTail::new(Arc::clone(&conf), Arc::clone(&cdc_log), Arc::clone(&schema));
+        thread::spawn(move|| {
+            cdc_tail.loop_tail();
+        });
+    }
+
+    //当前会话连接数
+    let sessions = Arc::new(AtomicUsize::new(0));
+    //所有会话共用的请求处理线程
+    let session_workers = Arc::new(Mutex::new(ThreadPool::new(SESSION_WORKERS)));
 
     let pool = ThreadPool::new(4);
     // accept connections and process them serially
     for stream in listener.incoming() {
         let conf = Arc::clone(&conf);
         let stream = stream.unwrap();
-        let (a, b) = (Arc::clone(&default_mysql_state), Arc::clone(&default_check_time));
+        let (a, b, c, d, e, f) = (Arc::clone(&default_mysql_state), Arc::clone(&default_check_time), Arc::clone(&slowlog_state), Arc::clone(&audit_log), Arc::clone(&schema), Arc::clone(&cdc_log));
+        let (sessions, session_workers) = (Arc::clone(&sessions), Arc::clone(&session_workers));
         pool.execute(move||{
-            handle_stream(stream, conf, a, b)
+            handle_stream(stream, conf, a, b, c, d, e, f, sessions, session_workers, start_time)
         });
     }
 }
 
 
-fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlState>>, last_check_time: Arc<Mutex<LastCheckTime>>) {
+///
+/// 未配置bind时监听[::]，linux下默认同时接受ipv4连接(ipv4地址为::ffff:a.b.c.d)，系统不支持ipv6时使用0.0.0.0
+///
+fn bind_listener(conf: &Config) -> Result<TcpListener, Box<dyn Error>> {
+    if conf.bind.len() > 0 {
+        let host = match conf.bind.find(':') {
+            Some(_) if !conf.bind.starts_with('[') => format!("[{}]", conf.bind),
+            _ => conf.bind.clone()
+        };
+        return Ok(TcpListener::bind(format!("{}:{}", host, conf.port))?);
+    }
+    match TcpListener::bind(format!("[::]:{}", conf.port)) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            info!("bind [::]:{} failed: {}, use 0.0.0.0", conf.port, e.to_string());
+            Ok(TcpListener::bind(format!("0.0.0.0:{}", conf.port))?)
+        }
+    }
+}
+
+fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlState>>, last_check_time: Arc<Mutex<LastCheckTime>>, slowlog_state: Arc<Mutex<SlowLogState>>, audit_log: Arc<Mutex<AuditLog>>, schema: Arc<Mutex<SchemaCache>>, cdc_log: Arc<Mutex<CdcLog>>, sessions: Arc<AtomicUsize>, session_workers: Arc<Mutex<ThreadPool>>, start_time: Instant) {
     tcp.set_read_timeout(Some(Duration::new(2,10))).expect("set_read_timeout call failed");
     tcp.set_write_timeout(Some(Duration::new(10,10))).expect("set_write_timeout call failed");
 
-    let result = readvalue::rec_packet(&mut tcp);
+    if let Err(e) = mysql::auth::authenticate(&mut tcp, &conf) {
+        info!("{}", e.to_string());
+        let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
+        mysql::check_state(&state);
+        return;
+    }
+
+    let result = readvalue::rec_packet_limited(&mut tcp, conf.max_packet_size);
 //    let mut buf = [0u8; 1];
 //    let result = tcp.read_exact(&mut buf);
     //println!("{}",buf[0]);
@@ -269,8 +576,30 @@ fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlSt
                         return;
                     };
                 }
-                mysql::MyProtocol::GetSlowLog => {}
-                mysql::MyProtocol::GetAuditLog => {}
+                mysql::MyProtocol::GetSlowLog => {
+                    if let Err(e) = mysql::slowlog::get_slow_log(&tcp, &conf, &slowlog_state, &buf){
+                        let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
+                        info!("{}",e.to_string());
+                        mysql::check_state(&state);
+                        return;
+                    }
+                }
+                mysql::MyProtocol::GetAuditLog => {
+                    if let Err(e) = mysql::audit::get_audit_log(&tcp, &conf, &audit_log, &buf){
+                        let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
+                        info!("{}",e.to_string());
+                        mysql::check_state(&state);
+                        return;
+                    }
+                }
+                mysql::MyProtocol::GetCdcRecords => {
+                    if let Err(e) = mysql::cdc::get_cdc_records(&tcp, &conf, &cdc_log, &buf){
+                        let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
+                        info!("{}",e.to_string());
+                        mysql::check_state(&state);
+                        return;
+                    }
+                }
                 mysql::MyProtocol::GetMonitor => {
                     if let Err(e) = mysql::monitor::mysql_monitor(&tcp, &conf){
                         let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
@@ -306,7 +635,7 @@ fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlSt
                     };
                 }
                 mysql::MyProtocol::PushBinlog => {
-                    if let Err(e) = mysql::syncbinlog::push_binlog_info(&conf, &mut tcp, &buf){
+                    if let Err(e) = mysql::syncbinlog::push_binlog_info(&conf, &schema, &mut tcp, &buf){
                         info!("{}", &e.to_string());
                         let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
                         mysql::check_state(&state);
@@ -314,7 +643,7 @@ fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlSt
                 }
                 mysql::MyProtocol::RecoveryCluster => {
                     info!("this is a recoverycluster packet !!");
-                    let state = mysql::recovery::recovery_my_slave(&mut tcp, &conf, &buf);
+                    let state = mysql::recovery::recovery_my_slave(&mut tcp, &conf, &schema, &buf);
                     match state {
                         Ok(()) => {
                             info!("recovery down ");
@@ -336,6 +665,31 @@ fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlSt
                     let state = mysql::send_value_packet(&tcp, &recovery_info, mysql::MyProtocol::GetRecoveryInfo);
                     mysql::check_state(&state);
                 }
+                mysql::MyProtocol::Ping => {
+                    let state = mysql::state_check::ping(&tcp, &state, start_time);
+                    mysql::check_state(&state);
+                }
+                mysql::MyProtocol::ReplicationStatus => {
+                    if let Err(e) = mysql::state_check::replication_status(&tcp, &conf){
+                        let state = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut tcp);
+                        info!("{}",e.to_string());
+                        mysql::check_state(&state);
+                        return;
+                    }
+                }
+                mysql::MyProtocol::Session => {
+                    //会话连接长期占用，使用单独线程处理，不占用线程池，超过MAX_SESSIONS时拒绝
+                    if sessions.fetch_add(1, Ordering::SeqCst) >= MAX_SESSIONS {
+                        sessions.fetch_sub(1, Ordering::SeqCst);
+                        let state = mysql::send_error_packet(&ReponseErr::new(String::from("too many sessions")), &mut tcp);
+                        mysql::check_state(&state);
+                        return;
+                    }
+                    thread::spawn(move|| {
+                        handle_session(tcp, conf, state, last_check_time, slowlog_state, audit_log, cdc_log, session_workers, start_time);
+                        sessions.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
                 mysql::MyProtocol::DownNodeCheck => {
                     let state = mysql::nodecheck::check_down_node(&mut tcp, &conf, &buf);
                     mysql::check_state(&state);
@@ -373,7 +727,7 @@ fn handle_stream(mut tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlSt
 }
 
 
-fn create_conn(config: &Config) -> Result<TcpStream, Box<dyn Error>> {
+fn create_conn(config: &Config) -> Result<MysqlStream, Box<dyn Error>> {
     let mut mysql_connection_info = io::connection::MysqlConnection::new(config)?;
     if let Err(e) = mysql_connection_info.create(config){
         info!("create mysql connection error: {:?}", e);
@@ -402,3 +756,103 @@ fn create_conn(config: &Config) -> Result<TcpStream, Box<dyn Error>> {
 //    let conn = io::connection::create_mysql_conn(config)?;
 //    return Ok(conn);
 }
+
+
+///
+/// 会话模式
+///     一个连接上处理多个请求，避免server端每秒轮询时频繁建立连接
+///     请求: 4字节request id(小端) + 普通数据包
+///     响应: 相同的request id + 普通数据包，所有会话的请求共用SESSION_WORKERS个线程并发处理，
+///           响应顺序与请求顺序不一定相同，server端按request id对应
+///           每个会话未完成的请求超过SESSION_PENDING时直接返回错误，不再排队
+///     server端需定期发送请求(如ping)保活，超过SESSION_IDLE_TIMEOUT没有请求时关闭连接
+///     只支持状态查询类请求，切换、恢复等操作仍使用单次请求的连接
+///
+const SESSION_IDLE_TIMEOUT: u64 = 60;
+/// 同时存在的会话数上限
+const MAX_SESSIONS: usize = 16;
+/// 所有会话共用的处理请求的线程数
+const SESSION_WORKERS: usize = 8;
+/// 每个会话未完成的请求数上限
+const SESSION_PENDING: usize = 8;
+
+fn handle_session(tcp: TcpStream, conf: Arc<Config>, state: Arc<Mutex<MysqlState>>, last_check_time: Arc<Mutex<LastCheckTime>>, slowlog_state: Arc<Mutex<SlowLogState>>, audit_log: Arc<Mutex<AuditLog>>, cdc_log: Arc<Mutex<CdcLog>>, workers: Arc<Mutex<ThreadPool>>, start_time: Instant) {
+    let mut tcp = tcp;
+    tcp.set_read_timeout(Some(Duration::from_secs(SESSION_IDLE_TIMEOUT))).expect("set_read_timeout call failed");
+    if let Err(e) = mysql::send_ok_packet(&tcp) {
+        info!("session start failed: {}", e.to_string());
+        return;
+    }
+    let peer = match tcp.peer_addr() {
+        Ok(v) => v.to_string(),
+        Err(_) => String::from("")
+    };
+    //响应由多个线程写入，每个响应需完整写入后才能写下一个
+    let writer = match tcp.try_clone() {
+        Ok(v) => Arc::new(Mutex::new(v)),
+        Err(e) => {
+            info!("session from {} start failed: {}", &peer, e.to_string());
+            return;
+        }
+    };
+    let pending = Arc::new(AtomicUsize::new(0));
+    info!("session from {} start", &peer);
+    loop {
+        let mut request_id = [0u8; 4];
+        if let Err(e) = tcp.read_exact(&mut request_id) {
+            info!("session from {} closed: {}", &peer, e.to_string());
+            return;
+        }
+        let buf = match readvalue::rec_packet_limited(&mut tcp, conf.max_packet_size) {
+            Ok(v) => v,
+            Err(e) => {
+                info!("session from {} closed: {}", &peer, e.to_string());
+                return;
+            }
+        };
+
+        if pending.fetch_add(1, Ordering::SeqCst) >= SESSION_PENDING {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            let mut response = request_id.to_vec();
+            let state = mysql::send_error_packet(&ReponseErr::new(String::from("too many pending requests in session")), &mut response)
+                .and_then(|_| Ok(writer.lock().unwrap().write_all(&response)?));
+            if let Err(e) = state {
+                info!("session from {} closed: {}", &peer, e.to_string());
+                return;
+            }
+            continue;
+        }
+
+        let (conf, state, last_check_time, slowlog_state, audit_log, cdc_log, writer, pending) = (Arc::clone(&conf), Arc::clone(&state), Arc::clone(&last_check_time),
+            Arc::clone(&slowlog_state), Arc::clone(&audit_log), Arc::clone(&cdc_log), Arc::clone(&writer), Arc::clone(&pending));
+        workers.lock().unwrap().execute(move|| {
+            //handler把响应写入缓存，完成后带上request id一次写入连接
+            let mut response = request_id.to_vec();
+            let result = match mysql::MyProtocol::new(&buf[0]) {
+                mysql::MyProtocol::Ping => mysql::state_check::ping(&mut response, &state, start_time),
+                mysql::MyProtocol::MysqlCheck => mysql::state_check::mysql_state_check(&mut response, &state, &last_check_time),
+                mysql::MyProtocol::ReplicationStatus => mysql::state_check::replication_status(&mut response, &conf),
+                mysql::MyProtocol::GetMonitor => mysql::monitor::mysql_monitor(&mut response, &conf),
+                mysql::MyProtocol::GetSlowLog => mysql::slowlog::get_slow_log(&mut response, &conf, &slowlog_state, &buf),
+                mysql::MyProtocol::GetAuditLog => mysql::audit::get_audit_log(&mut response, &conf, &audit_log, &buf),
+                mysql::MyProtocol::GetCdcRecords => mysql::cdc::get_cdc_records(&mut response, &conf, &cdc_log, &buf),
+                _ => Err(format!("type_code {} is not supported in session", buf[0]).into())
+            };
+            pending.fetch_sub(1, Ordering::SeqCst);
+            if let Err(e) = result {
+                info!("{}", e.to_string());
+                response.truncate(4);
+                if let Err(e) = mysql::send_error_packet(&ReponseErr::new(e.to_string()), &mut response) {
+                    info!("{}", e.to_string());
+                    return;
+                }
+            }
+            let mut writer = writer.lock().unwrap();
+            if let Err(e) = writer.write_all(&response) {
+                //写入失败时关闭连接，读取请求的循环随之退出
+                info!("session write failed: {}", e.to_string());
+                let _ = writer.shutdown(std::net::Shutdown::Both);
+            }
+        });
+    }
+}
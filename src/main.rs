@@ -8,7 +8,15 @@ use structopt::StructOpt;
 //}
 
 fn main() {
-    let args = mymha_client::Opt::from_args();
+    let mut args = mymha_client::Opt::from_args();
+    //带子命令时离线解析binlog，不启动服务
+    if let Some(cmd) = args.cmd.take() {
+        if let Err(e) = mymha_client::cli::run(cmd) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     let conf = mymha_client::Config::new(args).unwrap_or_else(|err|{
         println!("Problem parsing arguments: {}", err);
         std::process::exit(1);
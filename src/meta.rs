@@ -5,9 +5,13 @@
 
 use std::collections::HashMap;
 use crate::{io, Config};
-use serde_json::from_str;
 use std::error::Error;
-use serde::Serialize;
+use std::fs;
+use serde::{Serialize, Deserialize};
+use crate::binlog::readevent::{TableMap, ColumnInfo};
+use crate::io::command::{Row, FromRow};
+use crate::io::stream::MysqlStream;
+use std::sync::{Arc, Mutex};
 //use lazy_static;
 //
 //lazy_static!{
@@ -31,6 +35,8 @@ pub struct FlagsMeta{
     pub protocol_41: i32,
     pub transactions: i32,
     pub client_connect_with_db: i32,
+    pub client_ssl: i32,
+    pub client_found_rows: i32,
 }
 
 impl FlagsMeta {
@@ -46,7 +52,9 @@ impl FlagsMeta {
             long_flag: 1 << 2,
             protocol_41: 1 << 9,
             transactions: 1 << 13,
-            client_connect_with_db: 9
+            client_connect_with_db: 9,
+            client_ssl: 1 << 11,
+            client_found_rows: 1 << 1
         }
     }
 }
@@ -78,7 +86,7 @@ pub enum PackType {
     ComStmtReset,
     ComStmtSendLongData,
 }
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum  ColumnTypeDict{
     MysqlTypeDecimal,
     MysqlTypeTiny,
@@ -205,35 +213,632 @@ impl JsonType {
     }
 }
 
-pub fn get_col(conf: &Config, db: &String, tb: &String, table_cols_info: &mut HashMap<String, Vec<HashMap<String, String>>>) -> Result<(), Box<dyn Error>> {
-    let db_tbl = format!("{}.{}",db,tb);
-    match table_cols_info.get(&db_tbl) {
-        None => {
-            let mut conn = crate::create_conn(conf)?;
-            let sql = format!("select COLUMN_NAME,COLUMN_TYPE,COLUMN_KEY from information_schema.columns where table_schema = '{}' and table_name='{}'  order by ORDINAL_POSITION ;", db, tb);
-            let values = io::command::execute(&mut conn,&sql)?;
-            //println!("{:?}",values);
-            if values.len() > 0 {
-                table_cols_info.insert(db_tbl,values);
+///
+/// information_schema.columns中的字段信息
+///     也可由table map event的optional metadata生成，并保存在本地schema缓存文件中
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnMeta {
+    pub column_name: String,
+    pub column_type: String,
+    pub column_key: String,
+    pub character_set: Option<String>,      //字符类型字段的字符集，其他类型为NULL
+    pub is_nullable: bool,
+    pub key_ordinal: Option<usize>,         //在用于定位行的索引中的位置，不在该索引中为None
+    #[serde(default)]
+    pub is_unsigned: bool,
+}
+
+impl FromRow for ColumnMeta {
+    fn from_row(row: &Row) -> Result<ColumnMeta, Box<dyn Error>> {
+        let nullable: String = row.take("IS_NULLABLE")?;
+        let column_type: String = row.take("COLUMN_TYPE")?;
+        Ok(ColumnMeta{
+            column_name: row.take("COLUMN_NAME")?,
+            is_unsigned: column_type.contains("unsigned"),
+            column_type,
+            column_key: row.take("COLUMN_KEY")?,
+            character_set: row.take("CHARACTER_SET_NAME")?,
+            is_nullable: nullable == "YES",
+            key_ordinal: None
+        })
+    }
+}
+
+///
+/// information_schema.statistics中的索引信息
+///
+#[derive(Debug, Clone)]
+pub struct IndexColumn {
+    pub index_name: String,
+    pub column_name: String,
+    pub non_unique: usize,
+}
+
+impl FromRow for IndexColumn {
+    fn from_row(row: &Row) -> Result<IndexColumn, Box<dyn Error>> {
+        Ok(IndexColumn{
+            index_name: row.take("INDEX_NAME")?,
+            column_name: row.take("COLUMN_NAME")?,
+            non_unique: row.take("NON_UNIQUE")?
+        })
+    }
+}
+
+///
+/// 选择生成update/delete语句where条件使用的索引
+///     1. 主键
+///     2. 所有字段都为not null的唯一索引，字段数少的优先
+///     都没有时返回空，由调用方使用全部字段匹配
+///
+fn get_row_key(cols: &Vec<ColumnMeta>, indexes: &Vec<IndexColumn>) -> Vec<String> {
+    let mut unique: Vec<(String, Vec<String>)> = vec![];
+    for idx in indexes {
+        if idx.non_unique != 0 {
+            continue;
+        }
+        match unique.iter_mut().find(|(name, _)| name == &idx.index_name) {
+            Some((_, columns)) => columns.push(idx.column_name.clone()),
+            None => unique.push((idx.index_name.clone(), vec![idx.column_name.clone()]))
+        }
+    }
+    if let Some((_, columns)) = unique.iter().find(|(name, _)| name == "PRIMARY") {
+        return columns.clone();
+    }
+
+    let not_null = |name: &String| cols.iter().any(|c| &c.column_name == name && !c.is_nullable);
+    let mut candidates: Vec<&Vec<String>> = unique.iter()
+        .map(|(_, columns)| columns)
+        .filter(|columns| columns.iter().all(|c| not_null(c)))
+        .collect();
+    candidates.sort_by_key(|columns| columns.len());
+    match candidates.first() {
+        Some(columns) => columns.to_vec(),
+        None => vec![]
+    }
+}
+
+impl ColumnMeta {
+    ///
+    /// 根据table map中的字段类型生成与information_schema中column_type类似的类型名称
+    ///     getsql中根据是否包含text/char判断是否为字符类型, enum/set带有取值列表时输出为enum('a','b')
+    ///
+    fn type_name(info: &ColumnInfo) -> String {
+        let binary = info.collation == Some(63);
+        let name = match info.column_type {
+            ColumnTypeDict::MysqlTypeTiny => "tinyint",
+            ColumnTypeDict::MysqlTypeShort => "smallint",
+            ColumnTypeDict::MysqlTypeInt24 => "mediumint",
+            ColumnTypeDict::MysqlTypeLong => "int",
+            ColumnTypeDict::MysqlTypeLonglong => "bigint",
+            ColumnTypeDict::MysqlTypeFloat => "float",
+            ColumnTypeDict::MysqlTypeDouble => "double",
+            ColumnTypeDict::MysqlTypeDecimal |
+            ColumnTypeDict::MysqlTypeNewdecimal => "decimal",
+            ColumnTypeDict::MysqlTypeYear => "year",
+            ColumnTypeDict::MysqlTypeDate |
+            ColumnTypeDict::MysqlTypeNewdate => "date",
+            ColumnTypeDict::MysqlTypeTime |
+            ColumnTypeDict::MysqlTypeTime2 => "time",
+            ColumnTypeDict::MysqlTypeDatetime |
+            ColumnTypeDict::MysqlTypeDatetime2 => "datetime",
+            ColumnTypeDict::MysqlTypeTimestamp |
+            ColumnTypeDict::MysqlTypeTimestamp2 => "timestamp",
+            ColumnTypeDict::MysqlTypeBit => "bit",
+            ColumnTypeDict::MysqlTypeJson => "json",
+            ColumnTypeDict::MysqlTypeEnum => "enum",
+            ColumnTypeDict::MysqlTypeSet => "set",
+            ColumnTypeDict::MysqlTypeGeometry => "geometry",
+            ColumnTypeDict::MysqlTypeVarchar |
+            ColumnTypeDict::MysqlTypeVarString => if binary { "varbinary" } else { "varchar" },
+            ColumnTypeDict::MysqlTypeString => if binary { "binary" } else { "char" },
+            ColumnTypeDict::MysqlTypeTinyBlob => if binary { "tinyblob" } else { "tinytext" },
+            ColumnTypeDict::MysqlTypeBlob => if binary { "blob" } else { "text" },
+            ColumnTypeDict::MysqlTypeMediumBlob => if binary { "mediumblob" } else { "mediumtext" },
+            ColumnTypeDict::MysqlTypeLongBlob => if binary { "longblob" } else { "longtext" },
+            _ => "unknown"
+        };
+        if info.members.len() > 0 {
+            let members: Vec<String> = info.members.iter().map(|m| format!("'{}'", m.replace("'", "''"))).collect();
+            return format!("{}({})", name, members.join(","));
+        }
+        if info.unsigned {
+            format!("{} unsigned", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    ///
+    /// 由table map的optional metadata生成字段信息，需要binlog_row_metadata=FULL(带有字段名)
+    ///     optional metadata中只有主键信息，没有主键时由get_table_meta补充唯一索引
+    ///
+    pub fn from_table_map(map: &TableMap) -> Option<Vec<ColumnMeta>> {
+        if !map.has_column_name() {
+            return None;
+        }
+        let mut cols = vec![];
+        for (idx, info) in map.column_info.iter().enumerate() {
+            let key_ordinal = map.primary_key.iter().position(|k| *k == idx);
+            cols.push(ColumnMeta{
+                column_name: info.name.clone().unwrap_or_default(),
+                column_type: Self::type_name(info),
+                column_key: if key_ordinal.is_some() { "PRI".to_string() } else { "".to_string() },
+                character_set: match info.collation {
+                    Some(c) => charset_from_collation(c),
+                    None => None
+                },
+                is_nullable: info.nullable,
+                key_ordinal,
+                is_unsigned: info.unsigned
+            });
+        }
+        Some(cols)
+    }
+}
+
+///
+/// 比较table map中的字段类型与缓存中的表结构是否一致
+///     table map中没有字段的字符集时无法区分char/binary、text/blob等，字符类型只比较是否都为字符类型
+///
+fn type_family(column_type: &str) -> &str {
+    let name = column_type.split(|c| c == '(' || c == ' ').next().unwrap_or("");
+    match name {
+        "char" | "varchar" | "binary" | "varbinary" | "enum" | "set" |
+        "tinytext" | "text" | "mediumtext" | "longtext" |
+        "tinyblob" | "blob" | "mediumblob" | "longblob" => "string",
+        "point" | "linestring" | "polygon" | "multipoint" | "multilinestring" |
+        "multipolygon" | "geometrycollection" | "geomcollection" => "geometry",
+        _ => name
+    }
+}
+
+fn match_table_map(cols: &Vec<ColumnMeta>, map: &TableMap) -> bool {
+    if cols.len() != map.column_info.len() {
+        return false;
+    }
+    cols.iter().zip(map.column_info.iter()).all(|(col, info)| {
+        let map_type = ColumnMeta::type_name(info);
+        map_type == "unknown" || type_family(&map_type) == type_family(&col.column_type)
+    })
+}
+
+///
+/// collation id对应的字符集，只包含常用的字符集，不认识的返回None(生成sql时使用16进制)
+///
+pub fn charset_from_collation(collation: usize) -> Option<String> {
+    let name = match collation {
+        63 => "binary",
+        33 | 76 | 83 | 192..=215 | 223 => "utf8",
+        45 | 46 | 224..=247 | 255..=323 => "utf8mb4",
+        5 | 8 | 15 | 31 | 47 | 48 | 49 | 94 => "latin1",
+        11 | 65 => "ascii",
+        28 | 87 => "gbk",
+        24 | 86 => "gb2312",
+        1 | 84 => "big5",
+        248 | 249 => "gb18030",
+        _ => return None
+    };
+    Some(name.to_string())
+}
+
+///
+/// binlog中的位置，先比较文件名再比较偏移量
+///     binlog为空表示位置未知(如服务端推送的binlog片段)
+///
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BinlogPos {
+    pub binlog: String,
+    pub position: u64,
+}
+
+impl BinlogPos {
+    pub fn new(binlog: &String, position: u64) -> BinlogPos {
+        BinlogPos{ binlog: binlog.clone(), position }
+    }
+}
+
+///
+/// 某个位置之后生效的表结构
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaVersion {
+    pub since: BinlogPos,
+    pub columns: Vec<ColumnMeta>,
+}
+
+///
+/// 持久化的表结构缓存，所有binlog解析共用
+///     每个表按binlog位置保存多个版本，解析row event时使用该位置生效的版本
+///     解析到ddl时标记涉及的表，之后的row event重新从information_schema获取表结构
+///     mysql无法连接时使用缓存中的版本
+///
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SchemaCache {
+    #[serde(skip)]
+    path: String,
+    tables: HashMap<String, Vec<SchemaVersion>>,
+    stale: HashMap<String, BinlogPos>,      //ddl位置，该位置之后需要重新获取表结构
+    #[serde(skip)]
+    read_only: bool,                        //只读时获取的表结构只保存在内存中，不写回文件
+    #[serde(skip)]
+    dirty: bool,                            //有未写入文件的修改，由flush统一写入
+}
+
+impl SchemaCache {
+    pub fn new(path: &String) -> SchemaCache {
+        let mut cache: SchemaCache = match fs::read(path) {
+            Ok(v) => serde_json::from_slice(&v).unwrap_or_default(),
+            Err(_) => SchemaCache::default()
+        };
+        cache.path = path.clone();
+        cache
+    }
+
+    ///
+    /// 离线解析使用，读取缓存文件但不修改
+    ///
+    pub fn new_read_only(path: &String) -> SchemaCache {
+        let mut cache = SchemaCache::new(path);
+        cache.read_only = true;
+        cache
+    }
+
+    ///
+    /// 把修改写入缓存文件，insert/on_ddl只修改内存，每次解析结束时调用一次
+    ///
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.dirty || self.path.len() == 0 || self.read_only {
+            return Ok(());
+        }
+        //先写临时文件再重命名，避免写入过程中退出导致文件不完整
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, serde_json::to_vec(&self)?)?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    ///
+    /// 获取在pos位置生效的表结构
+    ///     早于第一个版本的位置使用第一个版本，位置未知时使用最新的版本
+    ///
+    pub fn get(&self, db_tbl: &String, pos: &BinlogPos) -> Option<&Vec<ColumnMeta>> {
+        let versions = self.tables.get(db_tbl)?;
+        if pos.binlog.len() == 0 {
+            return versions.last().map(|v| &v.columns);
+        }
+        match versions.iter().rev().find(|v| &v.since <= pos) {
+            Some(v) => Some(&v.columns),
+            None => versions.first().map(|v| &v.columns)
+        }
+    }
+
+    ///
+    /// pos位置是否有确定生效的版本，早于所有版本时get返回的是之后的表结构，需要与table map核对
+    ///
+    pub fn is_verified(&self, db_tbl: &String, pos: &BinlogPos) -> bool {
+        if pos.binlog.len() == 0 {
+            return true;
+        }
+        match self.tables.get(db_tbl) {
+            Some(versions) => versions.iter().any(|v| &v.since <= pos),
+            None => false
+        }
+    }
+
+    pub fn insert(&mut self, db_tbl: &String, since: BinlogPos, columns: Vec<ColumnMeta>) -> Result<(), Box<dyn Error>> {
+        let versions = self.tables.entry(db_tbl.clone()).or_insert(vec![]);
+        versions.retain(|v| v.since != since);
+        versions.push(SchemaVersion{ since, columns });
+        versions.sort_by(|a, b| a.since.cmp(&b.since));
+        self.stale.remove(db_tbl);
+        self.dirty = true;
+        Ok(())
+    }
+
+    ///
+    /// 表结构是否在pos之前被ddl修改过并且还没有获取新的版本，返回ddl的位置
+    ///
+    pub fn stale_since(&self, db_tbl: &String, pos: &BinlogPos) -> Option<BinlogPos> {
+        let ddl_pos = self.stale.get(db_tbl)?;
+        if pos.binlog.len() > 0 && ddl_pos > pos {
+            return None;
+        }
+        match self.tables.get(db_tbl).and_then(|v| v.last()) {
+            Some(v) if &v.since >= ddl_pos => None,
+            _ => Some(ddl_pos.clone())
+        }
+    }
+
+    ///
+    /// 解析到ddl时调用，无法确定涉及的表时标记该库下所有已缓存的表
+    ///
+    pub fn on_ddl(&mut self, database: &String, command: &String, pos: &BinlogPos) -> Result<(), Box<dyn Error>> {
+        let tables = match ddl_tables(database, command) {
+            Some(v) => v,
+            None => {
+                if !is_ddl(command) {
+                    return Ok(());
+                }
+                let prefix = format!("{}.", database);
+                self.tables.keys().filter(|k| k.starts_with(&prefix)).cloned().collect()
             }
+        };
+        if tables.len() == 0 {
+            return Ok(());
+        }
+        for db_tbl in tables {
+            info!("table {} changed by ddl at {}:{}", db_tbl, pos.binlog, pos.position);
+            self.stale.insert(db_tbl, pos.clone());
         }
-        Some(_) => {}
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+fn is_ddl(command: &String) -> bool {
+    let first = command.trim_start().split_whitespace().next().unwrap_or("").to_uppercase();
+    match first.as_str() {
+        "ALTER" | "CREATE" | "DROP" | "RENAME" => true,
+        _ => false
     }
+}
+
+///
+/// 把sql拆分为单词，反引号内为一个单词，逗号单独作为一个单词
+///
+fn ddl_tokens(command: &String) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut cur = String::new();
+    let mut quoted = false;
+    for c in command.chars() {
+        if quoted {
+            if c == '`' {
+                quoted = false;
+            } else {
+                cur.push(c);
+            }
+            continue;
+        }
+        match c {
+            '`' => quoted = true,
+            ',' | ';' | '(' => {
+                if cur.len() > 0 {
+                    tokens.push(cur.clone());
+                    cur.clear();
+                }
+                if c != ';' {
+                    tokens.push(c.to_string());
+                }
+            }
+            c if c.is_whitespace() => {
+                if cur.len() > 0 {
+                    tokens.push(cur.clone());
+                    cur.clear();
+                }
+            }
+            _ => cur.push(c),
+        }
+    }
+    if cur.len() > 0 {
+        tokens.push(cur);
+    }
+    tokens
+}
+
+///
+/// 获取alter/create/drop/rename table涉及的表, 返回库名.表名
+///     其他ddl(create index等)返回None
+///
+fn ddl_tables(database: &String, command: &String) -> Option<Vec<String>> {
+    let tokens = ddl_tokens(command);
+    let upper: Vec<String> = tokens.iter().map(|t| t.to_uppercase()).collect();
+    let full_name = |name: &String| -> String {
+        if name.contains('.') { name.clone() } else { format!("{}.{}", database, name) }
+    };
+    let mut idx = 1;
+    //跳过create temporary table
+    while idx < upper.len() && upper[idx] != "TABLE" {
+        if upper[0] == "ALTER" && (upper[idx] == "ONLINE" || upper[idx] == "IGNORE") {
+            idx += 1;
+            continue;
+        }
+        if (upper[0] == "CREATE" || upper[0] == "DROP") && upper[idx] == "TEMPORARY" {
+            idx += 1;
+            continue;
+        }
+        return None;
+    }
+    idx += 1;
+    if idx >= upper.len() {
+        return None;
+    }
+    match upper[0].as_str() {
+        "ALTER" => Some(vec![full_name(&tokens[idx])]),
+        "CREATE" => {
+            if upper.len() > idx + 2 && upper[idx] == "IF" && upper[idx + 1] == "NOT" && upper[idx + 2] == "EXISTS" {
+                idx += 3;
+            }
+            tokens.get(idx).map(|t| vec![full_name(t)])
+        }
+        "DROP" | "RENAME" => {
+            if upper[0] == "DROP" && upper.len() > idx + 1 && upper[idx] == "IF" && upper[idx + 1] == "EXISTS" {
+                idx += 2;
+            }
+            let mut tables = vec![];
+            for (i, t) in tokens.iter().enumerate().skip(idx) {
+                let u = &upper[i];
+                if t == "," || u == "TO" {
+                    continue;
+                }
+                if u == "RESTRICT" || u == "CASCADE" {
+                    break;
+                }
+                tables.push(full_name(t));
+            }
+            Some(tables)
+        }
+        _ => None
+    }
+}
+
+///
+/// 获取table map对应表在pos位置的字段信息，结果放入table_cols_info供生成sql使用
+///     1. table map中带有字段名时直接使用，不需要连接mysql，没有主键时从缓存或information_schema获取not null的唯一索引，都获取不到时使用全部字段匹配
+///     2. schema缓存中没有或者被ddl修改过时从information_schema获取，并保存到缓存
+///         information_schema中为当前的表结构，ddl之后获取的从ddl位置开始生效，否则从mysql当前的binlog位置开始生效
+///     3. 无法连接mysql时使用缓存中的版本
+///     pos早于所有缓存的版本时只能使用之后的版本，字段类型与table map不一致时报错
+///     conn为本次解析共用的连接，第一次需要时创建，获取表结构时不持有缓存的锁
+///
+pub fn get_table_meta(conf: &Config, conn: &mut Option<MysqlStream>, schema: &Arc<Mutex<SchemaCache>>,
+                      map: &TableMap, pos: &BinlogPos, table_cols_info: &mut HashMap<String, Vec<ColumnMeta>>) -> Result<(), Box<dyn Error>> {
+    let db_tbl = format!("{}.{}", map.database_name, map.table_name);
+    if let Some(mut cols) = ColumnMeta::from_table_map(map) {
+        if cols.iter().all(|c| c.key_ordinal.is_none()) {
+            //本次解析中已经获取过时直接使用，避免mysql无法连接时每个event都重试
+            let known = match table_cols_info.get(&db_tbl) {
+                Some(v) if v.iter().map(|c| &c.column_name).eq(cols.iter().map(|c| &c.column_name)) => Some(v.clone()),
+                _ => None
+            };
+            let key_cols = match known {
+                Some(v) => Ok(v),
+                None => schema_columns(conf, conn, schema, map, pos, &db_tbl)
+            };
+            match key_cols {
+                Ok(key_cols) => {
+                    for col in cols.iter_mut() {
+                        col.key_ordinal = key_cols.iter().find(|c| c.column_name == col.column_name).and_then(|c| c.key_ordinal);
+                    }
+                }
+                Err(e) => info!("get unique key of {} error: {}, use all columns", db_tbl, e)
+            }
+        }
+        table_cols_info.insert(db_tbl, cols);
+        return Ok(());
+    }
+
+    let cols = schema_columns(conf, conn, schema, map, pos, &db_tbl)?;
+    table_cols_info.insert(db_tbl, cols);
     Ok(())
 }
 
-pub fn get_version(conf: &Config) -> Result<u8, Box<dyn Error>> {
-    let mut conn = crate::create_conn(conf)?;
-    let sql = String::from("select @@version;");
-    let mut v = 0 as u8;
-    let values = crate::io::command::execute(&mut conn,&sql)?;
-    for row in values.iter(){
-        for (_, value) in row{
-            let a: Vec<&str> = value.split(".").collect();
-            v = from_str::<u8>(a[0]).unwrap();
+///
+/// 从缓存或information_schema获取表结构
+///
+fn schema_columns(conf: &Config, conn: &mut Option<MysqlStream>, schema: &Arc<Mutex<SchemaCache>>,
+                  map: &TableMap, pos: &BinlogPos, db_tbl: &String) -> Result<Vec<ColumnMeta>, Box<dyn Error>> {
+    let (stale, cached) = {
+        let cache = schema.lock().unwrap();
+        (cache.stale_since(db_tbl, pos), cache.get(db_tbl, pos).is_some())
+    };
+    //binlog未开启等无法确定生效位置时只在本次使用，不保存到缓存
+    let mut fetched = None;
+    if stale.is_some() || !cached {
+        match get_col_with_pos(conf, conn, &map.database_name, &map.table_name) {
+            Ok((cols, server_pos)) => {
+                if cols.len() > 0 {
+                    match stale.or(server_pos) {
+                        Some(since) => schema.lock().unwrap().insert(db_tbl, since, cols)?,
+                        None => fetched = Some(cols)
+                    }
+                }
+            }
+            Err(e) => {
+                *conn = None;
+                if !cached {
+                    return Err(e);
+                }
+                info!("get columns of {} error: {}, use schema cache", db_tbl, e);
+            }
+        }
+    }
+
+    let (cols, verified) = match fetched {
+        Some(v) => (v, false),
+        None => {
+            let cache = schema.lock().unwrap();
+            match cache.get(db_tbl, pos) {
+                Some(v) => (v.clone(), cache.is_verified(db_tbl, pos)),
+                None => return Err(format!("no columns info: {}", db_tbl).into())
+            }
+        }
+    };
+    if cols.len() != map.column_info.len() {
+        return Err(format!("columns of {} do not match table map, {} columns in schema, {} in binlog", db_tbl, cols.len(), map.column_info.len()).into());
+    }
+    if !verified {
+        if !match_table_map(&cols, map) {
+            return Err(format!("columns of {} at {}:{} are unknown, the schema cache only has later versions whose column types do not match table map", db_tbl, pos.binlog, pos.position).into());
+        }
+        info!("columns of {} at {}:{} are not verified, use a later version with the same column types", db_tbl, pos.binlog, pos.position);
+    }
+    Ok(cols)
+}
+
+///
+/// 获取表结构及mysql当前的binlog位置，先获取位置，之后的ddl会在解析到时重新获取
+///
+fn get_col_with_pos(conf: &Config, conn: &mut Option<MysqlStream>, db: &String, tb: &String) -> Result<(Vec<ColumnMeta>, Option<BinlogPos>), Box<dyn Error>> {
+    if conn.is_none() {
+        *conn = Some(crate::create_conn(conf)?);
+    }
+    let result = io::command::execute(conn.as_mut().unwrap(), &String::from("show master status"))?;
+    let mut server_pos = None;
+    if result.len() > 0 {
+        if let (Some(binlog), Some(position)) = (result[0].get("File"), result[0].get("Position")) {
+            server_pos = Some(BinlogPos::new(binlog, position.parse()?));
+        }
+    }
+    let cols = get_col(conf, conn, db, tb)?;
+    Ok((cols, server_pos))
+}
+
+pub fn get_col(conf: &Config, conn: &mut Option<MysqlStream>, db: &String, tb: &String) -> Result<Vec<ColumnMeta>, Box<dyn Error>> {
+    if conn.is_none() {
+        *conn = Some(crate::create_conn(conf)?);
+    }
+    let conn = conn.as_mut().unwrap();
+    let sql = String::from("select COLUMN_NAME,COLUMN_TYPE,COLUMN_KEY,CHARACTER_SET_NAME,IS_NULLABLE from information_schema.columns where table_schema = CONVERT(? USING utf8mb4) and table_name = CONVERT(? USING utf8mb4) order by ORDINAL_POSITION");
+    let params = vec![io::command::Param::Bytes(db.as_bytes().to_vec()), io::command::Param::Bytes(tb.as_bytes().to_vec())];
+    let mut values: Vec<ColumnMeta> = io::command::query_params(conn, &sql, &params)?;
+    //println!("{:?}",values);
+
+    let sql = String::from("select INDEX_NAME,COLUMN_NAME,NON_UNIQUE from information_schema.statistics where table_schema = CONVERT(? USING utf8mb4) and table_name = CONVERT(? USING utf8mb4) order by INDEX_NAME,SEQ_IN_INDEX");
+    let indexes: Vec<IndexColumn> = io::command::query_params(conn, &sql, &params)?;
+    for (ordinal, name) in get_row_key(&values, &indexes).iter().enumerate() {
+        if let Some(col) = values.iter_mut().find(|c| &c.column_name == name) {
+            col.key_ordinal = Some(ordinal);
+        }
+    }
+    Ok(values)
+}
+
+///
+/// gtid集合格式: uuid:1-10:15,uuid2:3，返回每个uuid对应的区间(包含两端)
+///
+pub fn parse_gtid_set(gtids: &String) -> Result<HashMap<String, Vec<(u64, u64)>>, Box<dyn Error>> {
+    let mut set: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    for item in gtids.replace("\n", "").split(",") {
+        let item = item.trim();
+        if item.len() == 0 {
+            continue;
+        }
+        let mut parts = item.split(":");
+        let sid = parts.next().unwrap_or("").trim().to_lowercase();
+        let ranges = set.entry(sid).or_insert(vec![]);
+        for range in parts {
+            let mut r = range.trim().splitn(2, "-");
+            let start: u64 = r.next().unwrap_or("").parse().map_err(|_| format!("invalid gtid set: {}", gtids))?;
+            let end: u64 = match r.next() {
+                Some(v) => v.parse().map_err(|_| format!("invalid gtid set: {}", gtids))?,
+                None => start
+            };
+            ranges.push((start, end));
         }
     }
-    Ok(v)
+    Ok(set)
 }
 
 pub enum ReadType {
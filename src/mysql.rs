@@ -3,13 +3,15 @@
 @datetime: 2019/11/11
 */
 
-use std::net::{TcpStream, IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::io::{Write, Read};
 
 pub mod state_check;
 pub mod monitor;
 pub mod slowlog;
 pub mod audit;
+pub mod cdc;
+pub mod auth;
 pub mod syncbinlog;
 pub mod setmaster;
 pub mod changemaster;
@@ -19,6 +21,7 @@ pub mod push_sql;
 use serde::{Serialize, Deserialize};
 use std::error::Error;
 use std::time::Duration;
+use crate::io::stream::MysqlStream;
 
 #[derive(Debug, Serialize)]
 pub enum  MyProtocol {
@@ -26,6 +29,7 @@ pub enum  MyProtocol {
     GetMonitor,
     GetSlowLog,
     GetAuditLog,
+    GetCdcRecords,      //获取cdc变更记录
     SetMaster,          //设置本机为新master
     ChangeMaster,
     PullBinlog,         //mysql服务宕机，拉取宕机节点差异binlog
@@ -39,6 +43,8 @@ pub enum  MyProtocol {
     SetVariables,
     RecoveryVariables,
     Command,            //执行追加sql
+    Session,            //建立长连接会话，之后的请求都带有request id
+    Auth,               //连接验证
     Ok,
     Error,
     UnKnow
@@ -79,10 +85,16 @@ impl MyProtocol {
             return MyProtocol::RecoveryVariables;
         }else if code == &0xf2 {
             return MyProtocol::PushBinlog;
+        }else if code == &0xf1 {
+            return MyProtocol::GetCdcRecords;
         }else if code == &0x01 {
             return MyProtocol::Ping;
         }else if code == &0x05 {
             return MyProtocol::Command;
+        }else if code == &0x06 {
+            return MyProtocol::Session;
+        }else if code == &0x07 {
+            return MyProtocol::Auth;
         }
         else {
             return MyProtocol::UnKnow;
@@ -95,6 +107,7 @@ impl MyProtocol {
             MyProtocol::GetMonitor => 0xfd,
             MyProtocol::GetSlowLog => 0xfc,
             MyProtocol::GetAuditLog => 0xfb,
+            MyProtocol::GetCdcRecords => 0xf1,
             MyProtocol::SetMaster => 0xfa,
             MyProtocol::ChangeMaster => 0xf9,
             MyProtocol::PullBinlog => 0xf8,
@@ -110,6 +123,8 @@ impl MyProtocol {
             MyProtocol::RecoveryVariables => 0x03,
             MyProtocol::Ping => 0x01,
             MyProtocol::Command => 0x05,
+            MyProtocol::Session => 0x06,
+            MyProtocol::Auth => 0x07,
             MyProtocol::UnKnow => 0xff
         }
     }
@@ -141,30 +156,33 @@ impl ReponseErr {
     }
 }
 
-pub fn send_error_packet(value: &ReponseErr, mut tcp: &TcpStream) -> Result<(), Box<dyn Error>> {
+pub fn send_error_packet<W: Write>(value: &ReponseErr, mut tcp: W) -> Result<(), Box<dyn Error>> {
     let value = serde_json::to_string(value)?;
     let mut buf = header(0x09, value.len() as u64);
     buf.extend(value.as_bytes());
-    tcp.write(buf.as_ref())?;
+    tcp.write_all(buf.as_ref())?;
     tcp.flush()?;
     Ok(())
 }
 
-pub fn send_ok_packet(mut tcp: &TcpStream) -> Result<(), std::io::Error> {
+pub fn send_ok_packet<W: Write>(mut tcp: W) -> Result<(), std::io::Error> {
     let null_packet = Null::new();
     let value = serde_json::to_string(&null_packet)?;
     let mut buf = header(MyProtocol::Ok.get_code(), value.len() as u64);
     buf.extend(value.as_bytes());
-    tcp.write(&buf)?;
+    tcp.write_all(&buf)?;
     tcp.flush()?;
     Ok(())
 }
 
-pub fn send_value_packet<T: Serialize>(mut tcp: &TcpStream, value: &T, type_code: MyProtocol) -> Result<(), Box<dyn Error>> {
+///
+/// tcp为TcpStream或会话模式中缓存响应的Vec<u8>
+///
+pub fn send_value_packet<W: Write, T: Serialize>(mut tcp: W, value: &T, type_code: MyProtocol) -> Result<(), Box<dyn Error>> {
     let value = serde_json::to_string(value)?;
     let mut buf = header(type_code.get_code(), value.len() as u64);
     buf.extend(value.as_bytes());
-    tcp.write(&buf)?;
+    tcp.write_all(&buf)?;
     tcp.flush()?;
     Ok(())
 }
@@ -200,7 +218,7 @@ fn header(code: u8, payload: u64) -> Vec<u8> {
 
 
 
-pub fn set_readonly(tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+pub fn set_readonly(tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
     info!("set readonly variables....");
     let set_read_only = String::from("set global read_only=1;");
     let set_no_super_read_only = String::from("set global super_read_only=0;");
@@ -217,7 +235,7 @@ pub fn set_readonly(tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn set_no_readonly(tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+pub fn set_no_readonly(tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
     info!("set variables...");
     let set_read_only = String::from("set global read_only=0;");
     let set_sync_binlog = String::from("set global sync_binlog=1;");
@@ -241,24 +259,65 @@ pub fn check_state(state: &Result<(), Box<dyn Error>>) {
     }
 }
 
+///
+/// 拆分host和port
+///     支持ip:port、hostname:port以及ipv6的[::1]:port格式
+///     返回的host不带中括号
+pub fn split_host(host_info: &str) -> Result<(String, u16), Box<dyn Error>> {
+    let host_info = host_info.trim();
+    let (host, port) = if host_info.starts_with("[") {
+        match host_info.find("]:") {
+            Some(idx) => (&host_info[1..idx], &host_info[idx + 2..]),
+            None => return Err(format!("invalid host info: {}, ipv6 address must be [ip]:port", host_info).into())
+        }
+    } else {
+        match host_info.rfind(":") {
+            Some(idx) if host_info[..idx].find(":").is_none() => (&host_info[..idx], &host_info[idx + 1..]),
+            _ => return Err(format!("invalid host info: {}, must be host:port or [ip]:port", host_info).into())
+        }
+    };
+    if host.len() == 0 {
+        return Err(format!("invalid host info: {}", host_info).into());
+    }
+    let port = port.parse::<u16>()?;
+    Ok((host.to_string(), port))
+}
+
+///
+/// 拼接host和port, ipv6地址使用中括号
+pub fn format_host(host: &str, port: usize) -> String {
+    if host.contains(":") {
+        return format!("[{}]:{}", host, port);
+    }
+    format!("{}:{}", host, port)
+}
+
+///
+/// 去掉ipv6地址的中括号，用于change master等需要单独host的地方
+pub fn strip_host(host: &str) -> String {
+    host.trim().trim_start_matches("[").trim_end_matches("]").to_string()
+}
+
 ///
 /// 建立socket连接
+///     hostname解析出多个地址时依次尝试
 pub fn conn(host_info: &str) -> Result<TcpStream, Box<dyn Error>> {
-    let host_info = host_info.split(":");
-    let host_vec = host_info.collect::<Vec<&str>>();
-    let port = host_vec[1].to_string().parse::<u16>()?;
-    let ip_vec = host_vec[0].split(".");
-    let ip_vec = ip_vec.collect::<Vec<&str>>();
-    let mut ip_info = vec![];
-    for i in ip_vec{
-        ip_info.push(i.to_string().parse::<u8>()?);
+    let (host, port) = split_host(host_info)?;
+    let addrs = (host.as_str(), port).to_socket_addrs()?;
+    let mut last_err: Box<dyn Error> = format!("can not resolve host: {}", host_info).into();
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, Duration::new(1,0)) {
+            Ok(tcp_conn) => {
+                tcp_conn.set_read_timeout(Some(Duration::new(10,10)))?;
+                tcp_conn.set_write_timeout(Some(Duration::new(10,10)))?;
+                return Ok(tcp_conn);
+            }
+            Err(e) => {
+                last_err = e.into();
+            }
+        }
     }
-    let addrs = SocketAddr::from((IpAddr::V4(Ipv4Addr::new(ip_info[0], ip_info[1], ip_info[2], ip_info[3])), port));
-    //let tcp_conn = TcpStream::connect(host_info)?;
-    let tcp_conn = TcpStream::connect_timeout(&addrs, Duration::new(1,0))?;
-    tcp_conn.set_read_timeout(Some(Duration::new(10,10)))?;
-    tcp_conn.set_write_timeout(Some(Duration::new(10,10)))?;
-    Ok(tcp_conn)
+    Err(last_err)
 }
 
 
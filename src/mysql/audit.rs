@@ -1,4 +1,475 @@
 /*
 @author: xiao cai niao
 @datetime: 2019/11/11
-*/
\ No newline at end of file
+*/
+
+use crate::Config;
+use crate::meta::{BinlogPos, SchemaCache};
+use crate::binlog::readbinlog::{Traction, TractionValue};
+use crate::binlog::readevent::{self, BinlogEvent, EventHeader, InitHeader, InitValue};
+use crate::mysql::MyProtocol;
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+/// 单个审计文件大小上限，超过后轮转
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// 保留的历史审计文件个数
+const MAX_FILES: usize = 8;
+/// 每条dml记录保存的sql条数上限
+const MAX_RECORD_SQLS: usize = 100;
+/// 每次最多读取的binlog字节数
+const MAX_READ_BYTES: u64 = 64 * 1024 * 1024;
+/// 分页获取时每页的记录数上限
+const MAX_PAGE_SIZE: usize = 1000;
+
+///
+/// 审计记录
+///     ddl: 每条语句一条记录
+///     dml: 每个row event一条记录，rows为影响行数
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: u32,
+    pub server_id: u32,
+    pub gtid: String,
+    pub binlog: String,
+    pub position: u32,
+    pub kind: String,           //DDL/INSERT/UPDATE/DELETE
+    pub schema: String,
+    pub table: String,
+    pub rows: usize,
+    pub sqls: Vec<String>,
+}
+
+impl AuditRecord {
+    fn new(traction: &TractionValue, gtid: &String, binlog: &String) -> AuditRecord {
+        AuditRecord{
+            timestamp: traction.timestamp,
+            server_id: traction.server_id,
+            gtid: gtid.clone(),
+            binlog: binlog.clone(),
+            position: traction.position,
+            kind: "".to_string(),
+            schema: "".to_string(),
+            table: "".to_string(),
+            rows: 0,
+            sqls: vec![]
+        }
+    }
+}
+
+///
+/// 把parse返回的事务拆分为审计记录
+///
+pub fn get_records(tractions: &Vec<TractionValue>, binlog: &String) -> Vec<AuditRecord> {
+    let mut records = vec![];
+    for traction in tractions {
+        let mut gtid = String::from("");
+        let mut schema = String::from("");
+        let mut table = String::from("");
+        let mut sql_offset = 0;
+        for event in &traction.event {
+            match event {
+                Traction::GtidEvent(v) => {
+                    gtid = format!("{}:{}", v.gtid.to_hyphenated(), v.gno_id);
+                }
+                Traction::QueryEvent(v) => {
+                    let command = v.command.trim().to_uppercase();
+                    if command == "BEGIN" || command == "COMMIT" {
+                        continue;
+                    }
+                    let mut record = AuditRecord::new(traction, &gtid, binlog);
+                    record.kind = "DDL".to_string();
+                    record.schema = v.database.clone();
+                    record.sqls.push(v.command.clone());
+                    records.push(record);
+                }
+                Traction::TableMapEvent(v) => {
+                    schema = v.database_name.clone();
+                    table = v.table_name.clone();
+                }
+                Traction::RowEventStatic{type_code, count} => {
+                    let mut record = AuditRecord::new(traction, &gtid, binlog);
+                    record.kind = match type_code {
+                        BinlogEvent::WriteEvent => "INSERT".to_string(),
+                        BinlogEvent::UpdateEvent => "UPDATE".to_string(),
+                        _ => "DELETE".to_string(),
+                    };
+                    record.schema = schema.clone();
+                    record.table = table.clone();
+                    record.rows = *count;
+                    let end = sql_offset + count;
+                    if end <= traction.cur_sql.len() {
+                        let keep = std::cmp::min(sql_offset + MAX_RECORD_SQLS, end);
+                        record.sqls = traction.cur_sql[sql_offset..keep].to_vec();
+                    }
+                    sql_offset = end;
+                    records.push(record);
+                }
+                _ => {}
+            }
+        }
+    }
+    records
+}
+
+///
+/// 审计读取位置，保存在audit.pos文件中，重启后从该位置继续
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditPosition {
+    pub binlog: String,
+    pub position: u64,
+}
+
+///
+/// 本地审计文件
+///     audit.log为当前写入的文件，轮转后依次为audit.log.1 ... audit.log.N，数字越大越旧
+///     每行一条json格式的审计记录
+///
+pub struct AuditLog {
+    pub dir: String,
+    counts: Vec<Option<usize>>,     //已轮转文件的行数，下标为文件序号，轮转后的文件不再修改，统计一次后缓存
+}
+
+impl AuditLog {
+    pub fn new(dir: &String) -> AuditLog {
+        AuditLog{ dir: dir.clone(), counts: vec![None; MAX_FILES + 1] }
+    }
+
+    fn file_name(&self, idx: usize) -> String {
+        if idx == 0 {
+            return format!("{}/audit.log", self.dir);
+        }
+        format!("{}/audit.log.{}", self.dir, idx)
+    }
+
+    fn pos_file(&self) -> String {
+        format!("{}/audit.pos", self.dir)
+    }
+
+    pub fn read_position(&self) -> Option<AuditPosition> {
+        match fs::read(self.pos_file()) {
+            Ok(v) => {
+                match serde_json::from_slice(&v) {
+                    Ok(t) => Some(t),
+                    Err(e) => {
+                        info!("invalid audit position file: {}", e.to_string());
+                        None
+                    }
+                }
+            }
+            Err(_) => None
+        }
+    }
+
+    ///
+    /// 先写入临时文件再重命名，避免写一半时宕机导致位置信息损坏
+    ///
+    pub fn save_position(&self, position: &AuditPosition) -> Result<(), Box<dyn Error>> {
+        let tmp = format!("{}.tmp", self.pos_file());
+        fs::write(&tmp, serde_json::to_string(position)?)?;
+        fs::rename(&tmp, self.pos_file())?;
+        Ok(())
+    }
+
+    pub fn write_records(&mut self, records: &Vec<AuditRecord>) -> Result<(), Box<dyn Error>> {
+        if records.len() == 0 {
+            return Ok(());
+        }
+        let mut lines = String::from("");
+        for record in records {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push_str("\n");
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut f = OpenOptions::new().create(true).read(true).append(true).open(self.file_name(0))?;
+        //宕机时最后一行可能没有写完整，新的记录从下一行开始
+        if f.metadata()?.len() > 0 {
+            let mut last = [0u8; 1];
+            f.seek(SeekFrom::End(-1))?;
+            f.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                f.write_all(b"\n")?;
+            }
+        }
+        f.write_all(lines.as_bytes())?;
+        f.sync_data()?;
+        if f.metadata()?.len() >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Box<dyn Error>> {
+        let oldest = self.file_name(MAX_FILES);
+        if Path::new(&oldest).exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for idx in (0..MAX_FILES).rev() {
+            let name = self.file_name(idx);
+            if Path::new(&name).exists() {
+                fs::rename(&name, self.file_name(idx + 1))?;
+            }
+        }
+        self.counts.insert(1, None);
+        self.counts.truncate(MAX_FILES + 1);
+        Ok(())
+    }
+
+    ///
+    /// 按页读取审计记录，最新的记录在前，page从1开始
+    ///     total为所有文件的行数，已轮转文件的行数只统计一次，只解析当前页所在的行
+    ///     无法解析的行(宕机时没有写完整)跳过，该页的记录数会少于page_size
+    ///
+    pub fn read_page(&mut self, page: usize, page_size: usize) -> Result<AuditPage, Box<dyn Error>> {
+        let page = if page == 0 { 1 } else { page };
+        let page_size = std::cmp::min(page_size, MAX_PAGE_SIZE);
+        let (start, end) = match (page - 1).checked_mul(page_size).and_then(|v| Some((v, v.checked_add(page_size)?))) {
+            Some(v) => v,
+            None => return Err(format!("page {} is out of range", page).into())
+        };
+        let mut total = 0;
+        let mut records = vec![];
+        for idx in 0..=MAX_FILES {
+            let name = self.file_name(idx);
+            if !Path::new(&name).exists() {
+                continue;
+            }
+            let count = match self.counts[idx] {
+                Some(v) if idx > 0 => v,
+                _ => {
+                    let v = BufReader::new(File::open(&name)?).split(b'\n').count();
+                    if idx > 0 {
+                        self.counts[idx] = Some(v);
+                    }
+                    v
+                }
+            };
+            //文件中的行从旧到新，当前页在文件中对应的行号范围为[first, last)
+            if total < end && total + count > start {
+                let first = count - std::cmp::min(end - total, count);
+                let last = count - start.saturating_sub(total);
+                let mut file_records = vec![];
+                let reader = BufReader::new(File::open(&name)?);
+                for line in reader.split(b'\n').skip(first).take(last - first) {
+                    let line = line?;
+                    match serde_json::from_slice::<AuditRecord>(&line) {
+                        Ok(v) => file_records.push(v),
+                        Err(e) => info!("skip invalid audit record in {}: {}", &name, e.to_string())
+                    }
+                }
+                records.extend(file_records.into_iter().rev());
+            }
+            total += count;
+        }
+        Ok(AuditPage{ total, page, page_size, records })
+    }
+}
+
+///
+/// 服务端分页获取审计记录的请求
+///
+#[derive(Deserialize, Debug)]
+pub struct AuditRequest {
+    #[serde(default = "default_page")]
+    pub page: usize,
+    #[serde(default = "default_page_size")]
+    pub page_size: usize,
+}
+
+fn default_page() -> usize {
+    1
+}
+
+fn default_page_size() -> usize {
+    100
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditPage {
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub records: Vec<AuditRecord>,
+}
+
+///
+/// 审计线程，持续读取本地binlog生成审计记录
+///     每次只解析到最后一个完整事务的结束位置，剩余部分下次再读取
+///     遇到rotate event时切换到下一个binlog文件
+///
+pub struct AuditTail {
+    pub conf: Arc<Config>,
+    pub log: Arc<Mutex<AuditLog>>,
+    pub schema: Arc<Mutex<SchemaCache>>,
+    pub position: Option<AuditPosition>,
+    pub fde: Option<readevent::FormatDescriptionEvent>,   //当前binlog文件的format description event
+}
+
+impl AuditTail {
+    pub fn new(conf: Arc<Config>, log: Arc<Mutex<AuditLog>>, schema: Arc<Mutex<SchemaCache>>) -> AuditTail {
+        let position = log.lock().unwrap().read_position();
+        AuditTail{ conf, log, schema, position, fde: None }
+    }
+
+    pub fn loop_tail(&mut self) {
+        loop {
+            if let Err(e) = self.tail() {
+                info!("audit error: {}", e.to_string());
+            }
+            thread::sleep(time::Duration::from_secs(2));
+        }
+    }
+
+    fn tail(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut position = match &self.position {
+            Some(v) => v.clone(),
+            None => {
+                let v = get_master_position(&self.conf)?;
+                info!("start audit from {}:{}", &v.binlog, v.position);
+                self.log.lock().unwrap().save_position(&v)?;
+                self.position = Some(v.clone());
+                v
+            }
+        };
+
+        let path = format!("{}/{}", self.conf.binlogdir, position.binlog);
+        let mut reader = crate::binlog::open_file(&path)?;
+        let fde = match &self.fde {
+            Some(v) => v.clone(),
+            None => {
+                let v = crate::binlog::readbinlog::read_format_description(&mut reader)?;
+                self.fde = Some(v.clone());
+                v
+            }
+        };
+        let end_pos = reader.seek(SeekFrom::End(0))?;
+        if end_pos <= position.position {
+            return Ok(());
+        }
+        let (buf, boundary, next_binlog) = read_complete(&mut reader, position.position, end_pos, &fde)?;
+        if boundary > 0 {
+            let mut cur = Cursor::new(&buf[..boundary]);
+            let rows_sql = crate::binlog::readbinlog::parse_with_format(&self.conf, &self.schema, &mut cur, boundary as u64, false,
+                                                                        &BinlogPos::new(&position.binlog, position.position), fde)?;
+            if rows_sql.error.len() > 0 {
+                return Err(rows_sql.error.into());
+            }
+            let records = get_records(&rows_sql.sqls, &position.binlog);
+            position.position += boundary as u64;
+            let mut log_lock = self.log.lock().unwrap();
+            log_lock.write_records(&records)?;
+            log_lock.save_position(&position)?;
+        }
+        if let Some(binlog) = next_binlog {
+            info!("audit rotate to new log: {}", &binlog);
+            position = AuditPosition{ binlog, position: 4 };
+            self.fde = None;
+            self.log.lock().unwrap().save_position(&position)?;
+        }
+        self.position = Some(position);
+        Ok(())
+    }
+}
+
+///
+/// 从position开始读取binlog，返回读取的数据、最后一个完整事务的结束位置(相对于position)及rotate后的binlog文件名
+///     每次读取MAX_READ_BYTES，单个事务超过该大小时继续读取，直到找到事务结束位置或读取到文件末尾
+///
+pub fn read_complete<R: Read + Seek>(reader: &mut R, position: u64, end_pos: u64, fde: &readevent::FormatDescriptionEvent) -> Result<(Vec<u8>, usize, Option<String>), Box<dyn Error>> {
+    let mut buf = vec![];
+    reader.seek(SeekFrom::Start(position))?;
+    loop {
+        let remain = end_pos - position - buf.len() as u64;
+        let read_bytes = std::cmp::min(remain, MAX_READ_BYTES);
+        let start = buf.len();
+        buf.resize(start + read_bytes as usize, 0);
+        reader.read_exact(&mut buf[start..])?;
+        let (boundary, next_binlog) = get_boundary(&buf, fde)?;
+        if boundary > 0 || next_binlog.is_some() || read_bytes == remain {
+            return Ok((buf, boundary, next_binlog));
+        }
+        info!("transaction at {} is larger than {} bytes, continue reading", position, buf.len());
+    }
+}
+
+///
+/// 查找最后一个完整事务的结束位置
+///     事务以xid event结尾，ddl及非事务表的事务以非BEGIN的query event结尾
+///     rotate event之前的数据都是完整的，同时返回下一个binlog文件名
+///
+pub fn get_boundary(buf: &Vec<u8>, fde: &readevent::FormatDescriptionEvent) -> Result<(usize, Option<String>), Box<dyn Error>> {
+    let mut offset = 0;
+    let mut boundary = 0;
+    while offset + 19 <= buf.len() {
+        let mut cur = Cursor::new(&buf[offset..offset + 19]);
+        let header: EventHeader = InitHeader::new(&mut cur)?;
+        let end = offset + header.event_length as usize;
+        if end > buf.len() {
+            break;
+        }
+        //去掉末尾的checksum
+        let payload_end = std::cmp::max(offset + 19, end - fde.checksum_len());
+        let mut payload = Cursor::new(buf[offset + 19..payload_end].to_vec());
+        match header.type_code {
+            BinlogEvent::XidEvent => {
+                boundary = end;
+            }
+            BinlogEvent::QueryEvent => {
+                let v = readevent::QueryEvent::read_event(&header, &mut payload, &fde.major_version())?;
+                if v.command.trim().to_uppercase() != "BEGIN" {
+                    boundary = end;
+                }
+            }
+            BinlogEvent::RotateLogEvent => {
+                let v = readevent::RotateLog::read_event(&header, &mut payload, &fde.major_version())?;
+                return Ok((offset, Some(v.binlog_file)));
+            }
+            BinlogEvent::FormatDescriptionEvent |
+            BinlogEvent::PreviousGtidsLogEvent => {
+                if boundary == offset {
+                    boundary = end;
+                }
+            }
+            _ => {}
+        }
+        offset = end;
+    }
+    Ok((boundary, None))
+}
+
+
+pub fn get_master_position(conf: &Arc<Config>) -> Result<AuditPosition, Box<dyn Error>> {
+    let mut conn = crate::create_conn(conf)?;
+    let result = crate::io::command::execute(&mut conn, &String::from("show master status"))?;
+    crate::io::command::close(&mut conn);
+    if result.len() > 0 {
+        if let (Some(binlog), Some(position)) = (result[0].get(&String::from("File")), result[0].get(&String::from("Position"))) {
+            return Ok(AuditPosition{ binlog: binlog.clone(), position: position.parse()? });
+        }
+    }
+    Err(String::from("binlog is not enabled").into())
+}
+
+///
+/// 服务端分页获取审计记录
+///
+pub fn get_audit_log<W: Write>(tcp: W, conf: &Arc<Config>, log: &Arc<Mutex<AuditLog>>, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+    if !conf.audit {
+        return Err(String::from("audit is not enabled, start the client with --audit").into());
+    }
+    let request: AuditRequest = if buf.len() > 9 {
+        serde_json::from_slice(&buf[9..])?
+    } else {
+        AuditRequest{ page: default_page(), page_size: default_page_size() }
+    };
+    let page = log.lock().unwrap().read_page(request.page, request.page_size)?;
+    crate::mysql::send_value_packet(tcp, &page, MyProtocol::GetAuditLog)?;
+    Ok(())
+}
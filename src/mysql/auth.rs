@@ -0,0 +1,111 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/11
+*/
+
+use crate::Config;
+use crate::mysql::{MyProtocol, send_value_packet};
+use crate::readvalue::rec_packet_limited;
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+/// auth数据包只包含随机数或签名，限制长度避免验证前分配大块内存
+const AUTH_PACKET_SIZE: u64 = 4096;
+
+///
+/// 连接建立后由client发送的随机数
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthChallenge {
+    pub nonce: String,
+}
+
+///
+/// 连接方使用共享密钥对随机数做hmac-sha256签名后回复
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthResponse {
+    pub sign: String,
+}
+
+fn sign(secret: &String, nonce: &String) -> Result<HmacSha256, Box<dyn Error>> {
+    let mut mac = match HmacSha256::new_varkey(secret.as_bytes()) {
+        Ok(v) => v,
+        Err(_) => return Err(String::from("invalid secret").into())
+    };
+    mac.input(nonce.as_bytes());
+    Ok(mac)
+}
+
+///
+/// 监听[::]时ipv4的连接地址为::ffff:a.b.c.d，转换为ipv4后再与allow_hosts比较
+///
+fn peer_ip(ip: IpAddr) -> String {
+    if let IpAddr::V6(v6) = ip {
+        let segments = v6.segments();
+        if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+            if let Some(v4) = v6.to_ipv4() {
+                return v4.to_string();
+            }
+        }
+    }
+    ip.to_string()
+}
+
+///
+/// 对接入的连接进行验证
+///     1. 配置了allow_hosts时，只接受列表中的地址
+///     2. 配置了secret时，发送随机数并校验对方回复的签名，校验失败直接断开
+///     只验证连接建立时的握手，之后的数据包没有签名，不能防止连接被劫持后篡改内容
+///
+pub fn authenticate(tcp: &mut TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
+    if conf.allow_hosts.len() > 0 {
+        let peer = peer_ip(tcp.peer_addr()?.ip());
+        if !conf.allow_hosts.contains(&peer) {
+            return Err(format!("host {} is not allowed", peer).into());
+        }
+    }
+
+    if conf.secret.len() == 0 {
+        return Ok(());
+    }
+    let nonce = Uuid::new_v4().to_simple().to_string();
+    send_value_packet(&mut *tcp, &AuthChallenge{ nonce: nonce.clone() }, MyProtocol::Auth)?;
+    let packet = rec_packet_limited(tcp, AUTH_PACKET_SIZE)?;
+    match MyProtocol::new(&packet[0]) {
+        MyProtocol::Auth => {
+            let value: AuthResponse = serde_json::from_slice(&packet[9..])?;
+            let code = hex::decode(&value.sign)?;
+            if sign(&conf.secret, &nonce)?.verify(&code).is_err() {
+                return Err(String::from("authentication failed").into());
+            }
+            Ok(())
+        }
+        _ => Err(String::from("authentication failed, expected auth packet").into())
+    }
+}
+
+///
+/// 作为连接方与其他client进行验证，用于宕机复检时连接其他节点
+///
+pub fn client_handshake(tcp: &mut TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
+    if conf.secret.len() == 0 {
+        return Ok(());
+    }
+    let packet = rec_packet_limited(tcp, AUTH_PACKET_SIZE)?;
+    match MyProtocol::new(&packet[0]) {
+        MyProtocol::Auth => {
+            let value: AuthChallenge = serde_json::from_slice(&packet[9..])?;
+            let code = sign(&conf.secret, &value.nonce)?.result().code();
+            send_value_packet(tcp, &AuthResponse{ sign: hex::encode(code) }, MyProtocol::Auth)?;
+            Ok(())
+        }
+        _ => Err(format!("authentication failed, invalid type code: {}", &packet[0]).into())
+    }
+}
@@ -0,0 +1,573 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/28
+*/
+
+use crate::Config;
+use crate::meta::{BinlogPos, SchemaCache};
+use crate::binlog::readbinlog::{Traction, TractionValue};
+use crate::binlog::readevent;
+use crate::io::replication::ReplicationStream;
+use crate::mysql::MyProtocol;
+use crate::mysql::audit;
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+use std::time::Instant;
+
+/// 单个cdc文件大小上限，超过后轮转
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+/// 保留的历史cdc文件个数
+const MAX_FILES: usize = 8;
+/// 复制协议每次最多接收的字节数，单个事务更大时下次继续接收
+const MAX_READ_BYTES: u64 = 64 * 1024 * 1024;
+/// 每次拉取返回的记录数上限
+const MAX_LIMIT: usize = 10000;
+
+const ROTATE_EVENT: u8 = 4;
+const FORMAT_DESCRIPTION_EVENT: u8 = 15;
+const QUERY_EVENT: u8 = 2;
+const XID_EVENT: u8 = 16;
+const HEARTBEAT_EVENT: u8 = 27;
+const HEARTBEAT_EVENT_V2: u8 = 41;
+const LOG_EVENT_ARTIFICIAL_F: u16 = 0x20;
+
+///
+/// 单行变更记录，每行一条
+///     seq从1开始连续递增，重启后从checkpoint继续，重复输出的记录seq相同，下游可以据此去重
+///     position为事务结束位置，同一事务的记录相同
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CdcRecord {
+    pub seq: u64,
+    pub op: String,             //insert/update/delete
+    pub schema: String,
+    pub table: String,
+    pub before: Option<serde_json::Map<String, serde_json::Value>>,
+    pub after: Option<serde_json::Map<String, serde_json::Value>>,
+    pub gtid: String,
+    pub binlog: String,
+    pub position: u32,
+    pub timestamp: u32,
+    pub server_id: u32,
+}
+
+///
+/// 把parse返回的事务拆分为每行一条的记录，seq从last_seq + 1开始
+///
+pub fn get_records(tractions: &Vec<TractionValue>, binlog: &String, last_seq: u64) -> Vec<CdcRecord> {
+    let mut records = vec![];
+    let mut seq = last_seq;
+    for traction in tractions {
+        let mut gtid = String::from("");
+        for event in &traction.event {
+            if let Traction::GtidEvent(v) = event {
+                gtid = format!("{}:{}", v.gtid.to_hyphenated(), v.gno_id);
+            }
+        }
+        for row in &traction.rows {
+            seq += 1;
+            records.push(CdcRecord{
+                seq,
+                op: row.op.clone(),
+                schema: row.database.clone(),
+                table: row.table.clone(),
+                before: row.before.clone(),
+                after: row.after.clone(),
+                gtid: gtid.clone(),
+                binlog: binlog.clone(),
+                position: traction.position,
+                timestamp: traction.timestamp,
+                server_id: traction.server_id
+            });
+        }
+    }
+    records
+}
+
+///
+/// cdc读取位置，保存在cdc.pos文件中
+///     记录写入成功后才更新，宕机重启时可能重复输出最后一批记录(at-least-once)，但不会丢失
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CdcCheckpoint {
+    pub binlog: String,
+    pub position: u64,
+    pub seq: u64,               //最后一条已写入记录的seq
+}
+
+///
+/// 本地cdc文件
+///     cdc.log为当前写入的文件，轮转后依次为cdc.log.1 ... cdc.log.N，数字越大越旧，每行一条json格式的记录
+///     配置了output(普通文件或命名管道)时同时写入，管道没有读取端时会阻塞直到下游打开，写入时不持有CdcLog的锁
+///
+pub struct CdcLog {
+    pub dir: String,
+    pub output: String,
+    pub error: String,          //cdc线程最近一次的错误，正常读取后清空，server端拉取时一起返回
+}
+
+impl CdcLog {
+    pub fn new(dir: &String, output: &String) -> CdcLog {
+        CdcLog{ dir: dir.clone(), output: output.clone(), error: String::from("") }
+    }
+
+    fn file_name(&self, idx: usize) -> String {
+        if idx == 0 {
+            return format!("{}/cdc.log", self.dir);
+        }
+        format!("{}/cdc.log.{}", self.dir, idx)
+    }
+
+    fn pos_file(&self) -> String {
+        format!("{}/cdc.pos", self.dir)
+    }
+
+    pub fn read_checkpoint(&self) -> Option<CdcCheckpoint> {
+        match fs::read(self.pos_file()) {
+            Ok(v) => {
+                match serde_json::from_slice(&v) {
+                    Ok(t) => Some(t),
+                    Err(e) => {
+                        info!("invalid cdc checkpoint file: {}", e.to_string());
+                        None
+                    }
+                }
+            }
+            Err(_) => None
+        }
+    }
+
+    ///
+    /// 先写入临时文件再重命名，避免写一半时宕机导致位置信息损坏
+    ///
+    pub fn save_checkpoint(&self, checkpoint: &CdcCheckpoint) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp = format!("{}.tmp", self.pos_file());
+        fs::write(&tmp, serde_json::to_string(checkpoint)?)?;
+        fs::rename(&tmp, self.pos_file())?;
+        Ok(())
+    }
+
+    ///
+    /// 写入并刷新cdc.log，写入output之后才能保存checkpoint
+    ///
+    pub fn write_records(&self, lines: &String) -> Result<(), Box<dyn Error>> {
+        if lines.len() == 0 {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut f = OpenOptions::new().create(true).read(true).append(true).open(self.file_name(0))?;
+        //宕机时最后一行可能没有写完整，新的记录从下一行开始
+        if f.metadata()?.len() > 0 {
+            let mut last = [0u8; 1];
+            f.seek(SeekFrom::End(-1))?;
+            f.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                f.write_all(b"\n")?;
+            }
+        }
+        f.write_all(lines.as_bytes())?;
+        f.sync_data()?;
+        if f.metadata()?.len() >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&self) -> Result<(), Box<dyn Error>> {
+        let oldest = self.file_name(MAX_FILES);
+        if Path::new(&oldest).exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for idx in (0..MAX_FILES).rev() {
+            let name = self.file_name(idx);
+            if Path::new(&name).exists() {
+                fs::rename(&name, self.file_name(idx + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// 打开现有的cdc文件(从旧到新)并取得checkpoint，持有锁时只做这一步，读取在释放锁之后进行
+    ///     轮转只是重命名及删除，已经打开的文件不受影响
+    ///
+    pub fn open_files(&self) -> Result<CdcFiles, Box<dyn Error>> {
+        let mut files = vec![];
+        for idx in (0..=MAX_FILES).rev() {
+            match File::open(self.file_name(idx)) {
+                Ok(f) => files.push(f),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into())
+            }
+        }
+        let last_seq = match self.read_checkpoint() {
+            Some(v) => v.seq,
+            None => 0
+        };
+        Ok(CdcFiles{ files, last_seq, error: self.error.clone() })
+    }
+}
+
+///
+/// 读取时使用的cdc文件，由CdcLog::open_files在持有锁时打开
+///
+pub struct CdcFiles {
+    files: Vec<File>,
+    last_seq: u64,
+    error: String,
+}
+
+impl CdcFiles {
+    ///
+    /// 每个cdc文件第一条记录的seq，按从旧到新的顺序返回(文件下标, seq)，没有完整记录的文件不返回
+    ///
+    fn file_index(&self) -> Result<Vec<(usize, u64)>, Box<dyn Error>> {
+        let mut index = vec![];
+        for (idx, f) in self.files.iter().enumerate() {
+            let reader = BufReader::new(f);
+            for line in reader.lines() {
+                if let Ok(record) = serde_json::from_str::<CdcRecord>(&line?) {
+                    index.push((idx, record.seq));
+                    break;
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    ///
+    /// 读取seq大于after_seq的记录，按seq顺序最多返回limit条(不超过MAX_LIMIT)
+    ///     从最后一个第一条seq不大于after_seq + 1的文件开始读取，更早的文件中都是已经处理过的记录
+    ///     需要的记录已经被轮转删除时返回错误，由下游决定是否从当前位置重新开始
+    ///
+    pub fn read_after(&self, after_seq: u64, limit: usize) -> Result<CdcPage, Box<dyn Error>> {
+        let limit = std::cmp::min(limit, MAX_LIMIT);
+        let index = self.file_index()?;
+        if let Some((_, first_seq)) = index.first() {
+            if after_seq + 1 < *first_seq {
+                return Err(format!("cdc records after seq {} have been removed, the oldest seq is {}", after_seq, first_seq).into());
+            }
+        }
+        let start = index.iter().rposition(|(_, seq)| *seq <= after_seq + 1).unwrap_or(0);
+        let mut records: Vec<CdcRecord> = vec![];
+        let mut seq = after_seq;
+        'all: for (idx, _) in index.iter().skip(start) {
+            let mut f = &self.files[*idx];
+            f.seek(SeekFrom::Start(0))?;
+            for line in BufReader::new(f).lines() {
+                let line = line?;
+                if line.len() == 0 {
+                    continue;
+                }
+                let record: CdcRecord = match serde_json::from_str(&line) {
+                    Ok(v) => v,
+                    //宕机时最后一行可能没有写完整
+                    Err(_) => continue
+                };
+                //重启后重复写入的记录只返回一次
+                if record.seq <= seq {
+                    continue;
+                }
+                seq = record.seq;
+                records.push(record);
+                if records.len() >= limit {
+                    break 'all;
+                }
+            }
+        }
+        Ok(CdcPage{ last_seq: self.last_seq, records, error: self.error.clone() })
+    }
+}
+
+///
+/// 服务端获取cdc记录的请求，after_seq为下游已经处理完成的最后一条记录
+///
+#[derive(Deserialize, Debug)]
+pub struct CdcRequest {
+    #[serde(default)]
+    pub after_seq: u64,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CdcPage {
+    pub last_seq: u64,          //已经写入的最后一条记录的seq
+    pub records: Vec<CdcRecord>,
+    #[serde(default)]
+    pub error: String,          //cdc线程出错时不再前进，需要人工处理(如表结构无法获取)
+}
+
+///
+/// 记录转为每行一条的json
+///
+fn record_lines(records: &Vec<CdcRecord>) -> Result<String, Box<dyn Error>> {
+    let mut lines = String::from("");
+    for record in records {
+        lines.push_str(&serde_json::to_string(record)?);
+        lines.push_str("\n");
+    }
+    Ok(lines)
+}
+
+///
+/// 写入output配置的文件或命名管道，管道没有读取端时打开会阻塞
+///
+fn write_output(output: &String, lines: &String) -> Result<(), Box<dyn Error>> {
+    if output.len() == 0 || lines.len() == 0 {
+        return Ok(());
+    }
+    let mut f = OpenOptions::new().create(true).append(true).open(output)?;
+    f.write_all(lines.as_bytes())?;
+    f.flush()?;
+    Ok(())
+}
+
+///
+/// cdc线程，持续读取本地binlog生成变更记录
+///     与审计相同，每次只解析到最后一个完整事务的结束位置，遇到rotate event时切换到下一个binlog文件
+///     binlog文件无法直接读取时通过复制协议从本地mysql拉取，保持一个阻塞的dump连接持续接收新的event
+///
+pub struct CdcTail {
+    pub conf: Arc<Config>,
+    pub log: Arc<Mutex<CdcLog>>,
+    pub schema: Arc<Mutex<SchemaCache>>,
+    pub checkpoint: Option<CdcCheckpoint>,
+    pub fde: Option<readevent::FormatDescriptionEvent>,   //当前binlog文件的format description event
+    pub from_mysql: bool,                                 //是否通过复制协议读取
+    pub stream: Option<ReplicationStream>,                //复制协议读取时的dump连接，出错时关闭并从checkpoint重新dump
+    pub pending: Vec<u8>,                                 //从dump连接收到但还没有处理的event，从checkpoint位置开始
+}
+
+impl CdcTail {
+    pub fn new(conf: Arc<Config>, log: Arc<Mutex<CdcLog>>, schema: Arc<Mutex<SchemaCache>>) -> CdcTail {
+        let checkpoint = log.lock().unwrap().read_checkpoint();
+        CdcTail{ conf, log, schema, checkpoint, fde: None, from_mysql: false, stream: None, pending: vec![] }
+    }
+
+    ///
+    /// 出错时一直重试，错误记录在CdcLog中由server端拉取时返回，相同的错误只输出一次日志
+    ///
+    pub fn loop_tail(&mut self) {
+        loop {
+            match self.tail() {
+                Ok(_) => {
+                    let mut log_lock = self.log.lock().unwrap();
+                    if log_lock.error.len() > 0 {
+                        info!("cdc recovered from: {}", &log_lock.error);
+                        log_lock.error = String::from("");
+                    }
+                }
+                Err(e) => {
+                    let error = match &self.checkpoint {
+                        Some(v) => format!("{} (checkpoint {}:{})", e.to_string(), v.binlog, v.position),
+                        None => e.to_string()
+                    };
+                    let mut log_lock = self.log.lock().unwrap();
+                    if log_lock.error != error {
+                        info!("cdc error: {}", &error);
+                        log_lock.error = error;
+                    }
+                    drop(log_lock);
+                    self.close_stream();
+                }
+            }
+            //dump连接阻塞等待新的event，不需要间隔
+            if self.stream.is_none() {
+                thread::sleep(time::Duration::from_secs(1));
+            }
+        }
+    }
+
+    fn close_stream(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            stream.close();
+        }
+        self.pending = vec![];
+    }
+
+    fn tail(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut checkpoint = match &self.checkpoint {
+            Some(v) => v.clone(),
+            None => {
+                let v = audit::get_master_position(&self.conf)?;
+                let v = CdcCheckpoint{ binlog: v.binlog, position: v.position, seq: 0 };
+                info!("start cdc from {}:{}", &v.binlog, v.position);
+                self.log.lock().unwrap().save_checkpoint(&v)?;
+                self.checkpoint = Some(v.clone());
+                v
+            }
+        };
+
+        let (buf, boundary, next_binlog, fde) = self.read_binlog(&checkpoint)?;
+        if boundary > 0 {
+            let mut cur = Cursor::new(&buf[..boundary]);
+            let rows_sql = crate::binlog::readbinlog::parse_rows_with_format(&self.conf, &self.schema, &mut cur, boundary as u64, false,
+                                                                             &BinlogPos::new(&checkpoint.binlog, checkpoint.position), fde)?;
+            if rows_sql.error.len() > 0 {
+                return Err(rows_sql.error.into());
+            }
+            let records = get_records(&rows_sql.sqls, &checkpoint.binlog, checkpoint.seq);
+            checkpoint.position += boundary as u64;
+            checkpoint.seq += records.len() as u64;
+            let lines = record_lines(&records)?;
+            let output = {
+                let log_lock = self.log.lock().unwrap();
+                log_lock.write_records(&lines)?;
+                log_lock.output.clone()
+            };
+            write_output(&output, &lines)?;
+            self.log.lock().unwrap().save_checkpoint(&checkpoint)?;
+        }
+        //dump连接中未处理的数据留到下次，rotate之后的event属于新的binlog文件，从新文件的开头继续接收
+        if self.stream.is_some() {
+            self.pending = match next_binlog {
+                Some(_) => vec![],
+                None => buf[boundary..].to_vec()
+            };
+        }
+        if let Some(binlog) = next_binlog {
+            info!("cdc rotate to new log: {}", &binlog);
+            checkpoint = CdcCheckpoint{ binlog, position: 4, seq: checkpoint.seq };
+            self.fde = None;
+            self.log.lock().unwrap().save_checkpoint(&checkpoint)?;
+        }
+        self.checkpoint = Some(checkpoint);
+        Ok(())
+    }
+
+    ///
+    /// 读取checkpoint之后的binlog数据，返回数据、最后一个完整事务的结束位置、rotate后的binlog文件名及format description event
+    ///     读取文件时单个事务超过audit::MAX_READ_BYTES继续读取，直到找到事务结束位置
+    ///
+    fn read_binlog(&mut self, checkpoint: &CdcCheckpoint) -> Result<(Vec<u8>, usize, Option<String>, readevent::FormatDescriptionEvent), Box<dyn Error>> {
+        let path = format!("{}/{}", self.conf.binlogdir, checkpoint.binlog);
+        let mut reader = match crate::binlog::open_file(&path) {
+            Ok(v) => {
+                self.from_mysql = false;
+                self.close_stream();
+                v
+            }
+            Err(e) => {
+                if !self.from_mysql {
+                    info!("open {} failed: {}, read binlog from mysql", path, e);
+                    self.from_mysql = true;
+                }
+                let (buf, fde) = self.dump_binlog(checkpoint)?;
+                let (boundary, next_binlog) = audit::get_boundary(&buf, &fde)?;
+                return Ok((buf, boundary, next_binlog, fde));
+            }
+        };
+        let fde = match &self.fde {
+            Some(v) => v.clone(),
+            None => {
+                let v = crate::binlog::readbinlog::read_format_description(&mut reader)?;
+                self.fde = Some(v.clone());
+                v
+            }
+        };
+        let end_pos = reader.seek(SeekFrom::End(0))?;
+        if end_pos <= checkpoint.position {
+            return Ok((vec![], 0, None, fde));
+        }
+        let (buf, boundary, next_binlog) = audit::read_complete(&mut reader, checkpoint.position, end_pos, &fde)?;
+        Ok((buf, boundary, next_binlog, fde))
+    }
+
+    ///
+    /// 通过复制协议读取checkpoint之后的event，返回之前未处理的数据加上本次收到的event
+    ///     第一次读取时从checkpoint开始dump，之后一直使用同一个连接
+    ///     从文件中间开始dump时服务端会先发送一个next_position为0的format description event，不属于文件内容
+    ///
+    fn dump_binlog(&mut self, checkpoint: &CdcCheckpoint) -> Result<(Vec<u8>, readevent::FormatDescriptionEvent), Box<dyn Error>> {
+        if self.stream.is_none() {
+            let mut stream = ReplicationStream::connect(&self.conf, self.conf.dump_server_id)?;
+            if let Err(e) = stream.dump(&checkpoint.binlog, checkpoint.position, false) {
+                stream.close();
+                return Err(e);
+            }
+            self.stream = Some(stream);
+            self.pending = vec![];
+            self.fde = None;
+        }
+        self.read_dump()?;
+        match &self.fde {
+            Some(v) => Ok((std::mem::replace(&mut self.pending, vec![]), v.clone())),
+            None => Err(String::from("no format description event received from mysql").into())
+        }
+    }
+
+    ///
+    /// 读取到rotate event时返回，否则读取到事务结束并且距开始超过1秒时返回，
+    ///     没有新的event时mysql按心跳间隔发送heartbeat event，收到时返回已经读取的数据
+    ///
+    fn read_dump(&mut self) -> Result<(), Box<dyn Error>> {
+        let stream = match self.stream.as_mut() {
+            Some(v) => v,
+            None => return Err(String::from("binlog dump is not started").into())
+        };
+        let start_time = Instant::now();
+        let start_len = self.pending.len();
+        loop {
+            let event = match stream.read_event()? {
+                Some(v) => v,
+                None => return Err(String::from("binlog dump closed by mysql").into())
+            };
+            if event.len() < 19 {
+                continue;
+            }
+            if event[4] == HEARTBEAT_EVENT || event[4] == HEARTBEAT_EVENT_V2 {
+                if self.pending.len() > start_len {
+                    return Ok(());
+                }
+                continue;
+            }
+            let flags = crate::readvalue::read_u16(&event[17..19]);
+            if flags & LOG_EVENT_ARTIFICIAL_F != 0 {
+                continue;
+            }
+            if event[4] == FORMAT_DESCRIPTION_EVENT {
+                self.fde = Some(readevent::FormatDescriptionEvent::read_event(&event[19..].to_vec())?);
+                if crate::readvalue::read_u32(&event[13..17]) == 0 {
+                    continue;
+                }
+            }
+            let type_code = event[4];
+            self.pending.extend(event);
+            if type_code == ROTATE_EVENT || (self.pending.len() - start_len) as u64 >= MAX_READ_BYTES {
+                return Ok(());
+            }
+            if (type_code == XID_EVENT || type_code == QUERY_EVENT) && start_time.elapsed().as_secs() >= 1 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+///
+/// 服务端获取cdc记录，处理完成后使用最后一条记录的seq作为下次请求的after_seq
+///
+pub fn get_cdc_records<W: Write>(tcp: W, conf: &Arc<Config>, log: &Arc<Mutex<CdcLog>>, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+    if !conf.cdc {
+        return Err(String::from("cdc is not enabled, start the client with --cdc").into());
+    }
+    let request: CdcRequest = if buf.len() > 9 {
+        serde_json::from_slice(&buf[9..])?
+    } else {
+        CdcRequest{ after_seq: 0, limit: default_limit() }
+    };
+    let files = log.lock().unwrap().open_files()?;
+    let page = files.read_after(request.after_seq, request.limit)?;
+    crate::mysql::send_value_packet(tcp, &page, MyProtocol::GetCdcRecords)?;
+    Ok(())
+}
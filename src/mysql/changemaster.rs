@@ -8,6 +8,7 @@ use std::sync::Arc;
 use crate::{Config};
 use std::error::Error;
 use crate::mysql::{ReponseErr};
+use crate::io::stream::MysqlStream;
 
 #[derive(Deserialize)]
 pub struct ChangeMasterInfo{
@@ -43,7 +44,7 @@ pub fn change_master(mut tcp: &TcpStream, conf: &Arc<Config>, buf: &Vec<u8>) ->
     }
 }
 
-fn change_master_info(tcp: &mut TcpStream, conf: &Arc<Config>, change_info: &ChangeMasterInfo) -> Result<(), Box<dyn Error>>{
+fn change_master_info(tcp: &mut MysqlStream, conf: &Arc<Config>, change_info: &ChangeMasterInfo) -> Result<(), Box<dyn Error>>{
     let stop_slave = String::from("stop slave for channel 'default';");
     let reset_slave_sql = String::from("reset slave for channel 'default';");
     let reset_master_sql = String::from("reset master;");
@@ -52,7 +53,7 @@ fn change_master_info(tcp: &mut TcpStream, conf: &Arc<Config>, change_info: &Cha
                                 master_port={},master_user='{}',\
                                 master_password='{}',\
                                 master_auto_position=1 for channel 'default'",
-                             change_info.master_host,change_info.master_port,conf.repl_user,conf.repl_passwd);
+                             crate::mysql::strip_host(&change_info.master_host),change_info.master_port,conf.repl_user,conf.repl_passwd);
 
     info!("{}", &stop_slave);
     if let Err(s) = crate::io::command::execute_update(tcp, &stop_slave){
@@ -3,11 +3,11 @@
 @datetime: 2019/11/11
 */
 
-use std::net::TcpStream;
+use std::io::Write;
 use std::sync::Arc;
 use crate::Config;
 use std::error::Error;
-use std::collections::HashMap;
+use crate::io::command::Row;
 use crate::mysql::MyProtocol;
 use serde::Serialize;
 use serde::Deserialize;
@@ -78,11 +78,13 @@ impl MysqlMonitorStatus{
 
 
 
-    pub fn parse_value(&mut self, result: &Vec<HashMap<String,String>>) -> Result<(), Box<dyn Error>> {
+    pub fn parse_value(&mut self, result: &Vec<Row>) -> Result<(), Box<dyn Error>> {
         self.time = crate::timestamp();
         for row in result{
-            let var_name = row.get(&String::from("Variable_name")).unwrap();
-            let value = row.get(&String::from("Value")).unwrap();
+            let (var_name, value) = match (row.get("Variable_name"), row.get("Value")) {
+                (Some(n), Some(v)) => (n, v),
+                _ => continue
+            };
             //info!("{},{}", &var_name, &value);
             if var_name == &String::from("Com_insert"){
                 self.com_insert = value.parse()?;
@@ -167,7 +169,7 @@ impl MysqlMonitorStatus{
 }
 
 
-pub fn mysql_monitor(tcp: &TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>>{
+pub fn mysql_monitor<W: Write>(tcp: W, conf: &Arc<Config>) -> Result<(), Box<dyn Error>>{
     let mut conn = crate::create_conn(&conf)?;
     let mut mysql_status = MysqlMonitorStatus::new();
     let result = crate::io::command::execute(&mut conn, &"show global status".to_string())?;
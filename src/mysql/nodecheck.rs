@@ -58,14 +58,14 @@ pub fn check_down_node(tcp: &mut TcpStream,conf: &Arc<Config>, buf: &Vec<u8>) ->
     let value: DownNodeCheck = serde_json::from_slice(value)?;
     info!("check status: {:?}....",value);
     let mut node_state = DownNodeCheckStatus::new(value.host.clone());
-    let state = get_node_state_from_host(&value.host);
+    let state = get_node_state_from_host(conf, &value.host);
     match state {
         Ok(v) => {
             info!("{:?}",v);
             if !v.online {
                 node_state.set_db_status();
             }
-            crate::mysql::send_value_packet(&tcp, &node_state, MyProtocol::DownNodeCheck)?;
+            crate::mysql::send_value_packet(&*tcp, &node_state, MyProtocol::DownNodeCheck)?;
             return Ok(());
         }
         Err(e) => {
@@ -74,9 +74,8 @@ pub fn check_down_node(tcp: &mut TcpStream,conf: &Arc<Config>, buf: &Vec<u8>) ->
         }
     }
     let mut new_conf = conf.clone_new();
-    let host_info = value.host.split(":");
-    let host_vec = host_info.collect::<Vec<&str>>();
-    let host_info = format!("{}:{}",host_vec[0],value.dbport);
+    let (host, _) = crate::mysql::split_host(&value.host)?;
+    let host_info = crate::mysql::format_host(&host, value.dbport);
     new_conf.alter_host(host_info);
     if let Err(e) = crate::create_conn(&new_conf) {
         let a = e.to_string();
@@ -87,13 +86,14 @@ pub fn check_down_node(tcp: &mut TcpStream,conf: &Arc<Config>, buf: &Vec<u8>) ->
 
     }
     info!("{:?}",node_state);
-    crate::mysql::send_value_packet(&tcp, &node_state, MyProtocol::DownNodeCheck)?;
+    crate::mysql::send_value_packet(&*tcp, &node_state, MyProtocol::DownNodeCheck)?;
     return Ok(());
 
 }
 
-fn get_node_state_from_host(host_info: &str) -> Result<MysqlState, Box<dyn Error>> {
+fn get_node_state_from_host(conf: &Arc<Config>, host_info: &str) -> Result<MysqlState, Box<dyn Error>> {
     let mut conn = conn(host_info)?;
+    crate::mysql::auth::client_handshake(&mut conn, conf)?;
     let mut buf: Vec<u8> = vec![];
     buf.push(0xfe);
     send_value_packet(&mut conn, &Null::new(), MyProtocol::MysqlCheck)?;
@@ -107,7 +107,7 @@ fn get_node_state_from_host(host_info: &str) -> Result<MysqlState, Box<dyn Error
         MyProtocol::Error => {
             let value: ReponseErr = serde_json::from_slice(&packet[9..])?;
             info!("error: {:?}", &value);
-            return get_node_state_from_host(host_info);
+            return get_node_state_from_host(conf, host_info);
         }
         _ => {
             let a = format!("return invalid type code: {}",&packet[0]);
@@ -4,6 +4,7 @@
 */
 
 use std::net::TcpStream;
+use crate::io::stream::MysqlStream;
 use std::sync::Arc;
 use crate::Config;
 use std::error::Error;
@@ -15,7 +16,9 @@ pub struct CommandSql{
 }
 
 impl CommandSql{
-    fn execute(&self, conn: &mut TcpStream) -> Result<(), Box<dyn Error>>{
+    fn execute(&self, conn: &mut MysqlStream) -> Result<(), Box<dyn Error>>{
+        //生成的sql中时间类型都是以utc输出，执行前需统一会话时区
+        crate::io::command::execute_update(conn, &String::from(crate::binlog::getsql::SESSION_TIME_ZONE))?;
         self.start_traction(conn)?;
         for sql in &self.sqls{
             crate::io::command::execute_update(conn, &sql)?;
@@ -24,11 +27,11 @@ impl CommandSql{
         Ok(())
     }
 
-    fn start_traction(&self,conn: &mut TcpStream) -> Result<(), Box<dyn Error>>{
+    fn start_traction(&self,conn: &mut MysqlStream) -> Result<(), Box<dyn Error>>{
         crate::io::command::execute_update(conn, &"begin;".to_string())?;
         Ok(())
     }
-    fn commit(&self, conn: &mut TcpStream) -> Result<(), Box<dyn Error>>{
+    fn commit(&self, conn: &mut MysqlStream) -> Result<(), Box<dyn Error>>{
         crate::io::command::execute_update(conn, &"commit;".to_string())?;
         Ok(())
     }
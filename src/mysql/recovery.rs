@@ -4,13 +4,17 @@
 */
 use serde::{Deserialize, Serialize};
 use std::net::TcpStream;
+use crate::io::stream::MysqlStream;
 use std::io::{Seek, SeekFrom};
+use std::fs;
 use serde_json;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use crate::Config;
+use crate::meta::{BinlogPos, SchemaCache};
 use crate::binlog::open_file;
-use crate::binlog::readevent::{Tell};
+use crate::binlog::readevent::{Tell, FormatDescriptionEvent};
 use crate::binlog::readbinlog::{ RowsSql};
+use crate::binlog::getsql::SqlStatement;
 use std::error::Error;
 use crate::mysql::{MyProtocol, Null};
 
@@ -39,13 +43,18 @@ impl RecoveryInfo {
     /// 执行回滚语句及change master命令
     ///
     fn recovery_rows(&self, conf: &Arc<Config>, sqls: &RowsSql) -> Result<(), Box<dyn Error>>{
+        //binlog_row_image不为FULL时update/delete缺少完整的before image，不能回滚
+        if sqls.sqls.iter().any(|t| t.rollback_incomplete) {
+            return Err(String::from("binlog row image is not full, can not rollback update/delete events").into());
+        }
         let conn = crate::create_conn(conf);
         match conn {
             Ok(mut tcp) => {
+                crate::io::command::execute_update(&mut tcp, &String::from(crate::binlog::getsql::SESSION_TIME_ZONE))?;
                 let traction_len = sqls.sqls.len();
                 for indx in 0..traction_len {
-                    let sql = &sqls.sqls[traction_len - indx - 1].rollback_sql;
-                    match_state(self.recovery_traction(&mut tcp, sql))?;
+                    let sql = &sqls.sqls[traction_len - indx - 1].rollback_stmt;
+                    match_state(execute_traction(&mut tcp, sql))?;
                 }
                 match_state(self.change_master(&mut tcp, conf))?;
                 match_state(crate::mysql::set_readonly(&mut tcp))?;
@@ -60,14 +69,14 @@ impl RecoveryInfo {
     ///
     /// 根据服务端发送的gtid信息进行change master修改，并启动主从复制
     ///
-    fn change_master(&self, tcp: &mut TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
+    fn change_master(&self, tcp: &mut MysqlStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
         let reset_master = String::from("reset master;");
         let set_sql = format!("set global gtid_purged = '{}'", self.gtid);
         let change_sql = format!("change master to master_host='{}',\
                                 master_port={},master_user='{}',\
                                 master_password='{}',\
                                 master_auto_position=1 for channel 'default'",
-                          self.masterhost,self.masterport,conf.repl_user,conf.repl_passwd);
+                          crate::mysql::strip_host(&self.masterhost),self.masterport,conf.repl_user,conf.repl_passwd);
         info!("{}",&reset_master);
         crate::io::command::execute_update(tcp, &reset_master)?;
         info!("{}",&set_sql);
@@ -79,14 +88,48 @@ impl RecoveryInfo {
         Ok(())
     }
 
-    fn recovery_traction(&self, tcp: &mut TcpStream, sqls: &Vec<String>) -> Result<(), Box<dyn Error>> {
-        for sql in sqls{
-            crate::io::command::execute_update(tcp, &sql)?;
+    ///
+    /// 回滚前把回滚的event写为binlog文件保存到flashbackdir，可以使用mysqlbinlog查看或回放
+    ///     写入失败不影响回滚，只记录日志
+    ///
+    fn write_flashback(&self, conf: &Arc<Config>, fde: &FormatDescriptionEvent, sqls: &RowsSql) {
+        let path = format!("{}/{}.{}.flashback", conf.flashbackdir, self.read_binlog, self.read_position);
+        let state = fs::create_dir_all(&conf.flashbackdir)
+            .map_err(|e| e.into())
+            .and_then(|_| crate::binlog::flashback::write_flashback(&path, fde, sqls));
+        match state {
+            Ok(Some(gtid)) => info!("write flashback binlog {}, gtid: {}", &path, gtid),
+            Ok(None) => info!("no flashback event"),
+            Err(e) => info!("write flashback binlog {} error: {}", &path, e)
         }
-        Ok(())
     }
 }
 
+///
+/// 一个binlog事务的语句在一个事务中执行，用于回滚及追加差异数据
+///     update/delete没有正好匹配一行时说明数据已经不一致，回滚该事务并报错
+///
+pub fn execute_traction(tcp: &mut MysqlStream, sqls: &Vec<SqlStatement>) -> Result<(), Box<dyn Error>> {
+    crate::io::command::execute_update(tcp, &String::from("begin;"))?;
+    for stmt in sqls{
+        let state = crate::io::command::execute_params_affected(tcp, &stmt.sql, &stmt.params).and_then(|affected| {
+            let check = stmt.sql.starts_with("UPDATE") || stmt.sql.starts_with("DELETE");
+            if check && affected != 1 {
+                return Err(format!("{} affected {} rows, expected 1", stmt.sql, affected).into());
+            }
+            Ok(())
+        });
+        if let Err(e) = state {
+            if let Err(r) = crate::io::command::execute_update(tcp, &String::from("rollback;")) {
+                info!("rollback error: {}", r);
+            }
+            return Err(e);
+        }
+    }
+    crate::io::command::execute_update(tcp, &String::from("commit;"))?;
+    Ok(())
+}
+
 
 
 ///
@@ -94,7 +137,7 @@ impl RecoveryInfo {
 /// 服务端发送宕机时新master的binlog以及gtid信息，
 /// 客户端接受到之后进行判断是否有回滚的数据，然后重新建立主从关系
 ///
-pub fn recovery_my_slave(tcp: &mut TcpStream, conf: &Arc<Config>, buf: &Vec<u8>) -> Result<(), Box<dyn Error>>{
+pub fn recovery_my_slave(tcp: &mut TcpStream, conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, buf: &Vec<u8>) -> Result<(), Box<dyn Error>>{
     info!("start");
     let value = &buf[9..];
     let rec_info: RecoveryInfo = serde_json::from_str(crate::readvalue::read_string_value(value).as_ref())?;
@@ -113,26 +156,46 @@ pub fn recovery_my_slave(tcp: &mut TcpStream, conf: &Arc<Config>, buf: &Vec<u8>)
             return Ok(());
         }
         info!("need to recover {} bytes of data", end_pos as usize - rec_info.read_position);
+        //从文件中间开始解析，format description event需要先从文件头读取
+        let fde = match crate::binlog::readbinlog::read_format_description(&mut reader) {
+            Ok(v) => v,
+            Err(e) => {
+                info!("read format description event error: {}", e);
+                FormatDescriptionEvent::new()
+            }
+        };
         reader.seek(SeekFrom::Start(rec_info.read_position as u64))?;
-        let result = crate::binlog::readbinlog::parse(conf, &mut reader, end_pos, true);
+        let result = crate::binlog::readbinlog::parse_with_format(conf, schema, &mut reader, end_pos, true,
+                                                                  &BinlogPos::new(&rec_info.read_binlog, 0), fde.clone());
         match result {
             Ok(r) => {
                 info!("{:?}", r);
+                if r.error.len() > 0 {
+                    //binlog解析不完整时不能回滚，返回已经解析到的位置
+                    let progress = match r.sqls.last() {
+                        Some(t) => format!("{} transactions parsed, last one ends at {}:{}", r.sqls.len(), rec_info.read_binlog, t.position),
+                        None => format!("no transaction parsed from {}:{}", rec_info.read_binlog, rec_info.read_position)
+                    };
+                    let reponse_err = crate::mysql::ReponseErr::new(format!("{}, {}", r.error, progress));
+                    crate::mysql::send_value_packet(&*tcp, &reponse_err, MyProtocol::Error)?;
+                    return Ok(());
+                }
                 recovery_row = r.clone();
+                rec_info.write_flashback(conf, &fde, &r);
                 rec_info.recovery_rows(conf , &r)?;
             }
             Err(e) => {
                 info!("{}", &e.to_string());
                 let reponse_err = crate::mysql::ReponseErr::new((*e.to_string()).parse()?);
-                crate::mysql::send_value_packet(&tcp, &reponse_err, MyProtocol::Error)?
+                crate::mysql::send_value_packet(&*tcp, &reponse_err, MyProtocol::Error)?
             }
         }
         recovery_row.set_rollback_etype();
-        crate::mysql::send_value_packet(&tcp, &recovery_row, MyProtocol::RecoveryValue)?;
+        crate::mysql::send_value_packet(&*tcp, &recovery_row, MyProtocol::RecoveryValue)?;
         return Ok(());
     }else {
         rec_info.recovery_replication(conf)?;
-        crate::mysql::send_value_packet(&tcp, &recovery_row, MyProtocol::RecoveryValue)?;
+        crate::mysql::send_value_packet(&*tcp, &recovery_row, MyProtocol::RecoveryValue)?;
         return Ok(());
     }
 
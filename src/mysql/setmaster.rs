@@ -9,12 +9,13 @@ use std::sync::Arc;
 use crate::mysql;
 use std::error::Error;
 use crate::mysql::ReponseErr;
+use crate::io::stream::MysqlStream;
 
 pub fn set_master(mut tcp: &TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
     let conn = crate::create_conn(conf);
     match conn {
-        Ok(mut tcp) => {
-            if let Err(e) = set_master_info(&mut tcp){
+        Ok(mut db_conn) => {
+            if let Err(e) = set_master_info(&mut db_conn){
                 let err = e.to_string();
                 info!("{}", &err);
                 crate::mysql::send_error_packet(&ReponseErr::new(err), &mut tcp)?;
@@ -34,7 +35,7 @@ pub fn set_master(mut tcp: &TcpStream, conf: &Arc<Config>) -> Result<(), Box<dyn
 ///
 /// 当该节点被选举为master，执行重置slave线程并把readonly和flush参数重置
 ///
-fn set_master_info(tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+fn set_master_info(tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
     let stop_slave_sql = String::from("stop slave;");
     let reset_slave_sql = String::from("reset slave all;");
     info!("{}", &stop_slave_sql);
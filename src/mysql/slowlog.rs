@@ -1,4 +1,557 @@
 /*
 @author: xiao cai niao
 @datetime: 2019/11/11
-*/
\ No newline at end of file
+*/
+
+use crate::Config;
+use crate::mysql::MyProtocol;
+use serde::{Serialize, Deserialize};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+/// 聚合后保留的最大sql指纹数量，超过时丢弃出现次数最少的
+const MAX_DIGESTS: usize = 5000;
+/// 未完整的记录最多缓存的字节数
+const MAX_PENDING: usize = 4 * 1024 * 1024;
+
+///
+/// 单条慢日志记录
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowLogEntry {
+    pub time: String,
+    pub user_host: String,
+    pub query_time: f64,
+    pub lock_time: f64,
+    pub rows_sent: usize,
+    pub rows_examined: usize,
+    pub schema: String,
+    pub sql: String,
+}
+
+impl SlowLogEntry {
+    fn new() -> SlowLogEntry {
+        SlowLogEntry{
+            time: "".to_string(),
+            user_host: "".to_string(),
+            query_time: 0.0,
+            lock_time: 0.0,
+            rows_sent: 0,
+            rows_examined: 0,
+            schema: "".to_string(),
+            sql: "".to_string()
+        }
+    }
+
+    ///
+    /// 解析"# Query_time: 2.000194  Lock_time: 0.000000 Rows_sent: 1  Rows_examined: 0"
+    /// 8.0开启log_slow_extra时后面还会有其他字段，直接忽略
+    ///
+    fn parse_query_time(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let items = line.trim_start_matches('#').split_whitespace().collect::<Vec<&str>>();
+        for idx in (0..items.len()).step_by(2) {
+            if idx + 1 >= items.len() {
+                break;
+            }
+            let value = items[idx + 1];
+            match items[idx] {
+                "Query_time:" => self.query_time = value.parse()?,
+                "Lock_time:" => self.lock_time = value.parse()?,
+                "Rows_sent:" => self.rows_sent = value.parse()?,
+                "Rows_examined:" => self.rows_examined = value.parse()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// 解析"# User@Host: root[root] @ localhost [127.0.0.1]  Id:     8"
+    ///
+    fn parse_user_host(&mut self, line: &str) {
+        let value = line.trim_start_matches("# User@Host:");
+        let value = match value.find("Id:") {
+            Some(idx) => &value[..idx],
+            None => value
+        };
+        self.user_host = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+    }
+}
+
+///
+/// 同一类sql的聚合信息
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlowLogDigest {
+    pub digest: String,
+    pub fingerprint: String,
+    pub schema: String,
+    pub sample: String,
+    pub user_host: String,
+    pub count: usize,
+    pub query_time_sum: f64,
+    pub query_time_max: f64,
+    pub lock_time_sum: f64,
+    pub rows_sent_sum: usize,
+    pub rows_examined_sum: usize,
+    pub first_seen: String,
+    pub last_seen: String,
+}
+
+impl SlowLogDigest {
+    fn new(digest: String, fingerprint: String, entry: &SlowLogEntry) -> SlowLogDigest {
+        SlowLogDigest{
+            digest,
+            fingerprint,
+            schema: entry.schema.clone(),
+            sample: entry.sql.clone(),
+            user_host: entry.user_host.clone(),
+            count: 0,
+            query_time_sum: 0.0,
+            query_time_max: 0.0,
+            lock_time_sum: 0.0,
+            rows_sent_sum: 0,
+            rows_examined_sum: 0,
+            first_seen: entry.time.clone(),
+            last_seen: entry.time.clone()
+        }
+    }
+
+    fn add(&mut self, entry: &SlowLogEntry) {
+        self.count += 1;
+        self.query_time_sum += entry.query_time;
+        self.lock_time_sum += entry.lock_time;
+        self.rows_sent_sum += entry.rows_sent;
+        self.rows_examined_sum += entry.rows_examined;
+        if entry.query_time >= self.query_time_max {
+            //样例sql保留耗时最长的一条
+            self.query_time_max = entry.query_time;
+            self.sample = entry.sql.clone();
+            self.user_host = entry.user_host.clone();
+        }
+        self.last_seen = entry.time.clone();
+    }
+}
+
+///
+/// 慢日志采集状态，由采集线程写入，GetSlowLog请求读取
+///
+pub struct SlowLogState {
+    pub file: String,
+    pub digests: HashMap<String, SlowLogDigest>,
+}
+
+impl SlowLogState {
+    pub fn new() -> SlowLogState {
+        SlowLogState{
+            file: "".to_string(),
+            digests: HashMap::new()
+        }
+    }
+
+    fn add_entry(&mut self, entry: &SlowLogEntry) {
+        let fingerprint = fingerprint(&entry.sql);
+        let digest = get_digest(&entry.schema, &fingerprint);
+        if !self.digests.contains_key(&digest) && self.digests.len() >= MAX_DIGESTS {
+            self.evict();
+        }
+        let value = self.digests.entry(digest.clone())
+            .or_insert_with(|| SlowLogDigest::new(digest, fingerprint, entry));
+        value.add(entry);
+    }
+
+    /// 丢弃出现次数最少的一批指纹
+    fn evict(&mut self) {
+        let mut counts = self.digests.iter().map(|(k, v)| (v.count, k.clone())).collect::<Vec<(usize, String)>>();
+        counts.sort();
+        for (_, key) in counts.iter().take(MAX_DIGESTS / 10) {
+            self.digests.remove(key);
+        }
+    }
+}
+
+///
+/// 服务端获取慢日志时的请求参数
+///
+#[derive(Deserialize, Debug)]
+pub struct SlowLogRequest {
+    #[serde(default = "default_top")]
+    pub top: usize,
+    #[serde(default = "default_order_by")]
+    pub order_by: String,       //total: 总耗时, max: 最大耗时, count: 执行次数
+    #[serde(default)]
+    pub reset: bool,            //获取后清空已聚合的数据
+}
+
+fn default_top() -> usize {
+    20
+}
+
+fn default_order_by() -> String {
+    "total".to_string()
+}
+
+impl SlowLogRequest {
+    fn new(buf: &Vec<u8>) -> Result<SlowLogRequest, Box<dyn Error>> {
+        if buf.len() > 9 {
+            let value: SlowLogRequest = serde_json::from_slice(&buf[9..])?;
+            return Ok(value);
+        }
+        Ok(SlowLogRequest{ top: default_top(), order_by: default_order_by(), reset: false })
+    }
+}
+
+///
+/// 回复给服务端的慢日志数据
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlowLogInfo {
+    pub file: String,
+    pub total: usize,
+    pub digests: Vec<SlowLogDigest>,
+}
+
+///
+/// 慢日志文件采集
+///     从文件末尾开始读取，每秒检查一次新增内容
+///     文件被清空或轮转(大小小于已读取位置)时从头开始读取
+///
+pub struct SlowLogTail {
+    pub conf: Arc<Config>,
+    pub state: Arc<Mutex<SlowLogState>>,
+    pub path: String,
+    pub position: u64,
+    pub pending: String,
+}
+
+impl SlowLogTail {
+    pub fn new(conf: Arc<Config>, state: Arc<Mutex<SlowLogState>>) -> SlowLogTail {
+        SlowLogTail{
+            conf,
+            state,
+            path: "".to_string(),
+            position: 0,
+            pending: "".to_string()
+        }
+    }
+
+    pub fn loop_tail(&mut self) {
+        loop {
+            if let Err(e) = self.tail() {
+                info!("slow log collection error: {}", e.to_string());
+                self.path = "".to_string();
+            }
+            thread::sleep(time::Duration::from_secs(1));
+        }
+    }
+
+    fn tail(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.path.len() == 0 {
+            self.path = get_slowlog_file(&self.conf)?;
+            let mut f = File::open(&self.path)?;
+            self.position = f.seek(SeekFrom::End(0))?;
+            self.pending = "".to_string();
+            let mut state_lock = self.state.lock().unwrap();
+            state_lock.file = self.path.clone();
+            info!("start collecting slow log: {}", &self.path);
+            return Ok(());
+        }
+
+        let mut f = File::open(&self.path)?;
+        let end_pos = f.seek(SeekFrom::End(0))?;
+        if end_pos < self.position {
+            info!("slow log {} has been truncated, read from the beginning", &self.path);
+            self.position = 0;
+            self.pending = "".to_string();
+        }
+        if end_pos == self.position {
+            return Ok(());
+        }
+        f.seek(SeekFrom::Start(self.position))?;
+        let mut buf = vec![];
+        f.read_to_end(&mut buf)?;
+        //只处理完整的行，剩余部分下次再读取
+        let last_line = match buf.iter().rposition(|&b| b == b'\n') {
+            Some(idx) => idx + 1,
+            None => return Ok(())
+        };
+        self.position += last_line as u64;
+        self.pending.push_str(&String::from_utf8_lossy(&buf[..last_line]));
+        if self.pending.len() > MAX_PENDING {
+            info!("slow log entry larger than {} bytes, skip it", MAX_PENDING);
+            self.pending = "".to_string();
+            return Ok(());
+        }
+
+        let (entries, rest) = parse_entries(&self.pending);
+        self.pending = rest;
+        if entries.len() > 0 {
+            let mut state_lock = self.state.lock().unwrap();
+            for entry in entries.iter() {
+                state_lock.add_entry(entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// 获取慢日志文件路径，相对路径时基于datadir
+///
+fn get_slowlog_file(conf: &Arc<Config>) -> Result<String, Box<dyn Error>> {
+    let mut conn = crate::create_conn(conf)?;
+    let sql = String::from("select @@slow_query_log_file as slow_query_log_file, @@datadir as datadir;");
+    let result = crate::io::command::execute(&mut conn, &sql)?;
+    crate::io::command::close(&mut conn);
+    if result.len() > 0 {
+        if let Some(file) = result[0].get(&String::from("slow_query_log_file")) {
+            if file.starts_with("/") {
+                return Ok(file.clone());
+            }
+            if let Some(datadir) = result[0].get(&String::from("datadir")) {
+                return Ok(format!("{}/{}", datadir.trim_end_matches('/'), file));
+            }
+        }
+    }
+    Err(String::from("get slow_query_log_file failed").into())
+}
+
+///
+/// 把读取到的文本拆分为多条慢日志记录
+/// 最后一条如果sql还没有以';'结尾，说明还未写完，返回给调用方留到下次处理
+///
+fn parse_entries(text: &str) -> (Vec<SlowLogEntry>, String) {
+    let mut entries = vec![];
+    let mut blocks: Vec<Vec<&str>> = vec![];
+    let mut cur: Vec<&str> = vec![];
+    let mut in_sql = false;
+    for line in text.lines() {
+        let is_header = line.starts_with("# Time:") || line.starts_with("# User@Host:");
+        if is_header && (in_sql || (line.starts_with("# Time:") && cur.len() > 0)) {
+            blocks.push(cur);
+            cur = vec![];
+            in_sql = false;
+        }
+        if !line.starts_with("#") && cur.len() > 0 {
+            in_sql = true;
+        }
+        cur.push(line);
+    }
+
+    let mut rest = String::from("");
+    if cur.len() > 0 {
+        let complete = match cur.last() {
+            Some(line) => in_sql && line.trim_end().ends_with(";"),
+            None => false
+        };
+        if complete {
+            blocks.push(cur);
+        } else {
+            for line in cur {
+                rest.push_str(line);
+                rest.push_str("\n");
+            }
+        }
+    }
+
+    for block in blocks {
+        if let Some(entry) = parse_entry(&block) {
+            entries.push(entry);
+        }
+    }
+    (entries, rest)
+}
+
+fn parse_entry(lines: &Vec<&str>) -> Option<SlowLogEntry> {
+    let mut entry = SlowLogEntry::new();
+    let mut has_query_time = false;
+    let mut sql_lines = vec![];
+    for line in lines {
+        if line.starts_with("# Time:") {
+            entry.time = line.trim_start_matches("# Time:").trim().to_string();
+        } else if line.starts_with("# User@Host:") {
+            entry.parse_user_host(line);
+        } else if line.starts_with("# Query_time:") {
+            if let Err(e) = entry.parse_query_time(line) {
+                info!("invalid slow log line {}: {}", line, e.to_string());
+                return None;
+            }
+            has_query_time = true;
+        } else if line.starts_with("#") {
+            continue;
+        } else if !has_query_time {
+            //文件头部的启动信息等
+            continue;
+        } else if line.to_lowercase().starts_with("use ") && sql_lines.len() == 0 {
+            entry.schema = line[4..].trim().trim_end_matches(';').trim_matches('`').to_string();
+        } else if line.starts_with("SET timestamp=") && sql_lines.len() == 0 {
+            continue;
+        } else {
+            sql_lines.push(*line);
+        }
+    }
+    if !has_query_time || sql_lines.len() == 0 {
+        return None;
+    }
+    entry.sql = sql_lines.join("\n");
+    Some(entry)
+}
+
+fn get_digest(schema: &String, fingerprint: &String) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(schema.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(fingerprint.as_bytes());
+    hasher.digest().to_string()[..32].to_string()
+}
+
+///
+/// 生成sql指纹
+///     去掉注释，常量替换为?，in/values列表合并为(?+)，空白字符合并，关键字小写
+///
+pub fn fingerprint(sql: &str) -> String {
+    let chars = sql.trim().trim_end_matches(';').chars().collect::<Vec<char>>();
+    let mut out = String::with_capacity(chars.len());
+    let mut idx = 0;
+    while idx < chars.len() {
+        let c = chars[idx];
+        if c == '/' && idx + 1 < chars.len() && chars[idx + 1] == '*' {
+            idx += 2;
+            while idx + 1 < chars.len() && !(chars[idx] == '*' && chars[idx + 1] == '/') {
+                idx += 1;
+            }
+            idx += 2;
+            push_space(&mut out);
+        } else if (c == '-' && idx + 2 < chars.len() && chars[idx + 1] == '-' && chars[idx + 2].is_whitespace()) || c == '#' {
+            while idx < chars.len() && chars[idx] != '\n' {
+                idx += 1;
+            }
+            push_space(&mut out);
+        } else if c == '\'' || c == '"' {
+            idx += 1;
+            while idx < chars.len() {
+                if chars[idx] == '\\' {
+                    idx += 2;
+                    continue;
+                }
+                if chars[idx] == c {
+                    //''表示转义的单引号
+                    if idx + 1 < chars.len() && chars[idx + 1] == c {
+                        idx += 2;
+                        continue;
+                    }
+                    break;
+                }
+                idx += 1;
+            }
+            idx += 1;
+            out.push('?');
+        } else if c == '`' {
+            out.push(c);
+            idx += 1;
+            while idx < chars.len() && chars[idx] != '`' {
+                out.push(chars[idx]);
+                idx += 1;
+            }
+            if idx < chars.len() {
+                out.push('`');
+            }
+            idx += 1;
+        } else if c.is_ascii_digit() && !is_ident_char(out.chars().last()) {
+            //数字、小数、科学计数法以及0x开头的十六进制
+            idx += 1;
+            while idx < chars.len() && (chars[idx].is_ascii_alphanumeric() || chars[idx] == '.') {
+                idx += 1;
+            }
+            if out.ends_with("-") || out.ends_with("+") {
+                let prev = out[..out.len() - 1].trim_end().chars().last();
+                if prev.is_none() || "(,=<>".contains(prev.unwrap()) {
+                    out.pop();
+                }
+            }
+            out.push('?');
+        } else if c.is_whitespace() {
+            push_space(&mut out);
+            idx += 1;
+        } else {
+            out.extend(c.to_lowercase());
+            idx += 1;
+        }
+    }
+    collapse_lists(out.trim())
+}
+
+fn is_ident_char(c: Option<char>) -> bool {
+    match c {
+        Some(c) => c.is_alphanumeric() || c == '_' || c == '$',
+        None => false
+    }
+}
+
+fn push_space(out: &mut String) {
+    if out.len() > 0 && !out.ends_with(" ") {
+        out.push(' ');
+    }
+}
+
+///
+/// "(?, ?, ?)" => "(?+)"，"values (?+), (?+)" => "values (?+)"
+///
+fn collapse_lists(sql: &str) -> String {
+    let chars = sql.chars().collect::<Vec<char>>();
+    let mut out = String::with_capacity(sql.len());
+    let mut idx = 0;
+    while idx < chars.len() {
+        if chars[idx] == '(' {
+            let mut end = idx + 1;
+            while end < chars.len() && (chars[end] == '?' || chars[end] == ',' || chars[end] == ' ') {
+                end += 1;
+            }
+            if end < chars.len() && chars[end] == ')' && end > idx + 1 {
+                let trimmed = out.trim_end();
+                if trimmed.ends_with("(?+),") {
+                    //多行values只保留一组
+                    let keep = trimmed.len() - 1;
+                    out.truncate(keep);
+                } else {
+                    out.push_str("(?+)");
+                }
+                idx = end + 1;
+                continue;
+            }
+        }
+        out.push(chars[idx]);
+        idx += 1;
+    }
+    out
+}
+
+///
+/// 返回聚合后的慢sql信息, 按请求参数排序后取top-N
+///
+pub fn get_slow_log<W: Write>(tcp: W, conf: &Arc<Config>, state: &Arc<Mutex<SlowLogState>>, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+    if !conf.slowlog {
+        return Err(String::from("slowlog is not enabled, start the client with --slowlog").into());
+    }
+    let request = SlowLogRequest::new(buf)?;
+    let mut state_lock = state.lock().unwrap();
+    let mut digests = state_lock.digests.values().cloned().collect::<Vec<SlowLogDigest>>();
+    match request.order_by.as_ref() {
+        "max" => digests.sort_by(|a, b| b.query_time_max.partial_cmp(&a.query_time_max).unwrap()),
+        "count" => digests.sort_by(|a, b| b.count.cmp(&a.count)),
+        _ => digests.sort_by(|a, b| b.query_time_sum.partial_cmp(&a.query_time_sum).unwrap()),
+    }
+    let total = digests.len();
+    digests.truncate(request.top);
+    let info = SlowLogInfo{ file: state_lock.file.clone(), total, digests };
+    if request.reset {
+        state_lock.digests.clear();
+    }
+    crate::mysql::send_value_packet(tcp, &info, MyProtocol::GetSlowLog)?;
+    Ok(())
+}
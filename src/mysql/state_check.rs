@@ -6,7 +6,8 @@
 
 use crate::{Config, mysql, readvalue};
 use std::sync::{Arc, Mutex};
-use std::net::TcpStream;
+use std::io::Write;
+use std::time::Instant;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
@@ -14,6 +15,8 @@ use chrono::prelude::*;
 use chrono;
 use std::{thread, time};
 use crate::io::socketio;
+use crate::io::stream::MysqlStream;
+use crate::io::command::Row;
 
 pub struct LastCheckTime{
     pub last_time: usize,   //最后一次检查的时间
@@ -27,6 +30,8 @@ pub struct MysqlState {
     pub sql_thread: bool,
     pub io_thread: bool,
     pub seconds_behind: usize,
+    #[serde(default)]
+    pub lag_known: bool,                //seconds_behind是否有效，复制线程未运行(Seconds_Behind_Master为NULL)或为master时为false，此时seconds_behind为0
     pub master_log_file: String,
     pub read_master_log_pos: usize,
     pub exec_master_log_pos: usize,
@@ -51,6 +56,7 @@ impl MysqlState {
             sql_thread: false,
             io_thread: false,
             seconds_behind: 0,
+            lag_known: false,
             master_log_file: "".to_string(),
             read_master_log_pos: 0,
             exec_master_log_pos: 0,
@@ -75,6 +81,7 @@ impl MysqlState {
             sql_thread: self.sql_thread.clone(),
             io_thread: self.io_thread.clone(),
             seconds_behind: self.seconds_behind.clone(),
+            lag_known: self.lag_known.clone(),
             master_log_file: self.master_log_file.clone(),
             read_master_log_pos: self.read_master_log_pos.clone(),
             exec_master_log_pos: self.exec_master_log_pos.clone(),
@@ -91,7 +98,7 @@ impl MysqlState {
         }
     }
 
-    pub fn update(&mut self, result: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    pub fn update(&mut self, result: &Row) -> Result<(), Box<dyn Error>> {
         self.role = String::from("slave");
 
         if let Some(slave_io_state) = result.get(&String::from("Slave_IO_Running")){
@@ -110,11 +117,11 @@ impl MysqlState {
             }
         };
 
-        if let Some(behind) = result.get(&String::from("Seconds_Behind_Master")){
-            //info!("{},{:?}", behind, Some(behind));
-            if behind.len() > 0 {
-                self.seconds_behind = behind.parse()?;
-            }
+        //复制线程未运行时为NULL，延迟无法计算，lag_known为false，不能当作无延迟
+        if result.index_of("Seconds_Behind_Master").is_some() {
+            let behind: Option<usize> = result.take("Seconds_Behind_Master")?;
+            self.seconds_behind = behind.unwrap_or(0);
+            self.lag_known = behind.is_some();
         }
 
         if let Some(log_file) = result.get(&String::from("Master_Log_File")){
@@ -140,18 +147,20 @@ impl MysqlState {
         Ok(())
     }
 
-    pub fn slave_state_check(&mut self, tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    pub fn slave_state_check(&mut self, tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
         let sql = "show slave status;";
         let result= crate::io::command::execute(tcp, &sql.to_string())?;
         if result.len() > 0 {
             mysql::check_state(&self.update(&result[0]));
         }else {
             self.role = String::from("master");
+            self.seconds_behind = 0;
+            self.lag_known = false;
         }
         Ok(())
     }
 
-    pub fn variable_check(&mut self, tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    pub fn variable_check(&mut self, tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
         let sql = String::from("select @@read_only as read_only,\
                                             @@sync_binlog as sync_binlog,\
                                             @@innodb_flush_log_at_trx_commit as innodb_flush_log_at_trx_commit,\
@@ -166,7 +175,7 @@ impl MysqlState {
         Ok(())
     }
 
-    pub fn update_variable(&mut self, result: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+    pub fn update_variable(&mut self, result: &Row) -> Result<(), Box<dyn Error>> {
         if let Some(v) = result.get(&String::from("read_only")){
             let a: u8 = v.parse()?;
             if a == 1{
@@ -200,7 +209,7 @@ impl MysqlState {
         Ok(())
     }
 
-    pub fn gtid_check(&mut self, tcp: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    pub fn gtid_check(&mut self, tcp: &mut MysqlStream) -> Result<(), Box<dyn Error>> {
         let sql = String::from("show master status");
         let result= crate::io::command::execute(tcp, &sql)?;
         if result.len() > 0 {
@@ -215,7 +224,7 @@ impl MysqlState {
 
 
 pub struct MysqlConn{
-    pub conn: TcpStream,
+    pub conn: MysqlStream,
     pub state: Arc<Mutex<MysqlState>>,
     pub laste_check_time: Arc<Mutex<LastCheckTime>>,
     pub conf: Arc<Config>,
@@ -334,7 +343,7 @@ impl MysqlConn{
 
 
 
-pub fn mysql_state_check(tcp: &TcpStream, state: &Arc<Mutex<MysqlState>>, last_check_time: &Arc<Mutex<LastCheckTime>>) -> Result<(), Box<dyn Error>> {
+pub fn mysql_state_check<W: Write>(tcp: W, state: &Arc<Mutex<MysqlState>>, last_check_time: &Arc<Mutex<LastCheckTime>>) -> Result<(), Box<dyn Error>> {
 //    let mut state = MysqlState::new();
 //    let tcp_conn = crate::create_conn(&conf);
 //    match tcp_conn {
@@ -355,14 +364,66 @@ pub fn mysql_state_check(tcp: &TcpStream, state: &Arc<Mutex<MysqlState>>, last_c
     if now_time - last_lock.last_time >= 10000{
         let state = MysqlState::new();
         //info!("the status data lags behind for more than 10s, send mysql default packet");
-        mysql::send_value_packet(&tcp, &state, mysql::MyProtocol::MysqlCheck)?;
+        mysql::send_value_packet(tcp, &state, mysql::MyProtocol::MysqlCheck)?;
         return Ok(())
     }
     let my_state = state_lock.my_clone();
-    mysql::send_value_packet(&tcp, &my_state, mysql::MyProtocol::MysqlCheck)?;
+    mysql::send_value_packet(tcp, &my_state, mysql::MyProtocol::MysqlCheck)?;
     Ok(())
 }
 
 
 
 
+
+
+///
+/// ping返回信息，仅用于server端判断client存活
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PingInfo {
+    pub version: String,        //client版本
+    pub uptime: usize,          //client运行时间, 单位秒
+    pub online: bool,           //最后一次检查mysql的状态
+}
+
+pub fn ping<W: Write>(tcp: W, state: &Arc<Mutex<MysqlState>>, start_time: Instant) -> Result<(), Box<dyn Error>> {
+    let online = state.lock().unwrap().online;
+    let info = PingInfo{
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime: start_time.elapsed().as_secs() as usize,
+        online
+    };
+    mysql::send_value_packet(tcp, &info, mysql::MyProtocol::Ping)?;
+    Ok(())
+}
+
+///
+/// 完整的slave同步状态，多源复制时每个channel一条
+///
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicationStatus {
+    pub role: String,
+    pub channels: Vec<HashMap<String, Option<String>>>,
+}
+
+pub fn replication_status<W: Write>(tcp: W, conf: &Arc<Config>) -> Result<(), Box<dyn Error>> {
+    let mut conn = crate::create_conn(conf)?;
+    let result = crate::io::command::execute(&mut conn, &String::from("show slave status"));
+    crate::io::command::close(&mut conn);
+    let mut channels = vec![];
+    for row in result? {
+        let mut channel = row.to_map();
+        //gtid较多时会带有换行符
+        for key in &["Retrieved_Gtid_Set", "Executed_Gtid_Set"] {
+            if let Some(Some(v)) = channel.get_mut(&key.to_string()) {
+                *v = v.replace("\n", "");
+            }
+        }
+        channels.push(channel);
+    }
+    let role = if channels.len() > 0 { "slave" } else { "master" };
+    let status = ReplicationStatus{ role: role.to_string(), channels };
+    mysql::send_value_packet(tcp, &status, mysql::MyProtocol::ReplicationStatus)?;
+    Ok(())
+}
@@ -5,13 +5,21 @@
 use serde::Deserialize;
 use serde::Serialize;
 use crate::Config;
-use std::sync::Arc;
+use crate::meta::{BinlogPos, SchemaCache};
+use std::sync::{Arc, Mutex};
 use std::net::TcpStream;
 use std::error::Error;
 use crate::binlog::open_file;
 use std::io::{Seek, SeekFrom, Read, Cursor};
 use crate::binlog::readevent::Tell;
 use crate::mysql::MyProtocol;
+use crate::io::replication::ReplicationStream;
+
+const ROTATE_EVENT: u8 = 4;
+const FORMAT_DESCRIPTION_EVENT: u8 = 15;
+const HEARTBEAT_EVENT: u8 = 27;
+const HEARTBEAT_EVENT_V2: u8 = 41;
+const LOG_EVENT_ARTIFICIAL_F: u16 = 0x20;
 
 #[derive(Deserialize, Debug)]
 pub struct SyncBinlogInfo{
@@ -34,39 +42,114 @@ pub fn pull_binlog_info(conf: &Arc<Config>, tcp: &mut TcpStream, buf: &Vec<u8>)
     let sync_info: SyncBinlogInfo = serde_json::from_slice(&buf[9..])?;
     info!("synchronization info: {:?}",&sync_info);
     let path = format!("{}/{}",conf.binlogdir,sync_info.binlog);
-    let mut reader = open_file(&path)?;
-    reader.seek(SeekFrom::End(0))?;
-    let end_pos = reader.tell()?;
-    let read_bytes = end_pos - sync_info.position as u64;
-    let mut binlog_value = BinlogValue::new();
-    if read_bytes > 0{
-        reader.seek(SeekFrom::Start(sync_info.position as u64))?;
-        reader.read_to_end(&mut binlog_value.value)?;
-    }
+    let binlog_value = match open_file(&path) {
+        Ok(mut reader) => {
+            let mut binlog_value = BinlogValue::new();
+            reader.seek(SeekFrom::End(0))?;
+            let end_pos = reader.tell()?;
+            if end_pos > sync_info.position as u64 {
+                //与复制协议拉取时一样先放入format description event，binlog_checksum=NONE时解析端才能确定没有checksum
+                let fde = read_format_description_buf(&mut reader)?;
+                if sync_info.position as u64 >= 4 + fde.len() as u64 {
+                    binlog_value.value.extend(fde);
+                }
+                reader.seek(SeekFrom::Start(sync_info.position as u64))?;
+                reader.read_to_end(&mut binlog_value.value)?;
+            }
+            binlog_value
+        }
+        Err(e) => {
+            //无法直接读取binlog文件时(不在同一台机器或没有权限)，通过复制协议从mysql拉取
+            info!("open {} failed: {}, pull binlog from mysql", path, e);
+            dump_binlog_value(conf, &sync_info)?
+        }
+    };
     crate::mysql::send_value_packet(tcp, &binlog_value, MyProtocol::PullBinlog)?;
     info!("successful synchronization");
     Ok(())
 }
 
-pub fn push_binlog_info(conf: &Arc<Config>, tcp: &mut TcpStream, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+///
+/// 读取binlog文件头部的format description event原始数据
+///
+fn read_format_description_buf<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    reader.seek(SeekFrom::Start(4))?;
+    let mut event = vec![0u8; 19];
+    reader.read_exact(&mut event)?;
+    if event[4] != FORMAT_DESCRIPTION_EVENT {
+        return Err(format!("invalid binlog file, first event type is {}", event[4]).into());
+    }
+    let event_length = crate::readvalue::read_u32(&event[9..13]) as usize;
+    if event_length < 19 {
+        return Err(format!("invalid format description event length: {}", event_length).into());
+    }
+    event.resize(event_length, 0);
+    reader.read_exact(&mut event[19..])?;
+    Ok(event)
+}
+
+///
+/// 以slave身份从本地mysql拉取指定位置之后的所有event，读取到最后一个binlog末尾时结束
+///     保留format description event供解析时确定checksum，去掉rotate、heartbeat及服务端生成的event
+///
+fn dump_binlog_value(conf: &Arc<Config>, sync_info: &SyncBinlogInfo) -> Result<BinlogValue, Box<dyn Error>> {
+    let mut stream = ReplicationStream::connect(conf, conf.dump_server_id)?;
+    stream.dump(&sync_info.binlog, sync_info.position as u64, true)?;
+    let mut binlog_value = BinlogValue::new();
+    loop {
+        let event = match stream.read_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            Err(e) => {
+                stream.close();
+                return Err(e);
+            }
+        };
+        if event.len() < 19 {
+            continue;
+        }
+        let flags = crate::readvalue::read_u16(&event[17..19]);
+        if flags & LOG_EVENT_ARTIFICIAL_F != 0 || event[4] == ROTATE_EVENT
+            || event[4] == HEARTBEAT_EVENT || event[4] == HEARTBEAT_EVENT_V2 {
+            continue;
+        }
+        binlog_value.value.extend(event);
+    }
+    stream.close();
+    info!("pull {} bytes from mysql", binlog_value.value.len());
+    Ok(binlog_value)
+}
+
+pub fn push_binlog_info(conf: &Arc<Config>, schema: &Arc<Mutex<SchemaCache>>, tcp: &mut TcpStream, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
     info!("append difference binlog");
     //info!("{:?}", buf);
     let value: BinlogValue = serde_json::from_slice(&buf[9..])?;
     let reader_size= value.value.len() as u64;
     info!("append {} bytes", reader_size);
     let mut cur = Cursor::new(value.value);
-    let mut rowsql = crate::binlog::readbinlog::parse(conf, &mut cur, reader_size, false)?;
+    let mut rowsql = crate::binlog::readbinlog::parse(conf, schema, &mut cur, reader_size, false, &BinlogPos::new(&"".to_string(), 0))?;
     rowsql.set_append_etype();
+    //binlog不完整时不追加任何数据
+    if rowsql.error.len() > 0 {
+        return Err(rowsql.error.into());
+    }
 
     let mut conn = crate::create_conn(conf)?;
-    for traction in &rowsql.sqls{
-        let sqls = &traction.cur_sql;
-        for sql in sqls{
-            info!("{}",sql);
-            crate::io::command::execute_update(&mut conn, sql)?;
+    crate::io::command::execute_update(&mut conn, &String::from(crate::binlog::getsql::SESSION_TIME_ZONE))?;
+    //每个事务单独提交，出错时该事务回滚，返回已经追加的事务数
+    for (idx, traction) in rowsql.sqls.iter().enumerate(){
+        for sql in &traction.cur_sql{
+            info!("{}", sql);
+        }
+        if let Err(e) = crate::mysql::recovery::execute_traction(&mut conn, &traction.cur_stmt) {
+            let progress = match idx {
+                0 => String::from("no transaction appended"),
+                _ => format!("{} of {} transactions appended, last one ends at position {} of the difference binlog", idx, rowsql.sqls.len(), rowsql.sqls[idx - 1].position)
+            };
+            return Err(format!("{}, {}", e, progress).into());
         }
     }
     info!("Ok");
-    crate::mysql::send_value_packet(&tcp, &rowsql, MyProtocol::RecoveryValue)?;
+    crate::mysql::send_value_packet(&*tcp, &rowsql, MyProtocol::RecoveryValue)?;
     Ok(())
 }
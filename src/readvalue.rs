@@ -150,10 +150,20 @@ pub fn read_nbytes<R: Read, S: Into<usize>>(r: &mut R, desired_bytes: S) -> io::
 /// 接收client返回的数据
 ///
 pub fn rec_packet(conn: &mut TcpStream) -> Result<Vec<u8>, Box<dyn Error>> {
+    rec_packet_limited(conn, u64::MAX)
+}
+
+///
+/// 接收对端发送的数据，包头中的长度来自对端，超过max_payload时在分配内存前返回错误
+///
+pub fn rec_packet_limited(conn: &mut TcpStream, max_payload: u64) -> Result<Vec<u8>, Box<dyn Error>> {
     let mut buf: Vec<u8> = vec![];
     let mut header: Vec<u8> = vec![0u8;9];
     conn.read_exact(&mut header)?;
     let payload = crate::readvalue::read_u64(&header[1..]);
+    if payload > max_payload {
+        return Err(format!("packet length {} exceeds the limit {}", payload, max_payload).into());
+    }
     let mut payload_buf: Vec<u8> = vec![0u8; payload as usize];
     conn.read_exact(&mut payload_buf)?;
     buf.extend(header);
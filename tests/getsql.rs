@@ -0,0 +1,127 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/25
+*/
+
+//!
+//! 生成sql时值及名称的转义，字符串不使用反斜杠转义，无法直接输出的值使用带字符集前缀的16进制
+//!
+
+use serde_json::json;
+use mymha_client::binlog::getsql::{out_delete, out_insert, RenderMode};
+use mymha_client::binlog::parsevalue::MySQLValue;
+use mymha_client::binlog::readevent::TableMap;
+use mymha_client::io::command::Param;
+use mymha_client::meta::ColumnMeta;
+
+fn column(name: &str, column_type: &str, character_set: Option<&str>) -> ColumnMeta {
+    ColumnMeta{
+        column_name: name.to_string(),
+        column_type: column_type.to_string(),
+        column_key: "".to_string(),
+        character_set: character_set.map(|c| c.to_string()),
+        is_nullable: true,
+        key_ordinal: None,
+        is_unsigned: false
+    }
+}
+
+fn table(db: &str, tb: &str) -> TableMap {
+    let mut map = TableMap::new();
+    map.database_name = db.to_string();
+    map.table_name = tb.to_string();
+    map
+}
+
+///
+/// 单个字段的insert语句中VALUES部分的值
+///
+fn insert_value(value: MySQLValue, col: ColumnMeta) -> String {
+    let stmt = out_insert(&vec![Some(value)], &vec![col], &table("test", "t1"), &RenderMode::Literal);
+    let prefix = "INSERT INTO `test`.`t1`(`c`) VALUES(";
+    assert!(stmt.sql.starts_with(prefix), "{}", stmt.sql);
+    assert!(stmt.sql.ends_with(");"), "{}", stmt.sql);
+    stmt.sql[prefix.len()..stmt.sql.len() - 2].to_string()
+}
+
+fn utf8_string(value: &str) -> String {
+    insert_value(MySQLValue::String(value.to_string()), column("c", "varchar(20)", Some("utf8mb4")))
+}
+
+#[test]
+fn string_quotes() {
+    assert_eq!(utf8_string("abc"), "_utf8mb4'abc'");
+    assert_eq!(utf8_string("it's"), "_utf8mb4'it''s'");
+    assert_eq!(utf8_string("'); drop table t1; --"), "_utf8mb4'''); drop table t1; --'");
+    assert_eq!(utf8_string("a\tb"), "_utf8mb4'a\tb'");
+}
+
+#[test]
+fn string_backslash_and_control() {
+    //反斜杠在默认sql_mode下是转义符，使用16进制避免依赖sql_mode
+    assert_eq!(utf8_string("a\\'b"), "_utf8mb4 X'615c2762'");
+    assert_eq!(utf8_string("a\0b"), "_utf8mb4 X'610062'");
+    assert_eq!(utf8_string("a\nb"), "_utf8mb4 X'610a62'");
+    assert_eq!(utf8_string("\u{1a}"), "_utf8mb4 X'1a'");
+}
+
+#[test]
+fn text_bytes_with_charset() {
+    //非utf8字符集或不是合法的utf8编码时使用16进制，字符集前缀保证按字段的字符集解释
+    let gbk = insert_value(MySQLValue::Blob(vec![0xd6, 0xd0, 0xce, 0xc4]), column("c", "varchar(20)", Some("gbk")));
+    assert_eq!(gbk, "_gbk X'd6d0cec4'");
+    let latin1 = insert_value(MySQLValue::Blob(b"abc".to_vec()), column("c", "text", Some("latin1")));
+    assert_eq!(latin1, "_latin1 X'616263'");
+    let invalid = insert_value(MySQLValue::Blob(vec![0x61, 0xff]), column("c", "char(2)", Some("utf8mb4")));
+    assert_eq!(invalid, "_utf8mb4 X'61ff'");
+    let valid = insert_value(MySQLValue::Blob("中'文".as_bytes().to_vec()), column("c", "mediumtext", Some("utf8")));
+    assert_eq!(valid, "_utf8'中''文'");
+    //字符集未知时只使用16进制
+    let unknown = insert_value(MySQLValue::Blob(b"it's".to_vec()), column("c", "varchar(20)", None));
+    assert_eq!(unknown, "X'69742773'");
+    //二进制字段
+    let binary = insert_value(MySQLValue::Blob(vec![0x00, 0x27, 0x5c]), column("c", "varbinary(20)", None));
+    assert_eq!(binary, "X'00275c'");
+}
+
+#[test]
+fn text_params_with_charset() {
+    let col = column("c", "varchar(20)", Some("latin1"));
+    let stmt = out_insert(&vec![Some(MySQLValue::Blob(vec![0xe9]))], &vec![col], &table("test", "t1"), &RenderMode::Param);
+    assert_eq!(stmt.sql, "INSERT INTO `test`.`t1`(`c`) VALUES(CONVERT(? USING latin1));");
+    assert_eq!(stmt.params.len(), 1);
+    match &stmt.params[0] {
+        Param::Bytes(v) => assert_eq!(v, &vec![0xe9]),
+        p => panic!("unexpected param {:?}", p)
+    }
+}
+
+#[test]
+fn identifier_backticks() {
+    let map = table("my`db", "t`1");
+    let cols = vec![column("i`d", "int(11)", None), column("na``me", "varchar(20)", Some("utf8mb4"))];
+    let values = vec![Some(MySQLValue::SignedInteger(1)), Some(MySQLValue::String("a".to_string()))];
+    let stmt = out_insert(&values, &cols, &map, &RenderMode::Literal);
+    assert_eq!(stmt.sql, "INSERT INTO `my``db`.`t``1`(`i``d`,`na````me`) VALUES(1,_utf8mb4'a');");
+
+    let stmt = out_delete(&values, &cols, &vec![0], &map, &RenderMode::Literal);
+    assert_eq!(stmt.sql, "DELETE FROM `my``db`.`t``1`  WHERE `i``d`=1;");
+}
+
+#[test]
+fn where_without_key() {
+    //没有主键时使用全部字段匹配，float字段不作为条件，json字段转换为json比较
+    let map = table("test", "t1");
+    let cols = vec![column("id", "int(11)", None), column("f", "float", None), column("doc", "json", None), column("name", "varchar(20)", Some("utf8mb4"))];
+    let values = vec![Some(MySQLValue::SignedInteger(1)), Some(MySQLValue::Float(1.1)), Some(MySQLValue::Json(json!({"a": 1}))), Some(MySQLValue::Null)];
+    let stmt = out_delete(&values, &cols, &vec![], &map, &RenderMode::Literal);
+    assert_eq!(stmt.sql, "DELETE FROM `test`.`t1`  WHERE `id`<=>1 AND `doc`<=>CAST(_utf8mb4'{\"a\":1}' AS JSON) AND `name`<=>Null LIMIT 1;");
+
+    let stmt = out_delete(&values, &cols, &vec![], &map, &RenderMode::Param);
+    assert_eq!(stmt.sql, "DELETE FROM `test`.`t1`  WHERE `id`<=>? AND `doc`<=>CAST(CONVERT(? USING utf8mb4) AS JSON) AND `name`<=>Null LIMIT 1;");
+    assert_eq!(stmt.params.len(), 2);
+
+    //只有float字段时仍然使用float字段匹配
+    let stmt = out_delete(&vec![Some(MySQLValue::Float(1.5))], &vec![column("f", "float", None)], &vec![], &map, &RenderMode::Literal);
+    assert_eq!(stmt.sql, "DELETE FROM `test`.`t1`  WHERE `f`<=>1.5 LIMIT 1;");
+}
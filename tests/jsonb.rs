@@ -0,0 +1,60 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/25
+*/
+
+//!
+//! write_binary_json生成的mysql binary json应能由read_binary_json还原
+//!
+
+use std::io::Cursor;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use mymha_client::binlog::jsonb::{read_binary_json, write_binary_json};
+
+fn round_trip(value: &JsonValue) -> Vec<u8> {
+    let data = write_binary_json(value).unwrap();
+    let v = read_binary_json(&mut Cursor::new(&data), &data.len()).unwrap();
+    assert_eq!(&v, value);
+    data
+}
+
+#[test]
+fn json_round_trip_scalar() {
+    for v in vec![json!(null), json!(true), json!(false), json!(0), json!(-32768), json!(70000),
+                  json!(-3000000000i64), json!(1.5), json!(""), json!("abc"), json!("中文")] {
+        round_trip(&v);
+    }
+}
+
+#[test]
+fn json_round_trip_small_container() {
+    let value = json!({
+        "k": 1,
+        "arr": [1, 2, true, null, "x", 1.5, -70000, {"a": []}],
+        "obj": {"b": {"c": "d"}, "empty": {}},
+        "long_key_name": "v"
+    });
+    let data = round_trip(&value);
+    //小于64KB时使用small格式，object的type为0x00
+    assert_eq!(data[0], 0x00);
+    round_trip(&json!([value, value]));
+}
+
+#[test]
+fn json_round_trip_large_container() {
+    let text = "x".repeat(1024);
+    let mut obj = serde_json::Map::new();
+    for i in 0..100 {
+        obj.insert(format!("key{}", i), json!([i, text.clone(), {"n": i}]));
+    }
+    let value = JsonValue::Object(obj);
+    let data = round_trip(&value);
+    //超过64KB时使用large格式，object的type为0x01
+    assert!(data.len() > 1 << 16);
+    assert_eq!(data[0], 0x01);
+
+    let array = json!([value, 1, "a", [text]]);
+    let data = round_trip(&array);
+    assert_eq!(data[0], 0x03);
+}
@@ -0,0 +1,127 @@
+/*
+@author: xiao cai niao
+@datetime: 2019/11/25
+*/
+
+//!
+//! 使用tests/fixtures下的binlog文件验证row event的回滚
+//!     每个文件为magic + format description event + table map event + 一个row event，开启crc32
+//!     表结构: test.t1(id int primary key, name varchar(20), doc json, created datetime(3))
+//!
+
+use std::fs;
+use std::io::Cursor;
+use mymha_client::binlog::readevent::{EventHeader, FormatDescriptionEvent, InitHeader, InitValue, TableMap};
+use mymha_client::binlog::rollback::rollback_row_event;
+
+const HEADER_LEN: usize = 19;
+//v2 update event中行数据的起始位置: header + table_id/flags + extra_len + columns + 两个columns-present bitmap
+const UPDATE_V2_ROWS_OFFSET: usize = 19 + 8 + 2 + 1 + 1 + 1;
+
+///
+/// 读取fixture中的table map和row event，row event去掉末尾的checksum并修改header中的event_length
+///
+fn load(name: &str) -> (TableMap, Vec<u8>) {
+    let data = fs::read(format!("{}/tests/fixtures/{}.binlog", env!("CARGO_MANIFEST_DIR"), name)).unwrap();
+    assert_eq!(&data[..4], b"\xfebin");
+    let mut events = vec![];
+    let mut offset = 4;
+    while offset < data.len() {
+        let length = u32::from_le_bytes([data[offset + 9], data[offset + 10], data[offset + 11], data[offset + 12]]) as usize;
+        events.push(data[offset..offset + length].to_vec());
+        offset += length;
+    }
+    assert_eq!(events.len(), 3);
+
+    let fde = FormatDescriptionEvent::read_event(&events[0][HEADER_LEN..].to_vec()).unwrap();
+    let checksum_len = fde.checksum_len();
+    assert_eq!(checksum_len, 4);
+
+    let map_event = strip_checksum(&events[1], checksum_len);
+    let mut header = read_header(&map_event);
+    header.post_header_length = fde.post_header_length(header.type_byte);
+    let mut payload = Cursor::new(map_event[HEADER_LEN..].to_vec());
+    let map = TableMap::read_event(&header, &mut payload, &fde.major_version()).unwrap();
+    assert_eq!(map.column_info.len(), 4);
+
+    (map, strip_checksum(&events[2], checksum_len))
+}
+
+fn strip_checksum(event: &Vec<u8>, checksum_len: usize) -> Vec<u8> {
+    let mut event = event[..event.len() - checksum_len].to_vec();
+    let event_length = event.len() as u32;
+    event[9..13].copy_from_slice(&event_length.to_le_bytes());
+    event
+}
+
+fn read_header(event: &Vec<u8>) -> EventHeader {
+    EventHeader::new(&mut Cursor::new(event)).unwrap()
+}
+
+fn rollback(event: &Vec<u8>, map: &TableMap) -> Vec<u8> {
+    let header = read_header(event);
+    let r = rollback_row_event(event, &header, map).unwrap();
+    assert_eq!(read_header(&r).event_length as usize, r.len());
+    r
+}
+
+///
+/// 回滚一次后类型应为rollback_type，回滚两次应得到原始的event
+///
+fn check_twice(name: &str, rollback_type: u8) {
+    let (map, event) = load(name);
+    let once = rollback(&event, &map);
+    assert_eq!(once[4], rollback_type, "{}", name);
+    assert_ne!(once, event, "{}", name);
+    let twice = rollback(&once, &map);
+    assert_eq!(twice, event, "{}", name);
+}
+
+#[test]
+fn rollback_write_delete_v1() {
+    check_twice("write_rows_v1", 25);
+    check_twice("delete_rows_v1", 23);
+}
+
+#[test]
+fn rollback_update_v1() {
+    check_twice("update_rows_v1", 24);
+}
+
+#[test]
+fn rollback_write_delete_v2() {
+    check_twice("write_rows_v2", 32);
+    check_twice("delete_rows_v2", 30);
+}
+
+#[test]
+fn rollback_update_v2() {
+    check_twice("update_rows_v2", 31);
+}
+
+#[test]
+fn rollback_multi_row_update() {
+    check_twice("update_rows_multi", 31);
+
+    //多行时后修改的行先回滚，update_rows_multi的第一行与update_rows_v2相同，回滚后应在最后
+    let (map, event) = load("update_rows_multi");
+    let (_, single) = load("update_rows_v2");
+    let once = rollback(&event, &map);
+    let single_once = rollback(&single, &map);
+    assert_eq!(once.len(), event.len());
+    assert!(once.ends_with(&single_once[UPDATE_V2_ROWS_OFFSET..]));
+    assert!(!once[UPDATE_V2_ROWS_OFFSET..].starts_with(&single_once[UPDATE_V2_ROWS_OFFSET..]));
+}
+
+#[test]
+fn rollback_partial_update() {
+    let (map, event) = load("partial_update_rows");
+    let (_, full) = load("partial_update_rows_full");
+    assert_eq!(event[4], 39);
+
+    //partial update回滚后为普通的update event，after image中为完整的json
+    let once = rollback(&event, &map);
+    assert_eq!(once[4], 31);
+    let twice = rollback(&once, &map);
+    assert_eq!(twice, full);
+}